@@ -1,41 +1,1017 @@
+use crate::cache::FetchCache;
 use crate::calibre::{
-    apply_cover_to_calibre_db, apply_opf_to_calibre_db, embed_metadata_into_formats,
-    fetch_metadata_to_opf_and_cover, list_candidate_books, refresh_one_book,
+    apply_cover_to_calibre_db, apply_opf_fields_to_calibre_db, apply_opf_to_calibre_db,
+    embed_metadata_into_formats, fetch_match_key, fetch_metadata_to_opf_and_cover,
+    fetch_with_external_provider, add_book_file, add_tag_to_book, can_embed,
+    discover_default_library, exact_match_clause, list_book_ids, list_candidate_books,
+    read_embedded_metadata, refresh_one_book, render_pdf_first_page, show_metadata_opf,
+    CandidateQuery, NO_STRONG_IDENTIFIER,
 };
 use crate::config::{
     init_tracing, load_config, normalize_library_spec, normalize_optional_string, Args, Command,
+    parse_duration, validate_config, Config, ExternalProviderMode,
 };
-use crate::dups::{run_dups, DupsSettings, OutputFormat};
-use crate::metadata::{metadata_snapshot, score_good_enough, snapshot_hash};
-use crate::runner::Runner;
-use crate::state::{get_book_state, load_state, now_iso, put_book_state, save_state, BookState};
+use crate::dups::{run_dups, write_output, DupsSettings, HashAlgo, OutputFormat};
+use crate::ignore::{IgnoreList, IGNORE_FILE_NAME};
+use crate::metadata::{
+    changed_fields, format_files, identity_key, is_good_enough, metadata_snapshot,
+    normalize_formats, opf_snapshot, score_good_enough, score_parts, snapshot_hash, Snapshot,
+};
+use crate::opf::read_opf;
+use crate::report::{
+    dry_run_plan_line, print_summary, scores_csv, write_report, BookResult, FetchEstimate,
+    ProgressWriter, RunReport, RunSummary, ScoreRow, SkipBreakdown,
+};
+use crate::runner::{FetchSlots, Runner};
+use crate::state::{
+    acquire_run_lock, display_timestamp, load_manifest, load_state, now_iso, save_manifest,
+    BookState, CoverState, ManifestEntry, StateSaver, StateStore,
+};
+use crate::diff::run_diff_state;
+use crate::merge::run_merge_duplicates;
+use crate::restore::run_restore;
+use crate::validate::run_validate_opf;
+use crate::verify::run_verify;
 use anyhow::{Context, Result};
 use clap::Parser;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
 use tracing::{debug, error, info, warn};
 
+/// States that are not retried unless the book's metadata changes.
+const TERMINAL_STATUSES: &[&str] = &[
+    "done",
+    "skipped_good_enough",
+    "embedded_only",
+    "failed_permanent",
+    "db_only_large_file",
+    "embed_unverified",
+    "done_no_embed",
+];
+
+/// Set by the Ctrl-C handler; books not yet started are left alone.
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// First Ctrl-C lets in-flight books finish; a second one exits immediately.
+fn install_interrupt_handler() {
+    let installed = ctrlc::set_handler(|| {
+        if STOP_REQUESTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        warn!("[interrupt] stopping after the current book(s); press Ctrl-C again to abort");
+    });
+    if let Err(e) = installed {
+        warn!(error = %e, "[interrupt] could not install Ctrl-C handler");
+    }
+}
+
+/// How a run ended, mapped to the process exit code (see `--help`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    Clean,
+    BooksFailed,
+    NoCandidates,
+    Interrupted,
+}
+
+impl RunOutcome {
+    pub fn exit_code(self) -> ExitCode {
+        ExitCode::from(match self {
+            RunOutcome::Clean => 0,
+            RunOutcome::BooksFailed => 1,
+            RunOutcome::NoCandidates => 2,
+            RunOutcome::Interrupted => 130,
+        })
+    }
+}
+
+/// `--config-check`: report unknown keys and semantic problems, then print
+/// the effective config. Never touches the library.
+fn config_check(config: &Config, unknown_keys: &[String], format: OutputFormat) -> Result<RunOutcome> {
+    let mut problems: Vec<String> = unknown_keys
+        .iter()
+        .map(|key| format!("unknown key: {key}"))
+        .collect();
+    problems.extend(validate_config(config));
+    let rendered = match format {
+        OutputFormat::Text => toml::to_string_pretty(config)?,
+        OutputFormat::Json => serde_json::to_string_pretty(config)?,
+    };
+    println!("{}", rendered.trim_end());
+    if problems.is_empty() {
+        eprintln!("config OK");
+        return Ok(RunOutcome::Clean);
+    }
+    for problem in &problems {
+        eprintln!("error: {problem}");
+    }
+    anyhow::bail!("config check failed with {} problem(s)", problems.len())
+}
+
 fn require_tool(name: &str) -> Result<()> {
     which::which(name).with_context(|| format!("Missing required tool on PATH: {name}"))?;
     Ok(())
 }
 
-fn process_one_book(
+struct BookContext<'a> {
+    runner: &'a Runner,
+    config: &'a Config,
+    workdir: &'a Path,
+    /// Unique per run; part of every artifact name so runs sharing a
+    /// persistent workdir never reuse each other's files
+    run_nonce: &'a str,
+    /// Remove a book's artifacts once it is finished
+    discard_artifacts: bool,
+    lib: &'a str,
+    target_formats: &'a BTreeMap<String, ()>,
+    /// Custom columns listed for scoring (`#name`)
+    custom_columns: &'a [String],
+    throttle: &'a FetchThrottle,
+    fetch_cache: Option<&'a FetchCache>,
+    /// Retry books even if state says they are finished (`--only-ids`)
+    force: bool,
+    /// Re-embed finished books without fetching (`--force-reembed`)
+    force_reembed: bool,
+    progress: Option<&'a ProgressWriter>,
+    /// Format of the dry-run plan printed to stdout
+    plan_format: OutputFormat,
+    /// Embed into every format the book has (`--all-formats`)
+    all_formats: bool,
+    /// Where pre-change OPFs go (`policy.backup_before_change`, never in dry runs)
+    backup_dir: Option<&'a Path>,
+    /// Dry runs still fetch (`--dry-run-network`)
+    fetch_in_dry_run: bool,
+}
+
+impl BookContext<'_> {
+    fn print_plan(
+        &self,
+        book_id: i64,
+        action: &str,
+        score: i32,
+        reasons: &[String],
+        snap: &Snapshot,
+    ) -> Result<()> {
+        let line = dry_run_plan_line(book_id, action, score, reasons, snap, self.plan_format)?;
+        println!("{line}");
+        Ok(())
+    }
+
+    /// The configured target formats, plus with `--all-formats` every other
+    /// format of the book that Calibre can write metadata into, minus
+    /// `formats.non_embeddable`. `None` when every targeted format the book
+    /// has is non-embeddable, so there is nothing to embed into.
+    fn embed_formats(
+        &self,
+        book_id: i64,
+        book: &serde_json::Value,
+    ) -> Option<BTreeMap<String, ()>> {
+        let listed = book.get("formats").unwrap_or(&serde_json::Value::Null);
+        let book_formats = normalize_formats(listed);
+        let mut formats = self.target_formats.clone();
+        if self.all_formats {
+            for fmt in &book_formats {
+                if formats.contains_key(fmt) {
+                    continue;
+                }
+                if can_embed(fmt) {
+                    formats.insert(fmt.clone(), ());
+                } else {
+                    info!(
+                        id = book_id,
+                        format = %fmt,
+                        "[embed] skipping format without a metadata writer"
+                    );
+                }
+            }
+        }
+        let non_embeddable = &self.config.formats.non_embeddable;
+        formats.retain(|fmt, _| !non_embeddable.iter().any(|n| n.eq_ignore_ascii_case(fmt)));
+        let has_any = book_formats.is_empty()
+            || book_formats.iter().any(|fmt| formats.contains_key(fmt));
+        has_any.then_some(formats)
+    }
+
+    /// `<workdir>/<id>.<run nonce>.<name>`
+    fn artifact_path(&self, book_id: i64, name: &str) -> PathBuf {
+        self.workdir.join(format!("{book_id}.{}.{name}", self.run_nonce))
+    }
+
+    fn remove_artifacts(&self, book_id: i64) {
+        let prefix = format!("{book_id}.{}.", self.run_nonce);
+        let Ok(entries) = std::fs::read_dir(self.workdir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    fn progress(&self, book_id: i64, title: &str, phase: &str, status: &str) {
+        if let Some(progress) = self.progress {
+            progress.book(book_id, title, phase, status);
+        }
+    }
+}
+
+/// Samples a fraction of embedded books and reads their metadata back from
+/// the format files to confirm the embed actually took.
+struct EmbedVerifier {
+    rng: Mutex<fastrand::Rng>,
+    rate: f64,
+    checked: AtomicUsize,
+    mismatched: AtomicUsize,
+}
+
+impl EmbedVerifier {
+    fn new(rate: f64, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => fastrand::Rng::with_seed(seed),
+            None => fastrand::Rng::new(),
+        };
+        Self {
+            rng: Mutex::new(rng),
+            rate: rate.clamp(0.0, 1.0),
+            checked: AtomicUsize::new(0),
+            mismatched: AtomicUsize::new(0),
+        }
+    }
+
+    fn should_sample(&self) -> bool {
+        self.rate > 0.0
+            && self.rng.lock().unwrap_or_else(PoisonError::into_inner).f64() < self.rate
+    }
+}
+
+/// Spaces fetch starts at least `interval` apart across all workers.
+struct FetchThrottle {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl FetchThrottle {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn wait(&self) {
+        if self.interval.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let slot = {
+            let mut next = self.next.lock().unwrap_or_else(PoisonError::into_inner);
+            let slot = (*next).max(now);
+            *next = slot + self.interval;
+            slot
+        };
+        if slot > now {
+            std::thread::sleep(slot - now);
+        }
+    }
+}
+
+/// Why a candidate was not processed this run.
+#[derive(Debug, Clone, Copy)]
+enum SkipReason {
+    AlreadyDone,
+    AlreadyKnown,
+    GoodEnough,
+    Backoff,
+    MaxFailCount,
+    NoStrongIdentifier,
+    /// Failed within `policy.retry_cooldown_seconds`; tallied as a backoff
+    Cooldown,
+    /// `--refetch-covers`: the current cover is large enough, or the fetched
+    /// one is no larger
+    CoverKept,
+}
+
+impl SkipReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            SkipReason::AlreadyDone => "already_done",
+            SkipReason::AlreadyKnown => "already_known",
+            SkipReason::GoodEnough => "good_enough",
+            SkipReason::Backoff => "backoff",
+            SkipReason::MaxFailCount => "max_fail_count",
+            SkipReason::NoStrongIdentifier => "no_strong_identifier",
+            SkipReason::Cooldown => "in_cooldown",
+            SkipReason::CoverKept => "cover_kept",
+        }
+    }
+}
+
+/// Per-run outcome counters shared by the workers.
+#[derive(Default)]
+struct Tally {
+    ok: usize,
+    fail: usize,
+    skipped: usize,
+    skips: SkipBreakdown,
+    /// Dry runs: books that would have been fetched
+    would_fetch: usize,
+    /// `--dry-run-network`: fetches that failed; not counted in `fail`
+    dry_run_fetch_failed: usize,
+    /// (reason, id, title) of each skip, for `--report-skipped`
+    skipped_books: Vec<(&'static str, i64, String)>,
+}
+
+impl Tally {
+    fn skip(&mut self, reason: SkipReason, book_id: i64, title: &str) {
+        self.skipped += 1;
+        self.skipped_books.push((reason.as_str(), book_id, title.to_string()));
+        match reason {
+            SkipReason::AlreadyDone => self.skips.already_done += 1,
+            SkipReason::AlreadyKnown => self.skips.already_known += 1,
+            SkipReason::GoodEnough => self.skips.good_enough += 1,
+            SkipReason::Backoff | SkipReason::Cooldown => self.skips.backoff += 1,
+            SkipReason::MaxFailCount => self.skips.max_fail_count += 1,
+            SkipReason::NoStrongIdentifier => self.skips.no_strong_identifier += 1,
+            SkipReason::CoverKept => self.skips.cover_kept += 1,
+        }
+    }
+}
+
+/// The `--report-skipped` list: skipped and filtered books grouped by reason.
+fn skipped_books_report(skipped: &[(&'static str, i64, String)]) -> String {
+    let mut by_reason: BTreeMap<&str, Vec<(i64, &str)>> = BTreeMap::new();
+    for (reason, id, title) in skipped {
+        by_reason.entry(reason).or_default().push((*id, title));
+    }
+    let mut out = format!("Skipped books: {}", skipped.len());
+    for (reason, mut books) in by_reason {
+        books.sort_unstable();
+        let why = match reason {
+            "already_done" => "finished earlier and metadata unchanged",
+            "already_known" => "already in state (--only-unprocessed)",
+            "good_enough" => "good enough; only embedded",
+            "backoff" => "failed and waiting out its retry backoff, or failed permanently",
+            "in_cooldown" => "failed within policy.retry_cooldown_seconds",
+            "max_fail_count" => "failed policy.max_fail_count times",
+            "no_strong_identifier" => "no strong identifier (fetch.require_strong_identifier)",
+            "cover_kept" => "current cover kept (--refetch-covers)",
+            "filtered_language" => "language is not English (policy.english_codes)",
+            "filtered_format" => "no format from formats.list",
+            "filtered_tags" => "tags outside policy.include_tags/exclude_tags",
+            _ => "",
+        };
+        out.push_str(&format!("\n{reason} ({why}): {}", books.len()));
+        for (id, title) in books {
+            out.push_str(&format!("\n  {id:>6}  {title}"));
+        }
+    }
+    out
+}
+
+/// The skip predicate for a candidate with prior state `prev`.
+fn skip_reason(
+    config: &Config,
+    only_unprocessed: bool,
+    force: bool,
+    prev: Option<&BookState>,
+    current_hash: &str,
+) -> Option<SkipReason> {
+    let prev = prev?;
+    // Any state at all, interrupted books included
+    if only_unprocessed {
+        return Some(SkipReason::AlreadyKnown);
+    }
+    // Interrupted books are otherwise always retried
+    if prev.status == "started" {
+        return None;
+    }
+    if force || (config.policy.reprocess_on_metadata_change && prev.last_hash != current_hash) {
+        return None;
+    }
+    if prev.status == "failed"
+        && config
+            .policy
+            .max_fail_count
+            .is_some_and(|cap| prev.fail_count >= cap as i32)
+    {
+        return Some(SkipReason::MaxFailCount);
+    }
+    if prev.status == "failed"
+        && let Some(after) = prev.retry_after_utc.as_deref().and_then(parse_calibre_datetime)
+        && after > chrono::Utc::now()
+    {
+        return Some(SkipReason::Backoff);
+    }
+    let cooldown = config.policy.retry_cooldown_seconds;
+    if prev.status == "failed"
+        && cooldown > 0
+        && let Some(attempt) = parse_calibre_datetime(&prev.last_attempt_utc)
+        && chrono::Utc::now() - attempt < chrono::Duration::seconds(cooldown as i64)
+    {
+        return Some(SkipReason::Cooldown);
+    }
+    if !TERMINAL_STATUSES.contains(&prev.status.as_str()) {
+        return None;
+    }
+    Some(if prev.status == "failed_permanent" {
+        SkipReason::Backoff
+    } else {
+        SkipReason::AlreadyDone
+    })
+}
+
+/// The `--explain` report: each filter and decision a run would apply to one
+/// book, in the order the run applies them. Reads state but never writes it.
+fn explain_book(
     runner: &Runner,
-    state: &mut crate::state::StateFile,
-    book: &serde_json::Value,
-    workdir: &Path,
     lib: &str,
-    target_formats: &BTreeMap<String, ()>,
-    reprocess_on_metadata_change: bool,
-    scoring: &crate::config::ScoringConfig,
-    delay_between_fetches_seconds: f64,
-    fetch_timeout_seconds: u64,
-    fetch_heartbeat_seconds: u64,
-    state_path: &Path,
-    dry_run: bool,
+    args: &Args,
+    config: &Config,
+    query: &CandidateQuery,
+    store: &StateStore,
+    since: Option<chrono::DateTime<chrono::Utc>>,
 ) -> Result<String> {
+    let book_id = args.explain.unwrap_or_default();
+    let mut clauses = query.extra_clauses.to_vec();
+    clauses.push(format!("id:{book_id}"));
+    let one = CandidateQuery { extra_clauses: &clauses, ..*query };
+    let (listed, filtered) = list_candidate_books(runner, lib, &one)?;
+    let candidate = listed.into_iter().find(|b| book_id_of(b) == book_id);
+    let book = match &candidate {
+        Some(book) => Some(book.clone()),
+        None => refresh_one_book(runner, lib, book_id, query.custom_columns)?,
+    };
+    let Some(book) = book else {
+        return Ok(format!("book {book_id}: not found in the library\ndecision: not processed"));
+    };
+    let title = book.get("title").and_then(|v| v.as_str()).unwrap_or("").trim();
+    let mut lines = vec![format!("book {book_id}: {title}")];
+
+    let mut dropped = Vec::new();
+    if candidate.is_none() {
+        dropped.push(if filtered.language > 0 {
+            "language is not English (policy.english_codes)".to_string()
+        } else if filtered.format > 0 {
+            "no format from formats.list".to_string()
+        } else if filtered.tags > 0 {
+            "tags outside policy.include_tags/exclude_tags".to_string()
+        } else {
+            "excluded by the listing search (--added-since, --author, tags, saved search, or \
+             format rules)"
+                .to_string()
+        });
+    }
+    if let Some(start_at) = args.start_at
+        && book_id < start_at
+    {
+        dropped.push(format!("below --start-at {start_at}"));
+    }
+    if let Some(since) = since
+        && book
+            .get("last_modified")
+            .and_then(|v| v.as_str())
+            .and_then(parse_calibre_datetime)
+            .is_some_and(|modified| modified < since)
+    {
+        dropped.push(format!("not modified since {}", since.to_rfc3339()));
+    }
+    if !args.only_ids.is_empty() && !args.only_ids.contains(&book_id) {
+        dropped.push("not in --only-ids".to_string());
+    }
+    if !is_remote_library(lib) {
+        let root = Path::new(lib).canonicalize().unwrap_or_else(|_| PathBuf::from(lib));
+        if let Some(ignore) = IgnoreList::load(Path::new(lib))?
+            && ignore.is_ignored(book_id, book_dir_in_library(&book, &root).as_deref())
+        {
+            dropped.push(format!("matched by {IGNORE_FILE_NAME}"));
+        }
+        for path in missing_format_files(&book, query.target_formats) {
+            dropped.push(format!("format file missing on disk: {}", path.display()));
+        }
+    }
+    if dropped.is_empty() {
+        lines.push("filters: passed".to_string());
+        if let Some(percent) = args.percent {
+            lines.push(format!("  subject to the --percent {percent} sample"));
+        }
+    } else {
+        lines.push("filters: filtered out".to_string());
+        lines.extend(dropped.iter().map(|why| format!("  {why}")));
+    }
+
+    let snap = metadata_snapshot(&book);
+    let hash = snapshot_hash(&snap)?;
+    let prev = store.get(book_id);
+    match &prev {
+        Some(prev) => {
+            let mut line = format!("state: {} (fail_count {}", prev.status, prev.fail_count);
+            if let Some(after) = &prev.retry_after_utc {
+                let after = display_timestamp(after, config.logging.local_time());
+                line.push_str(&format!(", retry after {after}"));
+            }
+            let same = if prev.last_hash == hash { "unchanged" } else { "changed" };
+            line.push_str(&format!(", metadata {same} since last run)"));
+            lines.push(line);
+        }
+        None => lines.push("state: none".to_string()),
+    }
+    let force = !args.only_ids.is_empty() || args.force_reembed;
+    let skip = skip_reason(config, args.only_unprocessed, force, prev.as_ref(), &hash);
+
+    let scoring = &config.scoring;
+    let parts = score_parts(&snap, scoring);
+    let score: i32 = parts.iter().map(|p| p.points).sum();
+    let good_enough = is_good_enough(score, &snap, scoring);
+    lines.push(format!(
+        "score: {score} (min_score_to_skip_fetch {}; good enough: {})",
+        scoring.min_score_to_skip_fetch,
+        if good_enough { "yes" } else { "no" }
+    ));
+    for part in &parts {
+        match &part.missing {
+            Some(missing) => lines.push(format!("  {}: {} ({missing})", part.field, part.points)),
+            None => lines.push(format!("  {}: {}", part.field, part.points)),
+        }
+    }
+
+    let reembed = args.force_reembed
+        && prev
+            .as_ref()
+            .is_some_and(|p| TERMINAL_STATUSES.contains(&p.status.as_str()));
+    let decision = if !dropped.is_empty() {
+        "not processed (filtered out)".to_string()
+    } else if let Some(reason) = skip {
+        format!("skip ({})", reason.as_str())
+    } else if reembed {
+        "embed only (--force-reembed)".to_string()
+    } else if good_enough {
+        "embed only (good enough)".to_string()
+    } else if config.fetch.require_strong_identifier
+        && fetch_match_key(&book, &config.fetch) == "title"
+    {
+        format!("skip ({NO_STRONG_IDENTIFIER})")
+    } else {
+        "fetch metadata, apply to the db, then embed".to_string()
+    };
+    let dry = if config.policy.dry_run { " [dry run]" } else { "" };
+    lines.push(format!("decision: {decision}{dry}"));
+    Ok(lines.join("\n"))
+}
+
+/// Saves the book's db metadata to `<backup dir>/<id>.opf` unless a backup
+/// already exists, so the file always holds the pre-change metadata.
+fn backup_book_metadata(ctx: &BookContext, book_id: i64) -> Result<()> {
+    let Some(dir) = ctx.backup_dir else {
+        return Ok(());
+    };
+    let path = dir.join(format!("{book_id}.opf"));
+    if path.exists() {
+        return Ok(());
+    }
+    let opf = show_metadata_opf(ctx.runner, ctx.lib, book_id)?;
+    let part = dir.join(format!("{book_id}.opf.part"));
+    std::fs::write(&part, opf).with_context(|| format!("Failed to write {}", part.display()))?;
+    std::fs::rename(&part, &path)
+        .with_context(|| format!("Failed to move {} -> {}", part.display(), path.display()))?;
+    debug!(id = book_id, path = %path.display(), "[backup] saved metadata");
+    Ok(())
+}
+
+/// `--artifacts-dir`: `<dir>/<id>/` with the fetched OPF and cover (when
+/// there were any), the listed snapshot as `before.json`, the db's snapshot
+/// after processing as `after.json` (not in dry runs) and `result.json`.
+fn write_book_artifacts(
+    ctx: &BookContext,
+    store: &StateStore,
+    dir: &Path,
+    book: &serde_json::Value,
+    action: &str,
+) -> Result<()> {
+    let book_id = book_id_of(book);
+    let out = dir.join(book_id.to_string());
+    std::fs::create_dir_all(&out).with_context(|| format!("Failed to create {}", out.display()))?;
+    let write_json = |name: &str, value: &serde_json::Value| -> Result<()> {
+        let path = out.join(name);
+        std::fs::write(&path, serde_json::to_string_pretty(value)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    };
+    for (artifact, name) in [("opf", "fetched.opf"), ("cover.jpg", "cover.jpg")] {
+        let src = ctx.artifact_path(book_id, artifact);
+        if src.is_file() {
+            std::fs::copy(&src, out.join(name))
+                .with_context(|| format!("Failed to copy {}", src.display()))?;
+        }
+    }
+    write_json("before.json", &serde_json::to_value(metadata_snapshot(book))?)?;
+    if !ctx.config.policy.dry_run
+        && let Some(refreshed) = refresh_one_book(ctx.runner, ctx.lib, book_id, ctx.custom_columns)?
+    {
+        write_json("after.json", &serde_json::to_value(metadata_snapshot(&refreshed))?)?;
+    }
+    let result = serde_json::json!({
+        "id": book_id,
+        "action": action,
+        "state": store.get(book_id),
+    });
+    write_json("result.json", &result)
+}
+
+/// Maps a fetch failure message onto its `[retry]` category.
+fn fetch_failure_category(message: &str) -> &'static str {
+    if message.contains("timed out") {
+        "fetch_timeout"
+    } else if message.contains("no OPF") || message.contains("no match") {
+        "fetch_no_match"
+    } else {
+        "network"
+    }
+}
+
+/// State for a failed attempt, with status and next retry time taken from the
+/// `[retry]` policy for `category`.
+fn failed_state(
+    config: &Config,
+    book_id: i64,
+    category: &str,
+    prev: Option<&BookState>,
+    last_hash: String,
+    message: String,
+) -> BookState {
+    let fail_count = prev.map_or(1, |p| p.fail_count + 1);
+    let (permanent, wait) = config.retry.get(category).after_failure(fail_count);
+    let permanent = permanent && !config.policy.never_permanent_ids.contains(&book_id);
+    BookState {
+        status: if permanent { "failed_permanent" } else { "failed" }.to_string(),
+        last_hash,
+        last_attempt_utc: now_iso(),
+        last_ok_utc: prev.and_then(|p| p.last_ok_utc.clone()),
+        message: Some(message),
+        fail_count,
+        retry_after_utc: wait
+            .filter(|_| !permanent)
+            .map(|w| (chrono::Utc::now() + w).to_rfc3339()),
+        source: prev.and_then(|p| p.source.clone()),
+        matched_by: prev.and_then(|p| p.matched_by.clone()),
+    }
+}
+
+/// Reads back the embedded title/authors of a sampled book's target formats.
+/// `false` if any format disagrees with the db.
+fn verify_embedded_sample(
+    ctx: &BookContext,
+    verifier: &EmbedVerifier,
+    book_id: i64,
+    book: &serde_json::Value,
+    embedded: &BTreeSet<String>,
+) -> Result<bool> {
+    if is_remote_library(ctx.lib) || !verifier.should_sample() {
+        return Ok(true);
+    }
+    let mut all_match = true;
+    let db = metadata_snapshot(book);
+    for (fmt, path) in embedded_format_files(book, embedded) {
+        let Some((title, authors)) = read_embedded_metadata(ctx.runner, &path)? else {
+            warn!(id = book_id, path = %path.display(), "[verify] could not read embedded metadata");
+            continue;
+        };
+        verifier.checked.fetch_add(1, Ordering::Relaxed);
+        if title != db.title || authors != db.authors {
+            all_match = false;
+            verifier.mismatched.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                id = book_id,
+                format = %fmt,
+                db_title = %db.title,
+                file_title = %title,
+                db_authors = %db.authors.join(" & "),
+                file_authors = %authors.join(" & "),
+                "[verify] embedded metadata does not match db"
+            );
+        } else {
+            debug!(id = book_id, format = %fmt, "[verify] embedded metadata matches db");
+        }
+    }
+    Ok(all_match)
+}
+
+/// The book's format files whose format was actually embedded; a format
+/// that was skipped or failed still carries its old metadata.
+fn embedded_format_files(
+    book: &serde_json::Value,
+    embedded: &BTreeSet<String>,
+) -> Vec<(String, PathBuf)> {
+    format_files(book.get("formats").unwrap_or(&serde_json::Value::Null))
+        .into_iter()
+        .filter(|(fmt, _)| embedded.contains(fmt))
+        .collect()
+}
+
+/// The largest target-format file over `policy.max_embed_file_bytes`, if
+/// any (local libraries only).
+fn oversized_format_file(ctx: &BookContext, book: &serde_json::Value) -> Option<(PathBuf, u64)> {
+    let limit = ctx.config.policy.max_embed_file_bytes?;
+    if is_remote_library(ctx.lib) {
+        return None;
+    }
+    format_files(book.get("formats").unwrap_or(&serde_json::Value::Null))
+        .into_iter()
+        .filter(|(fmt, _)| ctx.target_formats.contains_key(fmt))
+        .filter_map(|(_, path)| {
+            let bytes = path.metadata().ok()?.len();
+            (bytes > limit).then_some((path, bytes))
+        })
+        .max_by_key(|(_, bytes)| *bytes)
+}
+
+/// Adds `policy.processed_tag` (if configured) after a successful update.
+/// Returns a note for the book's state message.
+fn tag_processed_book(ctx: &BookContext, book_id: i64, title: &str) -> Result<Option<String>> {
+    let Some(tag) = &ctx.config.policy.processed_tag else {
+        return Ok(None);
+    };
+    let (ok, msg) = add_tag_to_book(ctx.runner, ctx.lib, book_id, tag)?;
+    if ok {
+        info!(id = book_id, title = %title, tag = %tag, result = %msg, "[tag] processed tag");
+        Ok(Some(msg))
+    } else {
+        warn!(id = book_id, title = %title, tag = %tag, error = %msg, "[warn] processed tag");
+        Ok(None)
+    }
+}
+
+fn image_dims(path: &Path) -> Option<(u32, u32)> {
+    let size = imagesize::size(path).ok()?;
+    Some((size.width as u32, size.height as u32))
+}
+
+/// Whether a cover of `dims` (None = missing or unreadable) should be refreshed.
+fn cover_below_threshold(ctx: &BookContext, dims: Option<(u32, u32)>) -> bool {
+    let fetch = &ctx.config.fetch;
+    if fetch.cover_min_width.is_none() && fetch.cover_min_height.is_none() {
+        return true;
+    }
+    let Some((w, h)) = dims else {
+        return true;
+    };
+    fetch.cover_min_width.is_some_and(|min| w < min)
+        || fetch.cover_min_height.is_some_and(|min| h < min)
+}
+
+/// `--refetch-covers`: fetch and apply just the cover, recording the outcome
+/// in the separate cover state. Acts with "done", "updated" (dry run) or
+/// "failed"; books it can't or needn't fetch for are skipped.
+fn refetch_cover(
+    ctx: &BookContext,
+    store: &StateStore,
+    book: &serde_json::Value,
+) -> Result<BookOutcome> {
+    let book_id = book_id_of(book);
+    let title = book.get("title").and_then(|v| v.as_str()).unwrap_or("").trim();
+    let current = if is_remote_library(ctx.lib) {
+        None
+    } else {
+        book.get("cover")
+            .and_then(|v| v.as_str())
+            .and_then(|p| image_dims(Path::new(p)))
+    };
+    if !cover_below_threshold(ctx, current) {
+        debug!(id = book_id, dims = ?current, "[cover] current cover meets minimum size");
+        return Ok(BookOutcome::Skipped(SkipReason::CoverKept));
+    }
+    if ctx.config.fetch.require_strong_identifier
+        && fetch_match_key(book, &ctx.config.fetch) == "title"
+    {
+        info!(id = book_id, title = %title, reason = NO_STRONG_IDENTIFIER, "[skip] cover");
+        return Ok(BookOutcome::Skipped(SkipReason::NoStrongIdentifier));
+    }
+    if ctx.config.policy.dry_run {
+        info!(id = book_id, title = %title, dims = ?current, "[dry-run] refetch cover");
+        return Ok(BookOutcome::Action("updated".to_string()));
+    }
+
+    ctx.progress(book_id, title, "fetch", "started");
+    let opf_path = ctx.artifact_path(book_id, "opf");
+    let cover_path = ctx.artifact_path(book_id, "cover.jpg");
+    ctx.throttle.wait();
+    let (ok_fetch, msg_fetch, _) = fetch_metadata(ctx, book, &opf_path, &cover_path)?;
+    let fetched = if ok_fetch { image_dims(&cover_path) } else { None };
+
+    let area = |(w, h): (u32, u32)| u64::from(w) * u64::from(h);
+    let (status, message) = match fetched {
+        None if ok_fetch => ("failed", "no cover downloaded".to_string()),
+        None => ("failed", msg_fetch),
+        Some(new) if current.is_some_and(|cur| area(cur) >= area(new)) => (
+            "not_better",
+            format!("fetched {}x{} is not larger than current", new.0, new.1),
+        ),
+        Some(new) => {
+            ctx.progress(book_id, title, "cover", "started");
+            let (ok_cov, msg_cov) =
+                apply_cover_to_calibre_db(ctx.runner, ctx.lib, book_id, &cover_path)?;
+            if ok_cov {
+                ("done", format!("applied {}x{}", new.0, new.1))
+            } else {
+                ("failed", msg_cov)
+            }
+        }
+    };
+
+    let prev = store.get_cover(book_id);
+    let (width, height) = match (status, fetched) {
+        ("done", Some((w, h))) => (Some(w), Some(h)),
+        _ => (current.map(|c| c.0), current.map(|c| c.1)),
+    };
+    store.put_cover(
+        book_id,
+        CoverState {
+            status: status.to_string(),
+            last_attempt_utc: now_iso(),
+            last_ok_utc: if status == "done" {
+                Some(now_iso())
+            } else {
+                prev.and_then(|p| p.last_ok_utc)
+            },
+            message: Some(message.clone()),
+            width,
+            height,
+        },
+    )?;
+    match status {
+        "done" => {
+            info!(id = book_id, title = %title, result = %message, "[cover] refreshed");
+            Ok(BookOutcome::Action("done".to_string()))
+        }
+        "failed" => {
+            warn!(id = book_id, title = %title, error = %message, "[cover] refresh failed");
+            Ok(BookOutcome::Action("failed".to_string()))
+        }
+        _ => {
+            info!(id = book_id, title = %title, result = %message, "[cover] kept current");
+            Ok(BookOutcome::Skipped(SkipReason::CoverKept))
+        }
+    }
+}
+
+/// Last-resort cover for cover-less PDFs: render page 1 and apply it.
+fn apply_first_page_cover_fallback(
+    ctx: &BookContext,
+    book_id: i64,
+    book: &serde_json::Value,
+    snap: &Snapshot,
+) -> Result<()> {
+    if !ctx.config.fetch.pdf_first_page_cover
+        || !ctx.config.policy.may_set("cover")
+        || snap.cover_present
+        || is_remote_library(ctx.lib)
+    {
+        return Ok(());
+    }
+    let files = format_files(book.get("formats").unwrap_or(&serde_json::Value::Null));
+    let Some((_, pdf)) = files.iter().find(|(fmt, _)| fmt == "pdf") else {
+        return Ok(());
+    };
+    let prefix = ctx.artifact_path(book_id, "firstpage");
+    let (ok_render, msg_render) = render_pdf_first_page(ctx.runner, pdf, &prefix)?;
+    if !ok_render {
+        warn!(id = book_id, error = %msg_render, "[warn] pdf first-page cover");
+        return Ok(());
+    }
+    let cover_path = prefix.with_extension("jpg");
+    let (ok_cov, msg_cov) = apply_cover_to_calibre_db(ctx.runner, ctx.lib, book_id, &cover_path)?;
+    if ok_cov {
+        info!(id = book_id, "[cover] applied pdf first page as cover");
+    } else {
+        warn!(id = book_id, error = %msg_cov, "[warn] pdf first-page cover");
+    }
+    Ok(())
+}
+
+/// Runs fetch-ebook-metadata and, when configured, the external provider as
+/// a fallback or as the primary source.
+fn fetch_metadata(
+    ctx: &BookContext,
+    book: &serde_json::Value,
+    opf_path: &Path,
+    cover_path: &Path,
+) -> Result<(bool, String, bool)> {
+    let fetch = &ctx.config.fetch;
+    let Some(command) = &fetch.external_provider else {
+        let (ok, msg) = fetch_metadata_to_opf_and_cover(
+            ctx.runner,
+            book,
+            opf_path,
+            cover_path,
+            fetch,
+            ctx.fetch_cache,
+        )?;
+        return Ok((ok, msg, false));
+    };
+    let external = || {
+        fetch_with_external_provider(
+            ctx.runner,
+            command,
+            book,
+            opf_path,
+            cover_path,
+            fetch.timeout_seconds,
+        )
+    };
+    let builtin = || {
+        fetch_metadata_to_opf_and_cover(ctx.runner, book, opf_path, cover_path, fetch, ctx.fetch_cache)
+    };
+    let primary = fetch.external_provider_mode == ExternalProviderMode::Primary;
+    let first = if primary { external()? } else { builtin()? };
+    if first.0 {
+        return Ok((true, first.1, primary));
+    }
+    let second = if primary { builtin()? } else { external()? };
+    if second.0 {
+        return Ok((true, second.1, !primary));
+    }
+    Ok((false, format!("{}; {}", first.1, second.1), false))
+}
+
+/// `fetch.source_min_score` gate: `Some(reason)` when the fetched OPF comes
+/// from a source whose threshold it doesn't reach.
+fn below_source_min_score(
+    ctx: &BookContext,
+    opf_path: &Path,
+    cover_path: &Path,
+) -> Result<Option<String>> {
+    if ctx.config.fetch.source_min_score.is_empty() {
+        return Ok(None);
+    }
+    let opf = read_opf(opf_path)?;
+    let source = opf.source();
+    let Some(min_score) = ctx.config.fetch.source_min_score(source) else {
+        return Ok(None);
+    };
+    let snap = opf_snapshot(&opf, cover_path.exists());
+    let (score, _) = score_good_enough(&snap, &ctx.config.scoring);
+    if score >= min_score {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "fetched metadata from {} scored {score}, below fetch.source_min_score {min_score}",
+        source.unwrap_or("unknown source")
+    )))
+}
+
+/// `--dry-run-network`: fetches into the workdir and logs how the result
+/// differs from the book's current metadata. Nothing is applied or recorded;
+/// a failed fetch is "fetch_failed", reported but not a run failure.
+fn dry_run_fetch(
+    ctx: &BookContext,
+    book: &serde_json::Value,
+    snap: &Snapshot,
+    opf_path: &Path,
+    cover_path: &Path,
+) -> Result<String> {
+    let book_id = book_id_of(book);
+    ctx.throttle.wait();
+    let (ok_fetch, msg_fetch, _) = fetch_metadata(ctx, book, opf_path, cover_path)?;
+    if !ok_fetch {
+        warn!(id = book_id, error = %msg_fetch, "[dry-run-network] fetch failed");
+        return Ok("fetch_failed".to_string());
+    }
+    let opf = read_opf(opf_path)?;
+    let fetched = opf_snapshot(&opf, cover_path.exists());
+    let (score_before, _) = score_good_enough(snap, &ctx.config.scoring);
+    let (score_after, _) = score_good_enough(&fetched, &ctx.config.scoring);
+    info!(
+        id = book_id,
+        source = opf.source().unwrap_or("unknown"),
+        score_before,
+        score_after,
+        changed = %changed_fields(snap, &fetched).join(","),
+        "[dry-run-network] fetched"
+    );
+    if let Some(reason) = below_source_min_score(ctx, opf_path, cover_path)? {
+        info!(id = book_id, reason = %reason, "[dry-run-network] result would be rejected");
+    }
+    Ok("updated".to_string())
+}
+
+/// What [`process_one_book`] did with a book.
+enum BookOutcome {
+    /// The action taken or planned: "done", "updated", "failed", ...
+    Action(String),
+    /// Not processed, and no state left behind
+    Skipped(SkipReason),
+}
+
+fn process_one_book(
+    ctx: &BookContext,
+    store: &StateStore,
+    book: &serde_json::Value,
+    verifier: &EmbedVerifier,
+) -> Result<BookOutcome> {
     let book_id = book
         .get("id")
         .and_then(|v| v.as_i64())
@@ -50,26 +1026,38 @@ fn process_one_book(
     let snap = metadata_snapshot(book);
     let h = snapshot_hash(&snap)?;
 
-    let prev = get_book_state(state, book_id);
-    if let Some(prev_state) = &prev {
-        if ["done", "skipped_good_enough", "embedded_only", "failed_permanent"]
-            .contains(&prev_state.status.as_str())
-            && (!reprocess_on_metadata_change || prev_state.last_hash == h)
-        {
-            let reason = if !reprocess_on_metadata_change {
-                "already processed"
-            } else {
-                "already processed for current metadata hash"
-            };
-            info!(id = book_id, title = %title, reason = %reason, "[skip]");
-            return Ok("skipped".to_string());
-        }
+    let prev = store.get(book_id);
+    // Finished books that --force-reembed sends back through: embed, never fetch
+    let reembed = ctx.force_reembed
+        && prev
+            .as_ref()
+            .is_some_and(|p| TERMINAL_STATUSES.contains(&p.status.as_str()));
+    if !ctx.force
+        && !reembed
+        && let Some(prev_state) = &prev
+        && TERMINAL_STATUSES.contains(&prev_state.status.as_str())
+        && (!ctx.config.policy.reprocess_on_metadata_change || prev_state.last_hash == h)
+    {
+        let reason = if !ctx.config.policy.reprocess_on_metadata_change {
+            "already processed"
+        } else {
+            "already processed for current metadata hash"
+        };
+        info!(id = book_id, title = %title, reason = %reason, "[skip]");
+        return Ok(BookOutcome::Action("skipped".to_string()));
     }
 
+    let scoring = &ctx.config.scoring;
     let (score, reasons) = score_good_enough(&snap, scoring);
-    let good_enough = score >= scoring.min_score_to_skip_fetch
-        && (!scoring.require_title || !snap.title.is_empty())
-        && (!scoring.require_authors || !snap.authors.is_empty());
+    let good_enough = is_good_enough(score, &snap, scoring);
+    if !good_enough
+        && !reembed
+        && ctx.config.fetch.require_strong_identifier
+        && fetch_match_key(book, &ctx.config.fetch) == "title"
+    {
+        info!(id = book_id, title = %title, reason = NO_STRONG_IDENTIFIER, "[skip] fetch");
+        return Ok(BookOutcome::Skipped(SkipReason::NoStrongIdentifier));
+    }
 
     let started = BookState {
         status: "started".to_string(),
@@ -78,57 +1066,138 @@ fn process_one_book(
         last_ok_utc: prev.as_ref().and_then(|p| p.last_ok_utc.clone()),
         message: Some("started".to_string()),
         fail_count: prev.as_ref().map(|p| p.fail_count).unwrap_or(0),
+        retry_after_utc: None,
+        source: prev.as_ref().and_then(|p| p.source.clone()),
+        matched_by: prev.as_ref().and_then(|p| p.matched_by.clone()),
     };
-    put_book_state(state, book_id, started);
-    save_state(state_path, state)?;
+    // Not persisted on its own: until a later put, the file keeps the previous state
+    store.stage(book_id, started);
 
-    if good_enough {
-        info!(
-            id = book_id,
-            title = %title,
-            score,
-            "[good-enough] embedding only"
-        );
-        if dry_run {
+    if good_enough || reembed {
+        let why = if good_enough { "good enough" } else { "forced re-embed" };
+        if good_enough {
+            info!(id = book_id, title = %title, score, "[good-enough] embedding only");
+        } else {
+            info!(id = book_id, title = %title, score, "[force-reembed] embedding only");
+        }
+        ctx.progress(book_id, &title, "embed", "started");
+        if ctx.config.policy.dry_run {
+            ctx.print_plan(book_id, "embed", score, &reasons, &snap)?;
             info!(
                 id = book_id,
                 title = %title,
-                formats = %target_formats.keys().cloned().collect::<Vec<_>>().join(","),
+                formats = %ctx.target_formats.keys().cloned().collect::<Vec<_>>().join(","),
                 "[dry-run] embed metadata"
             );
-            return Ok("embedded_only".to_string());
+            return Ok(BookOutcome::Action("embedded_only".to_string()));
         }
 
-        let (ok_embed, msg_embed) =
-            embed_metadata_into_formats(runner, lib, book_id, target_formats)?;
+        if let Some((path, bytes)) = oversized_format_file(ctx, book) {
+            info!(
+                id = book_id,
+                title = %title,
+                path = %path.display(),
+                bytes,
+                "[skip] embed (file over max_embed_file_bytes)"
+            );
+            let bs = BookState {
+                status: "db_only_large_file".to_string(),
+                last_hash: h,
+                last_attempt_utc: now_iso(),
+                last_ok_utc: Some(now_iso()),
+                message: Some(format!("{why}; embed skipped ({bytes} bytes)")),
+                fail_count: 0,
+                retry_after_utc: None,
+                source: prev.as_ref().and_then(|p| p.source.clone()),
+                matched_by: prev.as_ref().and_then(|p| p.matched_by.clone()),
+            };
+            store.put(book_id, bs)?;
+            return Ok(BookOutcome::Action("done".to_string()));
+        }
+
+        let Some(embed_formats) = ctx.embed_formats(book_id, book) else {
+            info!(id = book_id, title = %title, "[skip] embed (formats.non_embeddable only)");
+            let bs = BookState {
+                status: "done_no_embed".to_string(),
+                last_hash: h,
+                last_attempt_utc: now_iso(),
+                last_ok_utc: Some(now_iso()),
+                message: Some(format!("{why}; no embeddable format")),
+                fail_count: 0,
+                retry_after_utc: None,
+                source: prev.as_ref().and_then(|p| p.source.clone()),
+                matched_by: prev.as_ref().and_then(|p| p.matched_by.clone()),
+            };
+            store.put(book_id, bs)?;
+            return Ok(BookOutcome::Action("done".to_string()));
+        };
+        if let Err(e) = backup_book_metadata(ctx, book_id) {
+            let msg = format!("backup failed: {e:#}");
+            let bs = failed_state(ctx.config, book_id, "backup", prev.as_ref(), h, msg.clone());
+            store.put(book_id, bs)?;
+            warn!(id = book_id, title = %title, error = %msg, "[skip] backup");
+            return Ok(BookOutcome::Action("failed".to_string()));
+        }
+        let embed = embed_metadata_into_formats(
+            ctx.runner,
+            ctx.lib,
+            book_id,
+            &embed_formats,
+            ctx.config.policy.embed_all_formats_independently,
+        )?;
+        let ok_embed = !embed.embedded.is_empty();
+        let msg_embed = embed.message();
+        if !ok_embed {
+            let bs = failed_state(
+                ctx.config,
+                book_id,
+                "embed",
+                prev.as_ref(),
+                h,
+                format!("{} ({why} reasons: {})", msg_embed, reasons.join(", ")),
+            );
+            store.put(book_id, bs)?;
+            warn!(id = book_id, title = %title, error = %msg_embed, "[fail] embed");
+            ctx.progress(book_id, &title, "embed", "failed");
+            return Ok(BookOutcome::Action("failed".to_string()));
+        }
+        let mut h = h;
+        let tag_note = tag_processed_book(ctx, book_id, &title)?;
+        // The tag is part of the hashed metadata; rehash so it doesn't look changed next run
+        if tag_note.is_some()
+            && let Some(refreshed) =
+                refresh_one_book(ctx.runner, ctx.lib, book_id, ctx.custom_columns)?
+        {
+            h = snapshot_hash(&metadata_snapshot(&refreshed))?;
+        }
+        let verified = embed.partial()
+            || verify_embedded_sample(ctx, verifier, book_id, book, &embed.embedded_formats())?;
+        let status = if !verified && ctx.config.policy.verify_embed {
+            "embed_unverified"
+        } else {
+            "embedded_only"
+        };
         let bs = BookState {
-            status: if ok_embed { "embedded_only".to_string() } else { "failed".to_string() },
+            status: status.to_string(),
             last_hash: h,
             last_attempt_utc: now_iso(),
-            last_ok_utc: if ok_embed {
-                Some(now_iso())
-            } else {
-                prev.as_ref().and_then(|p| p.last_ok_utc.clone())
-            },
-            message: Some(if ok_embed {
-                "good enough; embedded".to_string()
-            } else {
-                format!("{} (good enough reasons: {})", msg_embed, reasons.join(", "))
+            last_ok_utc: Some(now_iso()),
+            message: Some(match &tag_note {
+                Some(note) => format!("{why}; {msg_embed}; {note}"),
+                None => format!("{why}; {msg_embed}"),
             }),
-            fail_count: if ok_embed {
-                0
-            } else {
-                prev.as_ref().map(|p| p.fail_count + 1).unwrap_or(1)
-            },
+            fail_count: 0,
+            retry_after_utc: None,
+            source: prev.as_ref().and_then(|p| p.source.clone()),
+            matched_by: prev.as_ref().and_then(|p| p.matched_by.clone()),
         };
-        put_book_state(state, book_id, bs);
-        save_state(state_path, state)?;
-        if ok_embed {
-            info!(id = book_id, title = %title, "[done] good enough; embedded");
+        store.put(book_id, bs)?;
+        if embed.partial() {
+            warn!(id = book_id, title = %title, result = %msg_embed, why, "[partial] embed");
         } else {
-            warn!(id = book_id, title = %title, error = %msg_embed, "[fail] embed");
+            info!(id = book_id, title = %title, status, why, "[done] embedded");
         }
-        return Ok(if ok_embed { "done".to_string() } else { "failed".to_string() });
+        return Ok(BookOutcome::Action("done".to_string()));
     }
 
     info!(
@@ -138,120 +1207,226 @@ fn process_one_book(
         missing = %reasons.join(", "),
         "[work] fetch metadata"
     );
+    ctx.progress(book_id, &title, "fetch", "started");
 
-    let opf_path = workdir.join(format!("{book_id}.opf"));
-    let cover_path = workdir.join(format!("{book_id}.cover.jpg"));
+    let opf_path = ctx.artifact_path(book_id, "opf");
+    let cover_path = ctx.artifact_path(book_id, "cover.jpg");
 
-    if dry_run {
+    if ctx.config.policy.dry_run {
+        ctx.print_plan(book_id, "fetch", score, &reasons, &snap)?;
+        if ctx.fetch_in_dry_run {
+            return dry_run_fetch(ctx, book, &snap, &opf_path, &cover_path).map(BookOutcome::Action);
+        }
         info!(
             id = book_id,
             title = %title,
-            formats = %target_formats.keys().cloned().collect::<Vec<_>>().join(","),
+            formats = %ctx.target_formats.keys().cloned().collect::<Vec<_>>().join(","),
             "[dry-run] fetch -> apply -> embed"
         );
-        return Ok("updated".to_string());
+        return Ok(BookOutcome::Action("updated".to_string()));
     }
 
-    let (ok_fetch, msg_fetch) = fetch_metadata_to_opf_and_cover(
-        runner,
-        book,
-        &opf_path,
-        &cover_path,
-        fetch_timeout_seconds,
-        fetch_heartbeat_seconds,
-    )?;
+    ctx.throttle.wait();
+    let (ok_fetch, msg_fetch, external) = fetch_metadata(ctx, book, &opf_path, &cover_path)?;
     if !ok_fetch {
-        let status = if msg_fetch.contains("timed out") {
-            "failed_permanent"
-        } else {
-            "failed"
-        };
-        let bs = BookState {
-            status: status.to_string(),
-            last_hash: h,
-            last_attempt_utc: now_iso(),
-            last_ok_utc: prev.as_ref().and_then(|p| p.last_ok_utc.clone()),
-            message: Some(msg_fetch.clone()),
-            fail_count: prev.as_ref().map(|p| p.fail_count + 1).unwrap_or(1),
-        };
-        put_book_state(state, book_id, bs);
-        save_state(state_path, state)?;
-        warn!(id = book_id, title = %title, error = %msg_fetch, "[skip] fetch");
-        return Ok("failed".to_string());
+        let category = fetch_failure_category(&msg_fetch);
+        let bs = failed_state(ctx.config, book_id, category, prev.as_ref(), h, msg_fetch.clone());
+        let status = bs.status.clone();
+        store.put(book_id, bs)?;
+        warn!(id = book_id, title = %title, error = %msg_fetch, category, "[skip] fetch");
+        ctx.progress(book_id, &title, "fetch", &status);
+        apply_first_page_cover_fallback(ctx, book_id, book, &snap)?;
+        return Ok(BookOutcome::Action("failed".to_string()));
     }
+    if let Some(reason) = below_source_min_score(ctx, &opf_path, &cover_path)? {
+        let category = "fetch_no_match";
+        let bs = failed_state(ctx.config, book_id, category, prev.as_ref(), h, reason.clone());
+        let status = bs.status.clone();
+        store.put(book_id, bs)?;
+        warn!(id = book_id, title = %title, reason = %reason, "[skip] fetched metadata rejected");
+        ctx.progress(book_id, &title, "fetch", &status);
+        apply_first_page_cover_fallback(ctx, book_id, book, &snap)?;
+        return Ok(BookOutcome::Action("failed".to_string()));
+    }
+    let (source, matched_by) = if external {
+        ("external", None)
+    } else {
+        let opf = read_opf(&opf_path).ok();
+        let source = opf.as_ref().and_then(|o| o.source()).unwrap_or("unknown");
+        (source, Some(fetch_match_key(book, &ctx.config.fetch)))
+    };
+    debug!(id = book_id, source, matched_by = ?matched_by, "[fetch] provenance");
 
-    if delay_between_fetches_seconds > 0.0 {
-        std::thread::sleep(Duration::from_secs_f64(delay_between_fetches_seconds));
+    if let Err(e) = backup_book_metadata(ctx, book_id) {
+        let msg = format!("backup failed: {e:#}");
+        let bs = failed_state(ctx.config, book_id, "backup", prev.as_ref(), h, msg.clone());
+        store.put(book_id, bs)?;
+        warn!(id = book_id, title = %title, error = %msg, "[skip] backup");
+        ctx.progress(book_id, &title, "apply", "failed");
+        return Ok(BookOutcome::Action("failed".to_string()));
     }
 
-    let (ok_set, msg_set) = apply_opf_to_calibre_db(runner, lib, book_id, &opf_path)?;
+    ctx.progress(book_id, &title, "apply", "started");
+    let settable_fields = &ctx.config.policy.settable_fields;
+    let apply = |cover: Option<&Path>| {
+        if settable_fields.is_empty() {
+            apply_opf_to_calibre_db(ctx.runner, ctx.lib, book_id, &opf_path, cover)
+        } else {
+            apply_opf_fields_to_calibre_db(
+                ctx.runner,
+                ctx.lib,
+                book_id,
+                &opf_path,
+                settable_fields,
+                cover,
+            )
+        }
+    };
+    let cover = (ctx.config.policy.may_set("cover")
+        && cover_path.metadata().is_ok_and(|m| m.len() > 0))
+    .then_some(cover_path.as_path());
+    let (mut ok_set, mut msg_set) = apply(cover)?;
+    let cover_applied = ok_set && cover.is_some();
+    if !ok_set && cover.is_some() {
+        warn!(
+            id = book_id,
+            title = %title,
+            error = %msg_set,
+            "[apply] combined metadata+cover call failed; retrying separately"
+        );
+        (ok_set, msg_set) = apply(None)?;
+    }
     if !ok_set {
-        let bs = BookState {
-            status: "failed".to_string(),
-            last_hash: h,
-            last_attempt_utc: now_iso(),
-            last_ok_utc: prev.as_ref().and_then(|p| p.last_ok_utc.clone()),
-            message: Some(msg_set.clone()),
-            fail_count: prev.as_ref().map(|p| p.fail_count + 1).unwrap_or(1),
-        };
-        put_book_state(state, book_id, bs);
-        save_state(state_path, state)?;
+        let bs = failed_state(ctx.config, book_id, "apply", prev.as_ref(), h, msg_set.clone());
+        store.put(book_id, bs)?;
         warn!(id = book_id, title = %title, error = %msg_set, "[skip] set_metadata");
-        return Ok("failed".to_string());
+        ctx.progress(book_id, &title, "apply", "failed");
+        return Ok(BookOutcome::Action("failed".to_string()));
     }
+    // The library has changed; checkpoint that before the slower cover/embed steps
+    let applied = BookState {
+        status: "started".to_string(),
+        last_hash: h.clone(),
+        last_attempt_utc: now_iso(),
+        last_ok_utc: prev.as_ref().and_then(|p| p.last_ok_utc.clone()),
+        message: Some("metadata applied; embed pending".to_string()),
+        fail_count: prev.as_ref().map(|p| p.fail_count).unwrap_or(0),
+        retry_after_utc: None,
+        source: Some(source.to_string()),
+        matched_by: matched_by.map(str::to_string),
+    };
+    store.put(book_id, applied)?;
 
-    let (ok_cov, msg_cov) = apply_cover_to_calibre_db(runner, lib, book_id, &cover_path)?;
-    if !ok_cov {
-        warn!(id = book_id, title = %title, error = %msg_cov, "[warn] cover");
+    if ctx.config.policy.may_set("cover") && !cover_applied {
+        ctx.progress(book_id, &title, "cover", "started");
+        let (ok_cov, msg_cov) =
+            apply_cover_to_calibre_db(ctx.runner, ctx.lib, book_id, &cover_path)?;
+        if !ok_cov {
+            warn!(id = book_id, title = %title, error = %msg_cov, "[warn] cover");
+            ctx.progress(book_id, &title, "cover", "failed");
+        } else if !cover_path.exists() {
+            apply_first_page_cover_fallback(ctx, book_id, book, &snap)?;
+        }
     }
 
-    let (ok_embed, msg_embed) =
-        embed_metadata_into_formats(runner, lib, book_id, target_formats)?;
+    ctx.progress(book_id, &title, "embed", "started");
+    let oversized = oversized_format_file(ctx, book);
+    let embed_formats = ctx.embed_formats(book_id, book);
+    let (ok_embed, partial, msg_embed, embedded) = match (&oversized, &embed_formats) {
+        (Some((path, bytes)), _) => {
+            info!(
+                id = book_id,
+                title = %title,
+                path = %path.display(),
+                bytes,
+                "[skip] embed (file over max_embed_file_bytes)"
+            );
+            (true, false, format!("embed skipped ({bytes} bytes)"), BTreeSet::new())
+        }
+        (None, None) => {
+            info!(id = book_id, title = %title, "[skip] embed (formats.non_embeddable only)");
+            (true, false, "embed skipped (no embeddable format)".to_string(), BTreeSet::new())
+        }
+        (None, Some(embed_formats)) => {
+            let embed = embed_metadata_into_formats(
+                ctx.runner,
+                ctx.lib,
+                book_id,
+                embed_formats,
+                ctx.config.policy.embed_all_formats_independently,
+            )?;
+            let embedded = embed.embedded_formats();
+            (!embed.embedded.is_empty(), embed.partial(), embed.message(), embedded)
+        }
+    };
     if !ok_embed {
-        let bs = BookState {
-            status: "failed".to_string(),
-            last_hash: h,
-            last_attempt_utc: now_iso(),
-            last_ok_utc: prev.as_ref().and_then(|p| p.last_ok_utc.clone()),
-            message: Some(msg_embed.clone()),
-            fail_count: prev.as_ref().map(|p| p.fail_count + 1).unwrap_or(1),
-        };
-        put_book_state(state, book_id, bs);
-        save_state(state_path, state)?;
+        let bs = failed_state(ctx.config, book_id, "embed", prev.as_ref(), h, msg_embed.clone());
+        store.put(book_id, bs)?;
         warn!(id = book_id, title = %title, error = %msg_embed, "[skip] embed");
-        return Ok("failed".to_string());
+        ctx.progress(book_id, &title, "embed", "failed");
+        return Ok(BookOutcome::Action("failed".to_string()));
     }
 
-    let refreshed = refresh_one_book(runner, lib, book_id)?;
-    let new_snap = if let Some(refreshed_book) = refreshed {
-        metadata_snapshot(&refreshed_book)
-    } else {
-        snap
+    let tag_note = tag_processed_book(ctx, book_id, &title)?;
+    let refreshed = refresh_one_book(ctx.runner, ctx.lib, book_id, ctx.custom_columns)?;
+    let checked_book = refreshed.as_ref().unwrap_or(book);
+    let verified = oversized.is_some()
+        || embed_formats.is_none()
+        || partial
+        || verify_embedded_sample(ctx, verifier, book_id, checked_book, &embedded)?;
+    let new_snap = match &refreshed {
+        Some(refreshed_book) => metadata_snapshot(refreshed_book),
+        // refresh_one_book has warned; fall back to the pre-embed snapshot
+        None => snap,
     };
     let new_hash = snapshot_hash(&new_snap)?;
 
+    let (status, summary) = if oversized.is_some() {
+        ("db_only_large_file", format!("fetched+applied; {msg_embed}"))
+    } else if embed_formats.is_none() {
+        ("done_no_embed", format!("fetched+applied; {msg_embed}"))
+    } else if partial {
+        warn!(id = book_id, title = %title, result = %msg_embed, "[partial] embed");
+        ("done", format!("fetched+applied; {msg_embed}"))
+    } else if !verified && ctx.config.policy.verify_embed {
+        let summary = "fetched+applied+embedded; embedded metadata differs from db";
+        ("embed_unverified", summary.to_string())
+    } else {
+        ("done", "fetched+applied+embedded".to_string())
+    };
     let bs = BookState {
-        status: "done".to_string(),
+        status: status.to_string(),
         last_hash: new_hash,
         last_attempt_utc: now_iso(),
         last_ok_utc: Some(now_iso()),
-        message: Some("fetched+applied+embedded".to_string()),
+        message: Some(match &tag_note {
+            Some(note) => format!("{summary}; {note}"),
+            None => summary,
+        }),
         fail_count: 0,
+        retry_after_utc: None,
+        source: Some(source.to_string()),
+        matched_by: matched_by.map(str::to_string),
     };
-    put_book_state(state, book_id, bs);
-    save_state(state_path, state)?;
-    info!(id = book_id, title = %title, "[done] updated + embedded");
-    Ok("done".to_string())
+    store.put(book_id, bs)?;
+    info!(id = book_id, title = %title, status, "[done] updated");
+    Ok(BookOutcome::Action("done".to_string()))
 }
 
-pub fn run() -> Result<()> {
-    let args = Args::parse();
+pub fn run() -> Result<RunOutcome> {
+    let run_started = Instant::now();
+    let mut args = Args::parse();
 
     let config_path = PathBuf::from(&args.config);
-    let mut config = load_config(&config_path)?;
+    let (mut config, unknown_keys, legacy_keys) = load_config(&config_path)?;
     config.library.path = normalize_optional_string(config.library.path);
+    config.logging.file = normalize_optional_string(config.logging.file);
     config.library.url = normalize_optional_string(config.library.url);
     config.state.path = normalize_optional_string(config.state.path);
+    config.fetch.workdir = normalize_optional_string(config.fetch.workdir);
+    config.fetch.external_provider = normalize_optional_string(config.fetch.external_provider);
+    config.policy.processed_tag = normalize_optional_string(config.policy.processed_tag);
+    config.policy.saved_search = normalize_optional_string(config.policy.saved_search);
     config.content_server.username = normalize_optional_string(config.content_server.username);
     config.content_server.password = normalize_optional_string(config.content_server.password);
 
@@ -268,19 +1443,90 @@ pub fn run() -> Result<()> {
     if args.calibre_password.is_some() {
         config.content_server.password = args.calibre_password.clone();
     }
-    if args.dry_run {
+    if args.dry_run || args.dry_run_network {
         config.policy.dry_run = true;
     }
+    if args.cover_from_first_page {
+        config.fetch.pdf_first_page_cover = true;
+    }
+    if args.isbn_only {
+        config.fetch.require_strong_identifier = true;
+    }
+    if let Some(jobs) = args.jobs {
+        config.policy.max_concurrency = jobs;
+    }
+    if args.max_fail_count.is_some() {
+        config.policy.max_fail_count = args.max_fail_count;
+    }
+    // Calibre lookup names are lowercase; accept "name", "#name" or "*name"
+    config.scoring.custom_columns = std::mem::take(&mut config.scoring.custom_columns)
+        .into_iter()
+        .map(|(k, w)| (format!("#{}", k.trim().trim_start_matches(['#', '*']).to_lowercase()), w))
+        .collect();
 
-    init_tracing(&config.logging.level);
+    let _log_guard = init_tracing(&config.logging)?;
+    for (old, new) in &legacy_keys {
+        warn!(key = %old, now = %new, "[config] legacy top-level key; move it into its section");
+    }
+    if !args.config_check {
+        for key in &unknown_keys {
+            warn!(key = %key, "[config] unknown key ignored (typo?)");
+        }
+    }
+
+    if let Some(Command::ValidateOpf(validate_args)) = &args.command {
+        run_validate_opf(&config.scoring, validate_args)?;
+        return Ok(RunOutcome::Clean);
+    }
+
+    if let Some(Command::DiffState(diff_args)) = &args.command {
+        let (state_path, _) = resolve_state_path(&args, &config)?;
+        run_diff_state(&state_path, diff_args)?;
+        return Ok(RunOutcome::Clean);
+    }
+
+    // The url silently wins below; say so unless the command line settled it
+    if !matches!(&args.command, Some(Command::Dups(_)))
+        && let (Some(using), Some(ignored)) = resolve_library(&config, &args, args.strict_config)?
+    {
+        warn!(
+            using = %using,
+            ignored = %ignored,
+            "[config] both library.path and library.url are set; using library.url"
+        );
+    }
+
+    let dups_has_library =
+        matches!(&args.command, Some(Command::Dups(d)) if !d.library.is_empty());
+    if config.library.path.is_none()
+        && config.library.url.is_none()
+        && !dups_has_library
+        && let Some(found) = discover_default_library()
+    {
+        info!(library = %found.display(), "[info] using library from Calibre's global config");
+        config.library.path = Some(found.display().to_string());
+    }
+
+    if args.config_check {
+        return config_check(&config, &unknown_keys, args.output);
+    }
 
     if let Some(Command::Dups(dups_args)) = &args.command {
-        let lib_override = dups_args.library.clone();
-        let lib_path = lib_override
-            .or_else(|| config.library.path.clone().map(PathBuf::from))
-            .ok_or_else(|| anyhow::anyhow!("Missing library path for dups"))?;
-        if !lib_path.is_dir() {
-            anyhow::bail!("Library path does not exist or is not a directory: {}", lib_path.display());
+        let libraries = if dups_args.library.is_empty() {
+            let lib_path = config
+                .library
+                .path
+                .clone()
+                .map(PathBuf::from)
+                .ok_or_else(|| anyhow::anyhow!("Missing library path for dups"))?;
+            vec![lib_path]
+        } else {
+            dups_args.library.clone()
+        };
+        for lib_path in &libraries {
+            if !lib_path.is_dir() {
+                anyhow::bail!("Library path does not exist or is not a directory: {}", lib_path.display());
+            }
         }
         let output = dups_args
             .output
@@ -301,6 +1547,11 @@ pub fn run() -> Result<()> {
         } else {
             dups_args.min_size
         };
+        let max_size = if dups_args.max_size == 0 {
+            config.dups.max_size
+        } else {
+            dups_args.max_size
+        };
         let include_sidecars = if dups_args.include_sidecars {
             true
         } else {
@@ -311,6 +1562,16 @@ pub fn run() -> Result<()> {
         } else {
             config.dups.follow_symlinks
         };
+        let parallel_walk = if dups_args.parallel_walk {
+            true
+        } else {
+            config.dups.parallel_walk
+        };
+        let hash = dups_args
+            .hash
+            .or_else(|| HashAlgo::parse(&config.dups.hash))
+            .unwrap_or(HashAlgo::Blake3);
+        let fast = dups_args.fast || config.dups.fast;
         let settings = DupsSettings {
             output,
             out,
@@ -318,31 +1579,69 @@ pub fn run() -> Result<()> {
             follow_symlinks,
             threads,
             min_size,
+            max_size,
             include_sidecars,
+            parallel_walk,
+            hash,
+            fast,
+            action: dups_args.action,
+            yes: dups_args.yes,
+            library_roots: dups_args.library_root.clone(),
         };
-        return run_dups(&lib_path, &settings);
+        run_dups(&libraries, &settings)?;
+        return Ok(RunOutcome::Clean);
     }
 
     require_tool("calibredb")?;
-    require_tool("fetch-ebook-metadata")?;
 
-    let lib_raw = config
-        .library
-        .url
-        .clone()
-        .or(config.library.path.clone())
+    let lib_raw = resolve_library(&config, &args, false)?
+        .0
         .ok_or_else(|| anyhow::anyhow!("Missing library or library_url in config"))?;
     let lib = normalize_library_spec(&lib_raw);
-    let is_remote = lib.starts_with("http://") || lib.starts_with("https://");
-    let state_path = if let Some(p) = config.state.path.clone() {
-        PathBuf::from(p)
-    } else {
-        default_state_path()?
+    let is_remote = is_remote_library(&lib);
+    let (state_path, state_source) = resolve_state_path(&args, &config)?;
+    let state_display = std::path::absolute(&state_path).unwrap_or_else(|_| state_path.clone());
+    info!(
+        state = %state_display.display(),
+        source = state_source,
+        exists = state_path.exists(),
+        "[info] state"
+    );
+    // --explain only reads, so it may run beside another instance
+    let _run_lock = match acquire_run_lock(&state_path)? {
+        Some(lock) => Some(lock),
+        None if args.force || args.explain.is_some() => {
+            warn!(
+                state = %state_path.display(),
+                "[lock] another instance holds the state lock; continuing (--force)"
+            );
+            None
+        }
+        None => anyhow::bail!(
+            "another instance is running against {} (pass --force to run anyway)",
+            state_path.display()
+        ),
     };
 
     if !is_remote && !Path::new(&lib).is_dir() {
         anyhow::bail!("Library path does not exist or is not a directory: {lib}");
     }
+    if config.fetch.pdf_first_page_cover {
+        if is_remote {
+            warn!("[info] pdf_first_page_cover ignored for remote libraries");
+            config.fetch.pdf_first_page_cover = false;
+        } else if which::which("pdftoppm").is_err() {
+            warn!("[info] pdftoppm not found on PATH; pdf first-page covers disabled");
+            config.fetch.pdf_first_page_cover = false;
+        }
+    }
+    if config.policy.verify_sample_rate > 0.0 {
+        if is_remote {
+            warn!("[info] verify_sample_rate ignored for remote libraries (format files are not local)");
+        } else {
+            require_tool("ebook-meta")?;
+        }
+    }
 
     let target_formats: BTreeMap<String, ()> = config
         .formats
@@ -355,25 +1654,256 @@ pub fn run() -> Result<()> {
     if target_formats.is_empty() {
         anyhow::bail!("No formats specified. Set formats in config.toml");
     }
+    let format_rules: BTreeMap<String, String> = config
+        .formats
+        .rules
+        .iter()
+        .map(|(k, v)| (k.trim().to_lowercase(), v.clone()))
+        .collect();
+    let custom_columns: Vec<String> = config.scoring.custom_columns.keys().cloned().collect();
 
     let runner = Runner {
         calibredb_env_mode: config.calibredb.env_mode,
+        resolved_env_mode: Mutex::new(None),
         debug_calibredb_env: config.calibredb.debug_env,
         headless_fetch: config.fetch.headless,
         headless_env: config.fetch.headless_env.clone(),
         fetch_use_xvfb: config.fetch.use_xvfb,
         calibre_username: config.content_server.username.clone(),
         calibre_password: config.content_server.password.clone(),
+        fetch_slots: FetchSlots::new(config.fetch.max_concurrent),
+        lock_retries: config.calibredb.lock_retries,
+        lock_retry_delay: Duration::from_secs_f64(config.calibredb.lock_retry_seconds.max(0.0)),
+        network_retries: config.calibredb.network_retries,
+        network_retry_delay: Duration::from_secs_f64(
+            config.calibredb.network_retry_seconds.max(0.0),
+        ),
     };
 
-    let mut state = load_state(&state_path)?;
-    let books = list_candidate_books(
-        &runner,
-        &lib,
-        config.policy.include_missing_language,
-        &config.policy.english_codes,
-        &target_formats,
-    )?;
+    if let Some(Command::Verify(verify_args)) = &args.command {
+        run_verify(&runner, &lib, &state_path, verify_args, config.logging.local_time())?;
+        return Ok(RunOutcome::Clean);
+    }
+    if let Some(Command::Restore(restore_args)) = &args.command {
+        let all_ok = run_restore(&runner, &lib, &backup_dir(&state_path), restore_args)?;
+        return Ok(if all_ok { RunOutcome::Clean } else { RunOutcome::BooksFailed });
+    }
+    if let Some(Command::MergeDuplicates(merge_args)) = &args.command {
+        let all_ok = run_merge_duplicates(&runner, &lib, is_remote, &config.scoring, merge_args)?;
+        return Ok(if all_ok { RunOutcome::Clean } else { RunOutcome::BooksFailed });
+    }
+    // enrich-file adds the file, then runs as `--only-ids <new id>`
+    if let Some(Command::EnrichFile(enrich_args)) = &args.command {
+        let path = enrich_args.path.clone();
+        if !path.is_file() {
+            anyhow::bail!("File not found: {}", path.display());
+        }
+        if config.policy.dry_run {
+            info!(path = %path.display(), "[dry-run] would add the file and enrich it");
+            return Ok(RunOutcome::Clean);
+        }
+        // Checked before the add, so a missing tool doesn't leave a bare import behind
+        require_tool("fetch-ebook-metadata")?;
+        let book_id = add_book_file(&runner, &lib, &path)?;
+        info!(id = book_id, path = %path.display(), "[enrich] added to the library");
+        args.only_ids = vec![book_id];
+    }
+    require_tool("fetch-ebook-metadata")?;
+
+    let mut search_clauses = Vec::new();
+    if let Some(raw) = &args.added_since {
+        let date = parse_date_arg(raw)
+            .with_context(|| format!("Invalid --added-since value: {raw}"))?;
+        search_clauses.push(format!("timestamp:>={date}"));
+    }
+    search_clauses.extend(exact_match_clause("authors", &args.author));
+    let since = args
+        .since
+        .as_deref()
+        .map(|raw| parse_since_arg(raw).with_context(|| format!("Invalid --since value: {raw}")))
+        .transpose()?;
+    if let Some(percent) = args.percent
+        && !(0.0..=100.0).contains(&percent)
+    {
+        anyhow::bail!("--percent must be between 0 and 100, got {percent}");
+    }
+
+    let store = StateStore::new(
+        load_state(&state_path)?,
+        StateSaver::new(
+            &state_path,
+            config.state.adaptive_flush,
+            Duration::from_millis(config.state.slow_save_ms),
+            config.state.max_flush_interval,
+        ),
+    );
+    let query = CandidateQuery {
+        include_missing_language: config.policy.include_missing_language,
+        english_codes: &config.policy.english_codes,
+        target_formats: &target_formats,
+        format_rules: &format_rules,
+        custom_columns: &custom_columns,
+        extra_clauses: &search_clauses,
+        saved_search: config.policy.saved_search.as_deref(),
+        include_tags: &config.policy.include_tags,
+        exclude_tags: &config.policy.exclude_tags,
+        comments_presence_only: config.calibredb.comments_presence_only,
+    };
+    if args.explain.is_some() {
+        let text = explain_book(&runner, &lib, &args, &config, &query, &store, since)?;
+        write_output(&text, None)?;
+        return Ok(RunOutcome::Clean);
+    }
+    let (mut books, filtered) = list_candidate_books(&runner, &lib, &query)?;
+    let identities: Vec<(i64, String)> = books
+        .iter()
+        .filter_map(|b| Some((book_id_of(b), identity_key(b)?)))
+        .collect();
+    let dry_run = config.policy.dry_run;
+    let moves = store.sync_identities(&identities, args.follow_library_moves, !dry_run)?;
+    if !moves.is_empty() {
+        let pairs = moves.iter().map(|(old, new)| format!("{old}->{new}")).collect::<Vec<_>>();
+        if dry_run {
+            info!(
+                count = moves.len(),
+                moves = %pairs.join(","),
+                "[dry-run] would move state entries to new ids"
+            );
+        } else {
+            warn!(
+                count = moves.len(),
+                moves = %pairs.join(","),
+                "[moves] state entries moved to new ids"
+            );
+        }
+    }
+    if args.include_deleted_check || args.prune_deleted {
+        let library_ids = list_book_ids(&runner, &lib, "")?;
+        if library_ids.is_empty() {
+            // An empty listing is far more likely a wrong library than a wiped one
+            warn!("[deleted] library returned no books; leaving state alone");
+        } else {
+            let gone = store.reconcile_deleted(&library_ids, args.prune_deleted, !dry_run)?;
+            let ids = gone.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(",");
+            if !gone.is_empty() && dry_run {
+                info!(
+                    count = gone.len(),
+                    ids = %ids,
+                    pruned = args.prune_deleted,
+                    "[dry-run] would mark or prune state entries for books no longer in the library"
+                );
+            } else if !gone.is_empty() {
+                warn!(
+                    count = gone.len(),
+                    ids = %ids,
+                    pruned = args.prune_deleted,
+                    "[deleted] state entries for books no longer in the library"
+                );
+            }
+        }
+    }
+    // Before the user's narrowing filters, so "nothing new since yesterday" is not an error
+    let listed = books.len();
+    // calibredb does not guarantee id order
+    books.sort_by_key(book_id_of);
+
+    if let Some(start_at) = args.start_at {
+        let before = books.len();
+        books.retain(|b| book_id_of(b) >= start_at);
+        info!(start_at, dropped = before - books.len(), "[info] start-at filter");
+    }
+
+    if let Some(since) = since {
+        let before = books.len();
+        // Books with an unreadable last_modified are kept rather than silently dropped
+        books.retain(|b| {
+            b.get("last_modified")
+                .and_then(|v| v.as_str())
+                .and_then(parse_calibre_datetime)
+                .is_none_or(|modified| modified >= since)
+        });
+        info!(since = %since.to_rfc3339(), dropped = before - books.len(), "[info] since filter");
+    }
+
+    if !args.only_ids.is_empty() {
+        let wanted: HashSet<i64> = args.only_ids.iter().copied().collect();
+        books.retain(|b| wanted.contains(&book_id_of(b)));
+        let found: HashSet<i64> = books.iter().map(book_id_of).collect();
+        for id in &args.only_ids {
+            if !found.contains(id) {
+                warn!(
+                    id,
+                    "[only-ids] not a candidate (wrong language, no target format, or unknown id)"
+                );
+            }
+        }
+        info!(count = books.len(), "[info] only-ids filter");
+    }
+
+    if !is_remote
+        && let Some(ignore) = IgnoreList::load(Path::new(&lib))?
+        && !ignore.is_empty()
+    {
+        let root = Path::new(&lib).canonicalize().unwrap_or_else(|_| PathBuf::from(&lib));
+        let before = books.len();
+        books.retain(|b| {
+            !ignore.is_ignored(book_id_of(b), book_dir_in_library(b, &root).as_deref())
+        });
+        info!(ignored = before - books.len(), file = IGNORE_FILE_NAME, "[info] ignore file");
+    }
+
+    let mut missing_files: Vec<i64> = Vec::new();
+    if !is_remote {
+        books.retain(|b| {
+            let missing = missing_format_files(b, &target_formats);
+            if missing.is_empty() {
+                return true;
+            }
+            let book_id = book_id_of(b);
+            for path in &missing {
+                warn!(id = book_id, path = %path.display(), "[missing] format file not found on disk");
+            }
+            missing_files.push(book_id);
+            false
+        });
+    }
+
+    if let Some(percent) = args.percent {
+        let total = books.len();
+        let keep = ((total as f64) * percent / 100.0).round() as usize;
+        let mut rng = match args.seed {
+            Some(seed) => fastrand::Rng::with_seed(seed),
+            None => fastrand::Rng::new(),
+        };
+        let mut picked: Vec<usize> = (0..total).collect();
+        rng.shuffle(&mut picked);
+        let picked: HashSet<usize> = picked.into_iter().take(keep).collect();
+        // Sampled books keep their id order
+        let mut index = 0;
+        books.retain(|_| {
+            index += 1;
+            picked.contains(&(index - 1))
+        });
+        info!(percent, retained = books.len(), total, seed = ?args.seed, "[info] percent sample");
+    }
+
+    // --only-unprocessed skips them like any other book with state
+    let interrupted_last_run: Vec<i64> = books
+        .iter()
+        .map(book_id_of)
+        .filter(|id| {
+            !args.only_unprocessed && store.get(*id).is_some_and(|s| s.status == "started")
+        })
+        .collect();
+    for id in &interrupted_last_run {
+        let message = store.get(*id).and_then(|s| s.message).unwrap_or_default();
+        warn!(id, last_step = %message, "[interrupted] book was interrupted last run; retrying");
+    }
+    if config.policy.prioritize_interrupted && !interrupted_last_run.is_empty() {
+        let first: HashSet<i64> = interrupted_last_run.iter().copied().collect();
+        // Stable, so both groups stay in id order
+        books.sort_by_key(|b| !first.contains(&book_id_of(b)));
+    }
 
     info!(library = %lib, "[info] library");
     if lib.starts_with("http://") || lib.starts_with("https://") {
@@ -382,23 +1912,148 @@ pub fn run() -> Result<()> {
             "[info] calibre content server auth"
         );
     }
-    info!(state = %state_path.display(), "[info] state");
+    let candidates = books.len();
     info!(
-        candidates = books.len(),
+        candidates,
         formats = %target_formats.keys().cloned().collect::<Vec<_>>().join(","),
         "[info] candidates (English-or-missing-language)"
     );
     if config.policy.dry_run {
         info!("[info] dry-run enabled (no changes will be written)");
     }
+    let warn_above = config.policy.max_candidates_warn;
+    if warn_above > 0 && candidates > warn_above && !config.policy.dry_run && !args.assume_yes {
+        confirm_large_run(candidates, warn_above)?;
+    }
+
+    let progress = args
+        .progress_json
+        .as_deref()
+        .map(ProgressWriter::open)
+        .transpose()?;
+    if let Some(progress) = &progress {
+        progress.listed(books.len());
+    }
+
+    let mut books_with_non_targeted_formats = Vec::new();
+    let mut non_targeted_formats: BTreeMap<String, usize> = BTreeMap::new();
+    for b in &books {
+        let formats = b.get("formats").unwrap_or(&serde_json::Value::Null);
+        let extra: Vec<String> = normalize_formats(formats)
+            .into_iter()
+            .filter(|f| !target_formats.contains_key(f))
+            .collect();
+        if extra.is_empty() {
+            continue;
+        }
+        books_with_non_targeted_formats.push(book_id_of(b));
+        for fmt in extra {
+            *non_targeted_formats.entry(fmt).or_default() += 1;
+        }
+    }
+
+    // verify_embed checks every embedded book
+    let verifier = EmbedVerifier::new(
+        if config.policy.verify_embed { 1.0 } else { config.policy.verify_sample_rate },
+        config.policy.verify_sample_seed,
+    );
+    let throttle = FetchThrottle::new(Duration::from_secs_f64(
+        config.policy.delay_between_fetches_seconds.max(0.0),
+    ));
+    let fetch_cache = match config.fetch.cache_ttl_seconds {
+        Some(ttl) if ttl > 0 && !args.no_cache => {
+            let dir = state_path
+                .parent()
+                .unwrap_or_else(|| Path::new("."))
+                .join("fetch-cache");
+            Some(FetchCache::new(dir, Duration::from_secs(ttl))?)
+        }
+        _ => None,
+    };
+
+    // Holds the temp dir (if any) so it is removed when the run ends.
+    let (_tempdir, workdir) = match &config.fetch.workdir {
+        Some(dir) => {
+            let dir = PathBuf::from(dir);
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create workdir {}", dir.display()))?;
+            if let Some(days) = config.fetch.artifact_retention_days {
+                let removed = cleanup_stale_artifacts(&dir, days)?;
+                info!(
+                    workdir = %dir.display(),
+                    removed,
+                    retention_days = days,
+                    "[info] cleaned stale artifacts"
+                );
+            }
+            (None, dir)
+        }
+        None => {
+            let tmp = tempfile::TempDir::new().context("failed to create temp dir")?;
+            let path = tmp.path().to_path_buf();
+            (Some(tmp), path)
+        }
+    };
+
+    let backup_dir = if config.policy.backup_before_change && !config.policy.dry_run {
+        let dir = backup_dir(&state_path);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create backup dir {}", dir.display()))?;
+        Some(dir)
+    } else {
+        None
+    };
+
+    let artifacts_dir = match &args.artifacts_dir {
+        Some(dir) => {
+            let dir = PathBuf::from(dir);
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create artifacts dir {}", dir.display()))?;
+            Some(dir)
+        }
+        None => None,
+    };
+
+    let run_nonce = format!("{}-{}", chrono::Utc::now().format("%Y%m%d%H%M%S"), std::process::id());
+    let ctx = BookContext {
+        runner: &runner,
+        config: &config,
+        workdir: &workdir,
+        run_nonce: &run_nonce,
+        discard_artifacts: config.fetch.workdir.is_none() || !config.fetch.keep_artifacts,
+        lib: &lib,
+        target_formats: &target_formats,
+        custom_columns: &custom_columns,
+        throttle: &throttle,
+        fetch_cache: fetch_cache.as_ref(),
+        force: !args.only_ids.is_empty(),
+        force_reembed: args.force_reembed,
+        progress: progress.as_ref(),
+        plan_format: args.output,
+        all_formats: args.all_formats,
+        backup_dir: backup_dir.as_deref(),
+        fetch_in_dry_run: args.dry_run_network,
+    };
+    let tally = Mutex::new(Tally::default());
 
-    let mut ok = 0;
-    let mut fail = 0;
-    let mut skipped = 0;
+    let book_results = Mutex::new(Vec::new());
+    let score_rows = Mutex::new(Vec::new());
+    let manifest = args
+        .manifest
+        .as_deref()
+        .map(|path| load_manifest(Path::new(path)))
+        .transpose()?
+        .map(Mutex::new);
 
-    let workdir = tempfile::TempDir::new().context("failed to create temp dir")?;
-    for b in books {
-        let book_id = b.get("id").and_then(|v| v.as_i64()).unwrap_or(-1);
+    let not_started = AtomicUsize::new(0);
+    install_interrupt_handler();
+    let handle = |b: &serde_json::Value| -> Result<()> {
+        if STOP_REQUESTED.load(Ordering::SeqCst) {
+            not_started.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        let book_started = Instant::now();
+        let book_id = book_id_of(b);
         let title = b
             .get("title")
             .and_then(|v| v.as_str())
@@ -407,91 +2062,558 @@ pub fn run() -> Result<()> {
             .to_string();
         let result = (|| -> Result<String> {
             debug!(id = book_id, title = %title, "[book] start");
-            let prev = get_book_state(&state, book_id);
-            let before_hash = snapshot_hash(&metadata_snapshot(&b))?;
-            if let Some(prev_state) = prev {
-                if ["done", "skipped_good_enough", "embedded_only", "failed_permanent"]
-                    .contains(&prev_state.status.as_str())
-                    && (!config.policy.reprocess_on_metadata_change
-                        || prev_state.last_hash == before_hash)
-                {
-                    skipped += 1;
-                    let reason = if !config.policy.reprocess_on_metadata_change {
-                        "already processed"
-                    } else {
-                        "already processed for current metadata hash"
-                    };
-                    info!(id = book_id, title = %title, reason = %reason, "[skip]");
-                    return Ok("skipped".to_string());
+            if args.refetch_covers {
+                let outcome = refetch_cover(&ctx, &store, b)?;
+                let mut t = tally.lock().unwrap_or_else(PoisonError::into_inner);
+                let action = match outcome {
+                    BookOutcome::Action(action) => action,
+                    BookOutcome::Skipped(reason) => {
+                        t.skip(reason, book_id, &title);
+                        return Ok("skipped".to_string());
+                    }
+                };
+                match action.as_str() {
+                    "done" | "updated" => t.ok += 1,
+                    _ => t.fail += 1,
                 }
+                if action == "updated" {
+                    t.would_fetch += 1;
+                }
+                return Ok(action);
+            }
+            if !ctx.force
+                && !ctx.force_reembed
+                && let Some(manifest) = &manifest
+                && let Some(entry) = manifest
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .books
+                    .get(&book_id.to_string())
+                && TERMINAL_STATUSES.contains(&entry.status.as_str())
+                && !entry.last_modified.is_empty()
+                && entry.last_modified == book_last_modified(b)
+            {
+                tally
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .skip(SkipReason::AlreadyDone, book_id, &title);
+                debug!(id = book_id, title = %title, "[skip] unchanged since manifest");
+                return Ok("skipped".to_string());
+            }
+            let prev = store.get(book_id);
+            let before_hash = snapshot_hash(&metadata_snapshot(b))?;
+            let force = ctx.force || ctx.force_reembed;
+            if let Some(reason) =
+                skip_reason(&config, args.only_unprocessed, force, prev.as_ref(), &before_hash)
+            {
+                tally
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .skip(reason, book_id, &title);
+                info!(id = book_id, title = %title, reason = reason.as_str(), "[skip]");
+                return Ok("skipped".to_string());
             }
 
-            let action = process_one_book(
-                &runner,
-                &mut state,
-                &b,
-                workdir.path(),
-                &lib,
-                &target_formats,
-                config.policy.reprocess_on_metadata_change,
-                &config.scoring,
-                config.policy.delay_between_fetches_seconds,
-                config.fetch.timeout_seconds,
-                config.fetch.heartbeat_seconds,
-                &state_path,
-                config.policy.dry_run,
-            )?;
+            let outcome = process_one_book(&ctx, &store, b, &verifier)?;
 
+            let mut t = tally.lock().unwrap_or_else(PoisonError::into_inner);
+            let action = match outcome {
+                BookOutcome::Action(action) => action,
+                // Leaves no state behind, so the book is up for a later title/author pass
+                BookOutcome::Skipped(reason) => {
+                    t.skip(reason, book_id, &title);
+                    return Ok("skipped".to_string());
+                }
+            };
             if config.policy.dry_run {
+                if action == "updated" || action == "fetch_failed" {
+                    t.would_fetch += 1;
+                }
                 if ["done", "updated", "embedded_only"].contains(&action.as_str()) {
-                    ok += 1;
+                    t.ok += 1;
+                } else if action == "fetch_failed" {
+                    t.dry_run_fetch_failed += 1;
                 } else if action == "failed" {
-                    fail += 1;
+                    t.fail += 1;
                 } else {
-                    skipped += 1;
+                    t.skip(SkipReason::AlreadyDone, book_id, &title);
                 }
             } else {
-                let after = get_book_state(&state, book_id);
-                if matches!(after.as_ref().map(|s| s.status.as_str()), Some("done")) {
-                    ok += 1;
-                } else if matches!(after.as_ref().map(|s| s.status.as_str()), Some("failed")) {
-                    fail += 1;
-                } else {
-                    skipped += 1;
+                let after = store.get(book_id);
+                match after.as_ref().map(|s| s.status.as_str()) {
+                    Some("done" | "db_only_large_file" | "embed_unverified" | "done_no_embed") => {
+                        t.ok += 1
+                    }
+                    Some("failed" | "failed_permanent") => t.fail += 1,
+                    Some("embedded_only") => t.skip(SkipReason::GoodEnough, book_id, &title),
+                    _ => t.skip(SkipReason::AlreadyDone, book_id, &title),
+                }
+                // The recorded status is more precise than the returned action
+                if let Some(after) = after {
+                    return Ok(after.status);
                 }
             }
             Ok(action)
         })();
 
-        if let Err(err) = result {
-            fail += 1;
-            if config.policy.dry_run {
-                error!(id = book_id, title = %title, error = %err, "[fail] exception");
-                continue;
+        let action = match result {
+            Ok(action) => action,
+            Err(err) => {
+                tally.lock().unwrap_or_else(PoisonError::into_inner).fail += 1;
+                if config.policy.dry_run {
+                    error!(id = book_id, title = %title, error = %err, "[fail] exception");
+                } else if args.refetch_covers {
+                    error!(id = book_id, title = %title, error = %err, "[cover] exception");
+                    let prev = store.get_cover(book_id);
+                    let cs = CoverState {
+                        status: "failed".to_string(),
+                        last_attempt_utc: now_iso(),
+                        last_ok_utc: prev.as_ref().and_then(|p| p.last_ok_utc.clone()),
+                        message: Some(format!("exception: {err}")),
+                        width: prev.as_ref().and_then(|p| p.width),
+                        height: prev.as_ref().and_then(|p| p.height),
+                    };
+                    store.put_cover(book_id, cs)?;
+                } else {
+                    let snap = metadata_snapshot(b);
+                    let h = snapshot_hash(&snap)?;
+                    let prev = store.get(book_id);
+                    let bs = failed_state(
+                        &config,
+                        book_id,
+                        "error",
+                        prev.as_ref(),
+                        h,
+                        format!("exception: {err}"),
+                    );
+                    store.put(book_id, bs)?;
+                }
+                "failed".to_string()
             }
-            let snap = metadata_snapshot(&b);
-            let h = snapshot_hash(&snap)?;
-            let prev = get_book_state(&state, book_id);
-            let bs = BookState {
-                status: "failed".to_string(),
-                last_hash: h,
-                last_attempt_utc: now_iso(),
-                last_ok_utc: prev.as_ref().and_then(|p| p.last_ok_utc.clone()),
-                message: Some(format!("exception: {err}")),
-                fail_count: prev.map(|p| p.fail_count + 1).unwrap_or(1),
-            };
-            put_book_state(&mut state, book_id, bs);
+        };
+
+        ctx.progress(book_id, &title, "done", &action);
+        if let Some(dir) = &artifacts_dir
+            && action != "skipped"
+            && let Err(err) = write_book_artifacts(&ctx, &store, dir, b, &action)
+        {
+            warn!(id = book_id, error = %format!("{err:#}"), "[artifacts] not written");
+        }
+        if ctx.discard_artifacts {
+            ctx.remove_artifacts(book_id);
+        }
+        let failed = action.starts_with("failed");
+
+        // After an update last_modified has moved on, so such books only take
+        // the fast path from the run after next
+        if !config.policy.dry_run
+            && let Some(manifest) = &manifest
+            && let Some(state) = store.get(book_id)
+        {
+            manifest
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .books
+                .insert(
+                    book_id.to_string(),
+                    ManifestEntry {
+                        final_hash: state.last_hash,
+                        status: state.status,
+                        last_modified: book_last_modified(b),
+                    },
+                );
         }
 
-        if !config.policy.dry_run {
-            save_state(&state_path, &mut state)?;
+        if args.scores_csv.is_some() {
+            let snap = metadata_snapshot(b);
+            let points: Vec<i32> =
+                score_parts(&snap, &config.scoring).iter().map(|p| p.points).collect();
+            let total = points.iter().sum();
+            let decision = if action == "skipped" {
+                "skip"
+            } else if is_good_enough(total, &snap, &config.scoring) {
+                "embed"
+            } else {
+                "fetch"
+            };
+            score_rows.lock().unwrap_or_else(PoisonError::into_inner).push(ScoreRow {
+                id: book_id,
+                title: title.clone(),
+                points,
+                total,
+                decision,
+            });
+        }
+        if args.report.is_some() {
+            let (score, reasons) = score_good_enough(&metadata_snapshot(b), &config.scoring);
+            book_results
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(BookResult {
+                    id: book_id,
+                    title,
+                    action,
+                    score,
+                    reasons,
+                    elapsed_seconds: book_started.elapsed().as_secs_f64(),
+                });
+        }
+        if failed && args.fail_fast {
+            anyhow::bail!("--fail-fast: book {book_id} failed");
         }
+        Ok(())
+    };
+
+    let jobs = config.policy.max_concurrency.max(1);
+    // Held until the summary has been logged
+    let loop_result = if jobs == 1 {
+        books.iter().try_for_each(handle)
+    } else {
+        info!(jobs, "[info] processing books concurrently");
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build()
+            .context("Failed to build worker thread pool")?;
+        pool.install(|| books.par_iter().try_for_each(handle))
+    };
+
+    if !config.policy.dry_run {
+        store.flush()?;
     }
 
+    let Tally {
+        ok,
+        fail,
+        skipped,
+        mut skips,
+        would_fetch,
+        dry_run_fetch_failed,
+        mut skipped_books,
+    } = tally.into_inner().unwrap_or_else(PoisonError::into_inner);
+    skips.filtered_language = filtered.language;
+    skips.filtered_format = filtered.format;
+    let already_known = skips.already_known;
+    let verify_checked = verifier.checked.load(Ordering::Relaxed);
+    let verify_mismatched = verifier.mismatched.load(Ordering::Relaxed);
+
     info!(done_ok = ok, done_failed = fail, skipped, "[summary]");
+    if dry_run_fetch_failed > 0 {
+        warn!(
+            failed = dry_run_fetch_failed,
+            "[summary] --dry-run-network fetches failed (not counted as run failures)"
+        );
+    }
+    info!(
+        already_done = skips.already_done,
+        already_known = skips.already_known,
+        good_enough = skips.good_enough,
+        backoff = skips.backoff,
+        max_fail_count = skips.max_fail_count,
+        no_strong_identifier = skips.no_strong_identifier,
+        cover_kept = skips.cover_kept,
+        filtered_language = skips.filtered_language,
+        filtered_format = skips.filtered_format,
+        "[summary] skip reasons"
+    );
+    if args.report_skipped {
+        skipped_books.extend(filtered.dropped.iter().cloned());
+        write_output(&skipped_books_report(&skipped_books), None)?;
+    }
+    if args.only_unprocessed {
+        info!(already_known, "[summary] skipped as already known (--only-unprocessed)");
+    }
+    if !missing_files.is_empty() {
+        warn!(
+            count = missing_files.len(),
+            ids = %missing_files.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(","),
+            "[summary] books with missing format files (not processed)"
+        );
+    }
+    if !interrupted_last_run.is_empty() {
+        info!(
+            count = interrupted_last_run.len(),
+            ids = %interrupted_last_run.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(","),
+            "[summary] books interrupted last run (retried)"
+        );
+    }
+    let fetch_estimate = config.policy.dry_run.then(|| FetchEstimate {
+        fetches: would_fetch,
+        requests: (would_fetch as f64 * config.fetch.estimate_requests_per_fetch.max(0.0)).ceil()
+            as u64,
+        cover_bytes: would_fetch as u64 * config.fetch.estimate_cover_bytes,
+    });
+    if let Some(est) = &fetch_estimate {
+        info!(
+            fetches = est.fetches,
+            requests = est.requests,
+            cover_bytes = est.cover_bytes,
+            "[dry-run] estimated fetch load"
+        );
+    }
+    if !books_with_non_targeted_formats.is_empty() {
+        info!(
+            count = books_with_non_targeted_formats.len(),
+            formats = %non_targeted_formats
+                .iter()
+                .map(|(fmt, n)| format!("{fmt}:{n}"))
+                .collect::<Vec<_>>()
+                .join(","),
+            "[summary] books with additional non-targeted formats"
+        );
+    }
+    if verify_checked > 0 {
+        info!(
+            checked = verify_checked,
+            mismatched = verify_mismatched,
+            "[summary] embed verification sample"
+        );
+    }
+
+    let summary = RunSummary {
+        library: lib.clone(),
+        dry_run: config.policy.dry_run,
+        candidates,
+        done_ok: ok,
+        done_failed: fail,
+        skipped,
+        skip_reasons: skips,
+        already_known,
+        missing_files,
+        interrupted_last_run,
+        books_with_non_targeted_formats,
+        non_targeted_formats,
+        verify_checked,
+        verify_mismatched,
+        fetch_estimate,
+        dry_run_fetch_failed,
+    };
+    if !config.policy.dry_run
+        && let (Some(path), Some(manifest)) = (&args.manifest, manifest)
+    {
+        let mut manifest = manifest.into_inner().unwrap_or_else(PoisonError::into_inner);
+        save_manifest(Path::new(path), &mut manifest)?;
+        info!(path = %path, books = manifest.books.len(), "[info] wrote manifest");
+    }
+    if let Some(path) = &args.report {
+        let mut books = book_results.into_inner().unwrap_or_else(PoisonError::into_inner);
+        books.sort_by_key(|r| r.id);
+        let report = RunReport {
+            generated_utc: now_iso(),
+            duration_seconds: run_started.elapsed().as_secs_f64(),
+            summary: &summary,
+            books,
+        };
+        write_report(Path::new(path), &report)?;
+        info!(path = %path, "[info] wrote run report");
+    }
+    if let Some(path) = &args.scores_csv {
+        let mut rows = score_rows.into_inner().unwrap_or_else(PoisonError::into_inner);
+        rows.sort_by_key(|r| r.id);
+        // Field names don't depend on the book
+        let fields: Vec<String> = books
+            .first()
+            .map(|b| score_parts(&metadata_snapshot(b), &config.scoring))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|p| p.field)
+            .collect();
+        write_output(&scores_csv(&fields, &rows), Some(Path::new(path)))?;
+        info!(path = %path, rows = rows.len(), "[info] wrote scores CSV");
+    }
+    if let Some(format) = args.summary_format {
+        print_summary(&summary, format)?;
+    }
+    loop_result?;
+    if STOP_REQUESTED.load(Ordering::SeqCst) {
+        warn!(
+            not_started = not_started.load(Ordering::Relaxed),
+            "[interrupt] run stopped early; state saved"
+        );
+        return Ok(RunOutcome::Interrupted);
+    }
+    Ok(if fail > 0 {
+        RunOutcome::BooksFailed
+    } else if listed == 0 {
+        RunOutcome::NoCandidates
+    } else {
+        RunOutcome::Clean
+    })
+}
+
+/// Removes files under `dir` whose mtime is older than `days`, then any
+/// directories left empty. Returns the number of files removed.
+fn cleanup_stale_artifacts(dir: &Path, days: u64) -> Result<usize> {
+    let cutoff = std::time::SystemTime::now()
+        .checked_sub(Duration::from_secs(days * 24 * 60 * 60))
+        .unwrap_or(std::time::UNIX_EPOCH);
+    let mut removed = 0;
+    for entry in walkdir::WalkDir::new(dir).min_depth(1).contents_first(true) {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                warn!(error = %e, "[cleanup] walk error");
+                continue;
+            }
+        };
+        let path = entry.path();
+        if entry.file_type().is_dir() {
+            // Only succeeds when empty; non-empty dirs are left alone.
+            let _ = std::fs::remove_dir(path);
+            continue;
+        }
+        let modified = entry.metadata().ok().and_then(|m| m.modified().ok());
+        if modified.is_some_and(|m| m < cutoff) {
+            match std::fs::remove_file(path) {
+                Ok(()) => removed += 1,
+                Err(e) => warn!(path = %path.display(), error = %e, "[cleanup] failed to remove"),
+            }
+        }
+    }
+    Ok(removed)
+}
+
+fn book_id_of(book: &serde_json::Value) -> i64 {
+    book.get("id").and_then(|v| v.as_i64()).unwrap_or(-1)
+}
+
+/// Targeted format files listed in the db that are absent on disk.
+fn missing_format_files(
+    book: &serde_json::Value,
+    target_formats: &BTreeMap<String, ()>,
+) -> Vec<PathBuf> {
+    format_files(book.get("formats").unwrap_or(&serde_json::Value::Null))
+        .into_iter()
+        .filter(|(fmt, path)| target_formats.contains_key(fmt) && !path.is_file())
+        .map(|(_, path)| path)
+        .collect()
+}
+
+/// Guardrail against a misconfigured search selecting most of the library:
+/// asks on a terminal, refuses otherwise.
+fn confirm_large_run(candidates: usize, warn_above: usize) -> Result<()> {
+    use std::io::{BufRead, IsTerminal, Write};
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!(
+            "{candidates} candidates exceed policy.max_candidates_warn ({warn_above}); \
+             pass --assume-yes to proceed, or narrow the search (try --dry-run first)"
+        );
+    }
+    eprint!(
+        "About to process {candidates} books (policy.max_candidates_warn = {warn_above}). \
+         Continue? [y/N] "
+    );
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        anyhow::bail!("aborted: {candidates} candidates not confirmed");
+    }
     Ok(())
 }
 
+/// The book's folder relative to the library root, from its format paths.
+fn book_dir_in_library(book: &serde_json::Value, root: &Path) -> Option<PathBuf> {
+    let files = format_files(book.get("formats").unwrap_or(&serde_json::Value::Null));
+    let (_, path) = files.first()?;
+    let dir = path.parent()?;
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    dir.strip_prefix(root).ok().map(Path::to_path_buf)
+}
+
+/// Accepts `YYYY-MM-DD` or an RFC3339 timestamp and returns the date part.
+fn parse_date_arg(raw: &str) -> Result<chrono::NaiveDate> {
+    let raw = raw.trim();
+    if let Ok(d) = chrono::NaiveDate::parse_from_str(raw, "%Y-%m-%d") {
+        return Ok(d);
+    }
+    let dt = chrono::DateTime::parse_from_rfc3339(raw)?;
+    Ok(dt.date_naive())
+}
+
+/// `--since`: an absolute RFC3339 timestamp or date, or a duration back from now
+/// (`90m`, `24h`, `7d`, `2w`).
+fn parse_since_arg(raw: &str) -> Result<chrono::DateTime<chrono::Utc>> {
+    let raw = raw.trim();
+    if let Some(dt) = parse_calibre_datetime(raw) {
+        return Ok(dt);
+    }
+    if let Ok(d) = chrono::NaiveDate::parse_from_str(raw, "%Y-%m-%d") {
+        return Ok(d.and_time(chrono::NaiveTime::MIN).and_utc());
+    }
+    let span = parse_duration(raw)
+        .map_err(|e| anyhow::anyhow!("expected a timestamp, a date, or a duration like 24h ({e})"))?;
+    Ok(chrono::Utc::now() - span)
+}
+
+/// Calibre usually emits RFC3339 with an offset; older versions and some
+/// servers omit the `T` or the offset (taken as UTC).
+fn parse_calibre_datetime(raw: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    let raw = raw.trim();
+    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(raw) {
+        return Some(dt.with_timezone(&chrono::Utc));
+    }
+    if let Ok(dt) = chrono::DateTime::parse_from_str(raw, "%Y-%m-%d %H:%M:%S%.f%:z") {
+        return Some(dt.with_timezone(&chrono::Utc));
+    }
+    ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"]
+        .iter()
+        .find_map(|fmt| chrono::NaiveDateTime::parse_from_str(raw, fmt).ok())
+        .map(|dt| dt.and_utc())
+}
+
+fn book_last_modified(book: &serde_json::Value) -> String {
+    book.get("last_modified")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .trim()
+        .to_string()
+}
+
+/// The library a run uses: `--library-url`, then `--library`, then
+/// library.url over library.path. When the config sets both and the command
+/// line did not settle it, the ignored path comes back too (for a warning),
+/// or it is an error under `--strict-config`.
+fn resolve_library(
+    config: &Config,
+    args: &Args,
+    strict: bool,
+) -> Result<(Option<String>, Option<String>)> {
+    if let Some(chosen) = args.library_url.as_ref().or(args.library.as_ref()) {
+        return Ok((Some(chosen.clone()), None));
+    }
+    match (&config.library.path, &config.library.url) {
+        (Some(path), Some(url)) => {
+            if strict {
+                anyhow::bail!(
+                    "both library.path ({path}) and library.url ({url}) are set; \
+                     remove one or pass --library/--library-url"
+                );
+            }
+            Ok((Some(url.clone()), Some(path.clone())))
+        }
+        (path, url) => Ok((url.clone().or(path.clone()), None)),
+    }
+}
+
+fn is_remote_library(lib: &str) -> bool {
+    lib.starts_with("http://") || lib.starts_with("https://")
+}
+
+/// The state file and the rule that chose it (flag, config or default).
+fn resolve_state_path(args: &Args, config: &Config) -> Result<(PathBuf, &'static str)> {
+    let state_override = normalize_optional_string(args.state.clone());
+    Ok(match (state_override, &config.state.path) {
+        (Some(p), _) => (PathBuf::from(p), "--state flag"),
+        (None, Some(p)) => (PathBuf::from(p), "config state.path"),
+        (None, None) => (default_state_path()?, "default ./.cache/state.json"),
+    })
+}
+
+/// `backups/` next to the state file; one `<id>.opf` per backed-up book.
+fn backup_dir(state_path: &Path) -> PathBuf {
+    state_path.parent().unwrap_or_else(|| Path::new(".")).join("backups")
+}
+
 fn default_state_path() -> Result<PathBuf> {
     let dir = std::env::current_dir()?.join(".cache");
     std::fs::create_dir_all(&dir)?;
@@ -504,3 +2626,197 @@ fn parse_dups_output(value: &str) -> OutputFormat {
         _ => OutputFormat::Text,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibre::EmbedResult;
+
+    fn args(cli: &[&str]) -> Args {
+        Args::try_parse_from(std::iter::once("calibre-updatr").chain(cli.iter().copied())).unwrap()
+    }
+
+    fn config(path: Option<&str>, url: Option<&str>) -> Config {
+        let mut config = Config::default();
+        config.library.path = path.map(str::to_string);
+        config.library.url = url.map(str::to_string);
+        config
+    }
+
+    #[test]
+    fn seeded_verifiers_pick_the_same_sample() {
+        let picks = |verifier: EmbedVerifier| (0..200).map(|_| verifier.should_sample()).collect();
+        let first: Vec<bool> = picks(EmbedVerifier::new(0.3, Some(42)));
+        let second: Vec<bool> = picks(EmbedVerifier::new(0.3, Some(42)));
+        assert_eq!(first, second);
+        // Neither all nor nothing, so the rate is actually applied
+        assert!(first.contains(&true) && first.contains(&false));
+    }
+
+    #[test]
+    fn verifier_rate_bounds_sample_all_or_nothing() {
+        assert!((0..50).all(|_| EmbedVerifier::new(1.0, Some(7)).should_sample()));
+        assert!((0..50).all(|_| !EmbedVerifier::new(0.0, Some(7)).should_sample()));
+    }
+
+    #[test]
+    fn library_url_wins_over_path_and_reports_the_ignored_path() {
+        let config = config(Some("/books"), Some("http://host:8080/#lib"));
+        let (using, ignored) = resolve_library(&config, &args(&[]), false).unwrap();
+        assert_eq!(using.as_deref(), Some("http://host:8080/#lib"));
+        assert_eq!(ignored.as_deref(), Some("/books"));
+    }
+
+    #[test]
+    fn a_single_library_setting_is_used_without_a_warning() {
+        let config = config(Some("/books"), None);
+        let (using, ignored) = resolve_library(&config, &args(&[]), true).unwrap();
+        assert_eq!(using.as_deref(), Some("/books"));
+        assert_eq!(ignored, None);
+    }
+
+    #[test]
+    fn command_line_library_settles_the_conflict() {
+        let config = config(Some("/books"), Some("http://host:8080/#lib"));
+        let (using, ignored) =
+            resolve_library(&config, &args(&["--library", "/other"]), true).unwrap();
+        assert_eq!(using.as_deref(), Some("/other"));
+        assert_eq!(ignored, None);
+
+        let cli = args(&["--library-url", "http://other:8080/"]);
+        let (using, ignored) = resolve_library(&config, &cli, true).unwrap();
+        assert_eq!(using.as_deref(), Some("http://other:8080/"));
+        assert_eq!(ignored, None);
+    }
+
+    #[test]
+    fn strict_config_rejects_both_library_settings() {
+        let config = config(Some("/books"), Some("http://host:8080/#lib"));
+        let err = resolve_library(&config, &args(&["--strict-config"]), true).unwrap_err();
+        assert!(err.to_string().contains("both library.path"), "{err}");
+    }
+
+    #[test]
+    fn only_embedded_formats_are_verified() {
+        let book = serde_json::json!({
+            "id": 7,
+            "formats": ["/lib/A/B (7)/B.epub", "/lib/A/B (7)/B.cbz"],
+        });
+        let embed = EmbedResult { embedded: vec!["EPUB".to_string()], failed: vec![] };
+        let files = embedded_format_files(&book, &embed.embedded_formats());
+        assert_eq!(files, vec![("epub".to_string(), PathBuf::from("/lib/A/B (7)/B.epub"))]);
+
+        // One combined call covers both formats
+        let embed = EmbedResult { embedded: vec!["CBZ,EPUB".to_string()], failed: vec![] };
+        let files = embedded_format_files(&book, &embed.embedded_formats());
+        let fmts: Vec<&str> = files.iter().map(|(f, _)| f.as_str()).collect();
+        assert_eq!(fmts, ["epub", "cbz"]);
+
+        let embed = EmbedResult::default();
+        assert!(embedded_format_files(&book, &embed.embedded_formats()).is_empty());
+    }
+
+    #[test]
+    fn since_accepts_durations_back_from_now() {
+        let before = chrono::Utc::now();
+        let since = parse_since_arg("24h").unwrap();
+        let after = chrono::Utc::now();
+        assert!(since >= before - chrono::Duration::hours(24));
+        assert!(since <= after - chrono::Duration::hours(24));
+
+        let since = parse_since_arg(" 7d ").unwrap();
+        let span = chrono::Utc::now() - since;
+        assert!(span >= chrono::Duration::days(7));
+        assert!(span < chrono::Duration::days(7) + chrono::Duration::minutes(1));
+    }
+
+    #[test]
+    fn since_accepts_timestamps_and_dates() {
+        let since = parse_since_arg("2024-03-01T12:00:00+02:00").unwrap();
+        assert_eq!(since.to_rfc3339(), "2024-03-01T10:00:00+00:00");
+        let since = parse_since_arg("2024-03-01").unwrap();
+        assert_eq!(since.to_rfc3339(), "2024-03-01T00:00:00+00:00");
+    }
+
+    #[test]
+    fn since_rejects_malformed_input() {
+        for raw in ["", "   ", "-3d", "h", "24", "24x", "yesterday"] {
+            let err = parse_since_arg(raw).unwrap_err();
+            assert!(err.to_string().contains("expected a timestamp"), "{raw:?}: {err}");
+        }
+    }
+
+    #[test]
+    fn calibre_datetimes_with_and_without_offset() {
+        let utc = |raw| parse_calibre_datetime(raw).map(|dt| dt.to_rfc3339());
+        let want = Some("2024-03-01T10:00:00+00:00".to_string());
+        assert_eq!(utc("2024-03-01T10:00:00+00:00"), want);
+        assert_eq!(utc("2024-03-01 12:00:00+02:00"), want);
+        assert_eq!(utc("2024-03-01T10:00:00"), want);
+        assert_eq!(utc("2024-03-01 10:00:00.000"), want);
+        assert_eq!(utc(""), None);
+        assert_eq!(utc("2024-03-01"), None);
+        assert_eq!(utc("not a date"), None);
+    }
+
+    #[test]
+    fn skip_reason_table() {
+        let ago = |secs| (chrono::Utc::now() - chrono::Duration::seconds(secs)).to_rfc3339();
+        let in_an_hour = (chrono::Utc::now() + chrono::Duration::hours(1)).to_rfc3339();
+        let state = |status: &str, fail_count, retry_after: Option<&str>| BookState {
+            status: status.to_string(),
+            last_hash: "h1".to_string(),
+            last_attempt_utc: ago(60),
+            fail_count,
+            retry_after_utc: retry_after.map(str::to_string),
+            ..BookState::default()
+        };
+        let mut strict = Config::default();
+        strict.policy.reprocess_on_metadata_change = true;
+        strict.policy.max_fail_count = Some(3);
+        strict.policy.retry_cooldown_seconds = 3600;
+        let lenient = Config::default();
+        let past = ago(10);
+
+        // (case, config, only_unprocessed, force, prev, current hash, expected)
+        type Case<'a> =
+            (&'a str, &'a Config, bool, bool, Option<BookState>, &'a str, Option<&'a str>);
+        let cases: Vec<Case> = vec![
+            ("new book", &strict, true, false, None, "h1", None),
+            ("only unprocessed", &lenient, true, false, Some(state("done", 0, None)), "h1",
+                Some("already_known")),
+            ("only unprocessed beats force", &lenient, true, true,
+                Some(state("started", 0, None)), "h2", Some("already_known")),
+            ("interrupted", &strict, false, false, Some(state("started", 5, None)), "h1", None),
+            ("force", &lenient, false, true, Some(state("done", 0, None)), "h1", None),
+            ("force past backoff", &strict, false, true,
+                Some(state("failed", 5, Some(&in_an_hour))), "h1", None),
+            ("done", &strict, false, false, Some(state("done", 0, None)), "h1",
+                Some("already_done")),
+            ("embedded only", &lenient, false, false, Some(state("embedded_only", 0, None)),
+                "h1", Some("already_done")),
+            ("failed permanently", &lenient, false, false,
+                Some(state("failed_permanent", 2, None)), "h1", Some("backoff")),
+            ("hash changed", &strict, false, false, Some(state("done", 0, None)), "h2", None),
+            ("hash changed, not watched", &lenient, false, false, Some(state("done", 0, None)),
+                "h2", Some("already_done")),
+            ("max fail count", &strict, false, false, Some(state("failed", 3, None)), "h1",
+                Some("max_fail_count")),
+            ("retry after", &lenient, false, false,
+                Some(state("failed", 1, Some(&in_an_hour))), "h1", Some("backoff")),
+            ("retry after passed", &lenient, false, false,
+                Some(state("failed", 1, Some(&past))), "h1", None),
+            ("cooldown", &strict, false, false, Some(state("failed", 1, None)), "h1",
+                Some("in_cooldown")),
+            ("no cooldown", &lenient, false, false, Some(state("failed", 1, None)), "h1", None),
+        ];
+        for (case, config, only_unprocessed, force, prev, hash, expected) in cases {
+            let got = skip_reason(config, only_unprocessed, force, prev.as_ref(), hash);
+            assert_eq!(got.map(SkipReason::as_str), expected, "{case}");
+        }
+
+        let mut cooled = state("failed", 1, None);
+        cooled.last_attempt_utc = ago(7200);
+        assert!(skip_reason(&strict, false, false, Some(&cooled), "h1").is_none());
+    }
+}
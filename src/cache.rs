@@ -0,0 +1,64 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, warn};
+
+/// On-disk cache of fetched OPFs (and covers) keyed by a hash of the fetch
+/// query, so reruns don't repeat identical network lookups.
+pub struct FetchCache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+impl FetchCache {
+    pub fn new(dir: PathBuf, ttl: Duration) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create fetch cache dir {}", dir.display()))?;
+        Ok(Self { dir, ttl })
+    }
+
+    /// Key for a fetch query: the query arguments, independent of output paths.
+    pub fn key(query: &[String]) -> String {
+        blake3::hash(query.join("\0").as_bytes()).to_hex().to_string()
+    }
+
+    fn paths(&self, key: &str) -> (PathBuf, PathBuf) {
+        (self.dir.join(format!("{key}.opf")), self.dir.join(format!("{key}.cover.jpg")))
+    }
+
+    /// Copies a fresh cached entry to `opf_path`/`cover_path`; false on a miss.
+    pub fn restore(&self, key: &str, opf_path: &Path, cover_path: &Path) -> bool {
+        let (opf, cover) = self.paths(key);
+        let fresh = std::fs::metadata(&opf)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| SystemTime::now().duration_since(t).ok())
+            .is_some_and(|age| age <= self.ttl);
+        if !fresh {
+            return false;
+        }
+        if let Err(e) = std::fs::copy(&opf, opf_path) {
+            warn!(error = %e, "[cache] failed to restore cached OPF");
+            return false;
+        }
+        if cover.exists() {
+            let _ = std::fs::copy(&cover, cover_path);
+        }
+        debug!(key, "[cache] hit");
+        true
+    }
+
+    /// Stores a successful fetch; failures to cache are only logged.
+    pub fn store(&self, key: &str, opf_path: &Path, cover_path: &Path) {
+        let (opf, cover) = self.paths(key);
+        if let Err(e) = std::fs::copy(opf_path, &opf) {
+            warn!(error = %e, "[cache] failed to store OPF");
+            return;
+        }
+        if cover_path.exists() {
+            let _ = std::fs::copy(cover_path, &cover);
+        } else {
+            let _ = std::fs::remove_file(&cover);
+        }
+    }
+}
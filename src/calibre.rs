@@ -1,13 +1,50 @@
 use crate::metadata::{
-    has_any_format, is_english_or_missing, normalize_identifiers_for_fetch,
+    has_any_format, metadata_snapshot, is_english_or_missing, normalize_identifiers_for_fetch,
     normalize_languages_for_filter,
 };
+use crate::cache::FetchCache;
+use crate::config::{source_key, FetchConfig};
+use crate::opf::{read_opf, OpfMetadata};
 use crate::runner::Runner;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde_json::Value;
-use std::collections::BTreeMap;
-use std::path::Path;
-use tracing::{error, info};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tracing::{debug, error, info, warn};
+
+/// Fields requested from `calibredb list`. `timestamp` is the date the book
+/// was added, `last_modified` the last metadata edit, `pubdate` the
+/// publication date.
+const BOOK_FIELDS: &[&str] = &[
+    "id",
+    "title",
+    "authors",
+    "publisher",
+    "pubdate",
+    "languages",
+    "formats",
+    "isbn",
+    "identifiers",
+    "tags",
+    "comments",
+    "cover",
+    "last_modified",
+    "timestamp",
+    "series",
+    "series_index",
+];
+
+/// `--fields` for a book listing; custom columns (`#name`) are requested
+/// as `*name`.
+fn book_fields(skip_comments: bool, custom_columns: &[String]) -> String {
+    BOOK_FIELDS
+        .iter()
+        .filter(|f| !(skip_comments && **f == "comments"))
+        .map(|f| f.to_string())
+        .chain(custom_columns.iter().map(|c| format!("*{}", c.trim_start_matches('#'))))
+        .collect::<Vec<_>>()
+        .join(",")
+}
 
 pub fn append_calibre_auth(
     cmd: &mut Vec<String>,
@@ -28,39 +65,93 @@ pub fn append_calibre_auth(
     }
 }
 
-pub fn list_candidate_books(
-    runner: &Runner,
-    lib: &str,
-    include_missing_language: bool,
-    english_codes: &[String],
+/// Builds the `calibredb list --search` expression for the target formats.
+///
+/// Each format becomes its own group, ANDed with the format's rule when one
+/// is configured, and the groups are ORed together. With `epub` unruled and
+/// `pdf = "cover:false"` the result is:
+///
+/// `(formats:epub) or (formats:pdf and (cover:false))`
+///
+/// Any extra clauses are ANDed onto the whole format expression:
+///
+/// `((formats:epub) or (formats:pdf)) and (timestamp:>=2024-01-01)`
+pub fn build_search_expr(
     target_formats: &BTreeMap<String, ()>,
-) -> Result<Vec<Value>> {
-    let fields = [
-        "id",
-        "title",
-        "authors",
-        "publisher",
-        "pubdate",
-        "languages",
-        "formats",
-        "isbn",
-        "identifiers",
-        "tags",
-        "comments",
-        "cover",
-        "last_modified",
-    ]
-    .join(",");
-
-    if target_formats.is_empty() {
-        anyhow::bail!("No target formats provided.");
-    }
-    let search_expr = target_formats
+    rules: &BTreeMap<String, String>,
+    extra_clauses: &[String],
+) -> String {
+    let formats_expr = target_formats
         .keys()
-        .map(|f| format!("formats:{f}"))
+        .map(|f| match rules.get(f).map(|r| r.trim()) {
+            Some(rule) if !rule.is_empty() => format!("(formats:{f} and ({rule}))"),
+            _ => format!("(formats:{f})"),
+        })
         .collect::<Vec<_>>()
         .join(" or ");
+    let extra: Vec<&String> = extra_clauses.iter().filter(|c| !c.trim().is_empty()).collect();
+    if extra.is_empty() {
+        return formats_expr;
+    }
+    let mut expr = format!("({formats_expr})");
+    for clause in extra {
+        expr.push_str(&format!(" and ({})", clause.trim()));
+    }
+    expr
+}
+
+/// `field:"=a" or field:"=b"`; `=` makes Calibre match whole values only
+/// (one tag, one author), case-insensitively.
+pub fn exact_match_clause(field: &str, values: &[String]) -> Option<String> {
+    let terms: Vec<String> = values
+        .iter()
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| format!("{field}:\"={}\"", v.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" or "))
+}
+
+/// The tag filter again, on the listed tags: Calibre's search syntax has
+/// enough quirks (hierarchical tags, escaping) that it is not trusted alone.
+fn passes_tag_filter(book: &Value, include: &[String], exclude: &[String]) -> bool {
+    let tags: HashSet<String> =
+        metadata_snapshot(book).tags.iter().map(|t| t.to_lowercase()).collect();
+    let has = |list: &[String]| {
+        list.iter()
+            .map(|t| t.trim().to_lowercase())
+            .any(|t| !t.is_empty() && tags.contains(&t))
+    };
+    let include_any = include.iter().any(|t| !t.trim().is_empty());
+    (!include_any || has(include)) && !has(exclude)
+}
+
+fn saved_search_clause(name: &str) -> String {
+    format!("search:\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
 
+/// Candidate selection inputs for [`list_candidate_books`].
+pub struct CandidateQuery<'a> {
+    pub include_missing_language: bool,
+    pub english_codes: &'a [String],
+    pub target_formats: &'a BTreeMap<String, ()>,
+    pub format_rules: &'a BTreeMap<String, String>,
+    pub extra_clauses: &'a [String],
+    /// Calibre saved search name, ANDed in as `search:"name"`
+    pub saved_search: Option<&'a str>,
+    /// Books must carry at least one of these tags (empty = any)
+    pub include_tags: &'a [String],
+    /// Books carrying any of these tags are never candidates
+    pub exclude_tags: &'a [String],
+    /// Custom columns (`#name`) to include in each book
+    pub custom_columns: &'a [String],
+    /// Skip the (potentially large) `comments` field and only record whether
+    /// each book has one, via a second id-only listing.
+    pub comments_presence_only: bool,
+}
+
+/// Runs `calibredb list --for-machine` and returns the parsed rows.
+fn run_list(runner: &Runner, lib: &str, fields: &str, search_expr: &str) -> Result<Vec<Value>> {
     let mut cmd = vec![
         "calibredb".to_string(),
         "--with-library".to_string(),
@@ -76,12 +167,12 @@ pub fn list_candidate_books(
         "list".to_string(),
         "--for-machine".to_string(),
         "--fields".to_string(),
-        fields,
+        fields.to_string(),
         "--search".to_string(),
-        search_expr,
+        search_expr.to_string(),
     ]);
 
-    let cp = runner.run(&cmd, true, None)?;
+    let cp = runner.run_listing(&cmd)?;
     if cp.status_code != 0 {
         let stderr = cp.stderr.to_lowercase();
         if stderr.contains("another calibre program such as calibre-server")
@@ -102,6 +193,13 @@ Example: --library-url \"http://localhost:8081/#en_nonfiction\""
         if stderr.contains("no books matching the search expression") {
             return Ok(vec![]);
         }
+        if stderr.contains("unknown search") {
+            anyhow::bail!(
+                "calibredb does not know a saved search used in the search expression.\n\
+Saved search names are case-sensitive; check policy.saved_search against\n\
+Calibre's Preferences > Searching > Saved searches for this library."
+            );
+        }
         error!(rc = cp.status_code, "[fatal] calibredb list failed");
         if !cp.stderr.trim().is_empty() {
             error!(stderr = %cp.stderr.chars().take(500).collect::<String>(), "[fatal] calibredb list stderr");
@@ -113,23 +211,191 @@ Example: --library-url \"http://localhost:8081/#en_nonfiction\""
     let arr = data
         .as_array()
         .ok_or_else(|| anyhow::anyhow!("Unexpected JSON shape from calibredb list"))?;
+    Ok(arr.iter().filter(|b| b.is_object()).cloned().collect())
+}
+
+/// Lists only the ids of books matching `search_expr` (empty = all books).
+pub fn list_book_ids(runner: &Runner, lib: &str, search_expr: &str) -> Result<HashSet<i64>> {
+    let rows = run_list(runner, lib, "id", search_expr)?;
+    Ok(rows
+        .iter()
+        .filter_map(|b| b.get("id").and_then(|v| v.as_i64()))
+        .collect())
+}
+
+/// Every book in the library with the standard listing fields, unfiltered.
+pub fn list_all_books(runner: &Runner, lib: &str) -> Result<Vec<Value>> {
+    run_list(runner, lib, &book_fields(false, &[]), "")
+}
+
+/// Books `calibredb list` returned that [`list_candidate_books`] dropped.
+#[derive(Debug, Default, Clone)]
+pub struct CandidateFilterCounts {
+    pub language: usize,
+    pub format: usize,
+    /// Listed despite the tag clauses, then dropped by [`passes_tag_filter`]
+    pub tags: usize,
+    /// (reason, id, title) of each dropped book
+    pub dropped: Vec<(&'static str, i64, String)>,
+}
+
+impl CandidateFilterCounts {
+    fn record(&mut self, book: &Value, reason: &'static str) {
+        let id = book.get("id").and_then(|v| v.as_i64()).unwrap_or_default();
+        let title = book.get("title").and_then(|v| v.as_str()).unwrap_or("").trim();
+        self.dropped.push((reason, id, title.to_string()));
+    }
+}
+
+pub fn list_candidate_books(
+    runner: &Runner,
+    lib: &str,
+    query: &CandidateQuery,
+) -> Result<(Vec<Value>, CandidateFilterCounts)> {
+    let fields = book_fields(query.comments_presence_only, query.custom_columns);
+
+    if query.target_formats.is_empty() {
+        anyhow::bail!("No target formats provided.");
+    }
+    let mut clauses = query.extra_clauses.to_vec();
+    let saved_clause = query.saved_search.map(saved_search_clause);
+    clauses.extend(saved_clause.clone());
+    clauses.extend(exact_match_clause("tags", query.include_tags));
+    clauses.extend(exact_match_clause("tags", query.exclude_tags).map(|c| format!("not ({c})")));
+    let search_expr = build_search_expr(query.target_formats, query.format_rules, &clauses);
+    debug!(search = %search_expr, "[list] calibredb search expression");
+
+    let rows = run_list(runner, lib, &fields, &search_expr)?;
+    if rows.is_empty()
+        && let (Some(name), Some(clause)) = (query.saved_search, &saved_clause)
+    {
+        // Calibre treats some unknown names as matching nothing rather than failing
+        let matched = list_book_ids(runner, lib, clause)?.len();
+        if matched == 0 {
+            warn!(
+                saved_search = %name,
+                "[list] saved search matched no books; check the name (case-sensitive) \
+under Preferences > Searching > Saved searches"
+            );
+        } else {
+            info!(
+                saved_search = %name,
+                matched,
+                "[list] saved search matched books, but none with a target format"
+            );
+        }
+    }
+
+    let with_comments = if query.comments_presence_only {
+        let expr = format!("({search_expr}) and comments:true");
+        Some(list_book_ids(runner, lib, &expr)?)
+    } else {
+        None
+    };
 
     let mut out = Vec::new();
-    for b in arr {
-        if !b.is_object() {
+    let mut filtered = CandidateFilterCounts::default();
+    for mut b in rows {
+        if !passes_tag_filter(&b, query.include_tags, query.exclude_tags) {
+            filtered.tags += 1;
+            filtered.record(&b, "filtered_tags");
             continue;
         }
         let formats_val = b.get("formats").unwrap_or(&Value::Null);
-        if !has_any_format(formats_val, target_formats) {
+        if !has_any_format(formats_val, query.target_formats) {
+            filtered.format += 1;
+            filtered.record(&b, "filtered_format");
             continue;
         }
         let langs = normalize_languages_for_filter(b.get("languages").unwrap_or(&Value::Null));
-        if !is_english_or_missing(&langs, include_missing_language, english_codes) {
+        if !is_english_or_missing(&langs, query.include_missing_language, query.english_codes) {
+            filtered.language += 1;
+            filtered.record(&b, "filtered_language");
             continue;
         }
-        out.push(b.clone());
+        if let Some(ids) = &with_comments {
+            let id = b.get("id").and_then(|v| v.as_i64()).unwrap_or(-1);
+            if let Some(obj) = b.as_object_mut() {
+                obj.insert("comments".to_string(), Value::Bool(ids.contains(&id)));
+            }
+        }
+        out.push(b);
+    }
+    if filtered.tags > 0 {
+        warn!(
+            dropped = filtered.tags,
+            "[list] calibredb returned books outside policy.include_tags/exclude_tags; dropped"
+        );
+    }
+    Ok((out, filtered))
+}
+
+/// Lower ranks are forwarded first when the identifier count is capped.
+fn identifier_rank(key: &str) -> u8 {
+    match key {
+        "isbn" => 0,
+        "amazon" | "asin" => 1,
+        "goodreads" => 2,
+        _ => 3,
+    }
+}
+
+/// Maps a `fetch.allowed_sources` entry to the metadata source plugin name
+/// `fetch-ebook-metadata --allowed-plugin` expects. Unknown names (e.g.
+/// third-party plugins) are passed through unchanged.
+fn metadata_plugin_name(source: &str) -> String {
+    match source_key(source).as_str() {
+        "google" => "Google",
+        "googleimages" => "Google Images",
+        "amazon" | "amazoncom" => "Amazon.com",
+        "openlibrary" => "Open Library",
+        "overdrive" => "OverDrive",
+        "edelweiss" => "Edelweiss",
+        "douban" | "doubanbooks" => "Douban Books",
+        "bigbooksearch" => "Big Book Search",
+        _ => return source.trim().to_string(),
+    }
+    .to_string()
+}
+
+/// Orders identifiers by trust (isbn, amazon/asin, goodreads, then the rest
+/// alphabetically) and keeps at most `max` of them.
+fn ranked_identifiers(
+    identifiers: HashMap<String, String>,
+    max: Option<usize>,
+) -> Vec<(String, String)> {
+    let mut ranked: Vec<(String, String)> = identifiers.into_iter().collect();
+    ranked.sort_by(|a, b| {
+        identifier_rank(&a.0)
+            .cmp(&identifier_rank(&b.0))
+            .then_with(|| a.0.cmp(&b.0))
+    });
+    if let Some(max) = max {
+        ranked.truncate(max);
+    }
+    ranked
+}
+
+/// Why a book is not fetched under `fetch.require_strong_identifier`.
+pub const NO_STRONG_IDENTIFIER: &str = "no strong identifier for isbn-only mode";
+
+/// What `fetch_metadata_to_opf_and_cover` keys its query on for `book`:
+/// `isbn`, `identifier`, or `title` (title/authors only).
+pub fn fetch_match_key(book: &Value, fetch: &FetchConfig) -> &'static str {
+    let has_isbn = book
+        .get("isbn")
+        .and_then(|v| v.as_str())
+        .is_some_and(|s| !s.trim().is_empty());
+    if has_isbn {
+        return "isbn";
+    }
+    let identifiers =
+        normalize_identifiers_for_fetch(book.get("identifiers").unwrap_or(&Value::Null));
+    if ranked_identifiers(identifiers, fetch.max_identifiers_forwarded).is_empty() {
+        "title"
+    } else {
+        "identifier"
     }
-    Ok(out)
 }
 
 pub fn fetch_metadata_to_opf_and_cover(
@@ -137,9 +403,11 @@ pub fn fetch_metadata_to_opf_and_cover(
     book: &Value,
     opf_path: &Path,
     cover_path: &Path,
-    timeout_seconds: u64,
-    heartbeat_seconds: u64,
+    fetch: &FetchConfig,
+    cache: Option<&FetchCache>,
 ) -> Result<(bool, String)> {
+    let timeout_seconds = fetch.timeout_seconds;
+
     let title = book
         .get("title")
         .and_then(|v| v.as_str())
@@ -147,15 +415,26 @@ pub fn fetch_metadata_to_opf_and_cover(
         .trim()
         .to_string();
     let authors_val = book.get("authors").unwrap_or(&Value::Null);
-    let authors = match authors_val {
+    let author_list: Vec<String> = match authors_val {
         Value::Array(arr) => arr
             .iter()
             .filter_map(|v| v.as_str())
             .map(|s| s.trim().to_string())
             .filter(|s| !s.is_empty())
-            .collect::<Vec<_>>()
-            .join(", "),
-        _ => authors_val.as_str().unwrap_or("").trim().to_string(),
+            .collect(),
+        _ => {
+            let s = authors_val.as_str().unwrap_or("").trim();
+            if s.is_empty() {
+                vec![]
+            } else {
+                vec![s.to_string()]
+            }
+        }
+    };
+    let authors = if fetch.primary_author_only {
+        author_list.into_iter().next().unwrap_or_default()
+    } else {
+        author_list.join(&fetch.authors_delimiter)
     };
 
     let isbn = book
@@ -165,14 +444,34 @@ pub fn fetch_metadata_to_opf_and_cover(
         .trim()
         .to_string();
     let identifiers = normalize_identifiers_for_fetch(book.get("identifiers").unwrap_or(&Value::Null));
+    let identifiers = ranked_identifiers(identifiers, fetch.max_identifiers_forwarded);
+    if fetch.require_strong_identifier && isbn.is_empty() && identifiers.is_empty() {
+        return Ok((false, NO_STRONG_IDENTIFIER.to_string()));
+    }
+
+    // fetch writes to .part files that are renamed only after a clean exit, so
+    // a killed fetch never leaves a truncated OPF or cover for the apply step
+    let opf_part = part_path(opf_path);
+    let cover_part = part_path(cover_path);
+    let discard_parts = || {
+        let _ = std::fs::remove_file(&opf_part);
+        let _ = std::fs::remove_file(&cover_part);
+    };
+    discard_parts();
+    let _ = std::fs::remove_file(opf_path);
+    let _ = std::fs::remove_file(cover_path);
 
     let mut cmd = vec![
         "fetch-ebook-metadata".to_string(),
         "--opf".to_string(),
-        opf_path.display().to_string(),
+        opf_part.display().to_string(),
         "--cover".to_string(),
-        cover_path.display().to_string(),
+        cover_part.display().to_string(),
     ];
+    for source in fetch.allowed_sources.iter().filter(|s| !s.trim().is_empty()) {
+        cmd.push("--allowed-plugin".to_string());
+        cmd.push(metadata_plugin_name(source));
+    }
 
     if !isbn.is_empty() {
         cmd.push("--isbn".to_string());
@@ -187,38 +486,154 @@ pub fn fetch_metadata_to_opf_and_cover(
             cmd.push(title.clone());
         }
         if !authors.is_empty() {
+            info!(title = %title, authors = %authors, "[fetch] author query");
             cmd.push("--authors".to_string());
             cmd.push(authors);
         }
     }
 
-    info!(timeout_seconds, title = %title, "[fetch] starting fetch-ebook-metadata");
-    let cp = runner.run_fetch_streaming(
-        &cmd,
-        std::time::Duration::from_secs(timeout_seconds),
-        std::time::Duration::from_secs(heartbeat_seconds),
-    )?;
-    if cp.timed_out {
-        return Ok((false, format!("fetch-ebook-metadata timed out after {}s", timeout_seconds)));
+    // Everything after the output paths identifies the query
+    let cache_key = cache.map(|_| FetchCache::key(&cmd[5..]));
+    if let (Some(cache), Some(key)) = (cache, &cache_key)
+        && cache.restore(key, opf_path, cover_path)
+    {
+        info!(title = %title, "[fetch] using cached result");
+        return Ok((true, "fetched (cached)".to_string()));
     }
-    if cp.status_code != 0 {
+
+    // Non-zero exits are usually network trouble and get retried with
+    // exponential backoff; timeouts and empty results are final.
+    let mut attempt = 0;
+    loop {
+        info!(timeout_seconds, title = %title, attempt, "[fetch] starting fetch-ebook-metadata");
+        let cp = runner.run_fetch_streaming(
+            &cmd,
+            std::time::Duration::from_secs(timeout_seconds),
+            std::time::Duration::from_secs(fetch.heartbeat_seconds),
+        )?;
+        if cp.timed_out {
+            discard_parts();
+            return Ok((false, format!("fetch-ebook-metadata timed out after {}s", timeout_seconds)));
+        }
+        if cp.status_code == 0 {
+            break;
+        }
+        discard_parts();
         let mut msg = format!("fetch-ebook-metadata failed rc={}", cp.status_code);
         if !cp.stderr.trim().is_empty() {
             msg.push_str(&format!(" stderr={}", cp.stderr.trim().chars().take(500).collect::<String>()));
         }
-        return Ok((false, msg));
+        if attempt >= fetch.max_retries {
+            if attempt > 0 {
+                msg.push_str(&format!(" (after {} attempts)", attempt + 1));
+            }
+            return Ok((false, msg));
+        }
+        let backoff = fetch.retry_backoff_seconds.max(0.0) * 2f64.powi(attempt as i32);
+        warn!(title = %title, attempt, backoff_seconds = backoff, error = %msg, "[fetch] retrying");
+        std::thread::sleep(std::time::Duration::from_secs_f64(backoff));
+        attempt += 1;
+    }
+    if opf_part.exists() {
+        std::fs::rename(&opf_part, opf_path)
+            .with_context(|| format!("Failed to move {} -> {}", opf_part.display(), opf_path.display()))?;
+    }
+    if cover_part.exists() {
+        std::fs::rename(&cover_part, cover_path).with_context(|| {
+            format!("Failed to move {} -> {}", cover_part.display(), cover_path.display())
+        })?;
     }
     if !opf_path.exists() || opf_path.metadata()?.len() == 0 {
         return Ok((false, "fetch-ebook-metadata produced no OPF".to_string()));
     }
+    if let (Some(cache), Some(key)) = (cache, &cache_key) {
+        cache.store(key, opf_path, cover_path);
+    }
     Ok((true, "fetched".to_string()))
 }
 
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// Runs the user's `fetch.external_provider` command for one book.
+///
+/// Contract: the command (program and arguments separated by whitespace; use
+/// a wrapper script for anything more elaborate) is run with these env vars:
+///
+/// - `CALIBRE_UPDATR_BOOK_ID`, `CALIBRE_UPDATR_TITLE`, `CALIBRE_UPDATR_AUTHORS`
+///   (joined with ` & `), `CALIBRE_UPDATR_ISBN`, `CALIBRE_UPDATR_IDENTIFIERS`
+///   (`key:value` pairs joined with `,`)
+/// - `CALIBRE_UPDATR_OPF_PATH`: where it must write the OPF
+/// - `CALIBRE_UPDATR_COVER_PATH`: where it may write a cover image
+///
+/// Exit 0 means an OPF was written; exit 2 means no match; anything else is a
+/// failure. It is subject to `fetch.timeout_seconds` like the built-in fetch.
+pub fn fetch_with_external_provider(
+    runner: &Runner,
+    command: &str,
+    book: &Value,
+    opf_path: &Path,
+    cover_path: &Path,
+    timeout_seconds: u64,
+) -> Result<(bool, String)> {
+    let cmd: Vec<String> = command.split_whitespace().map(|s| s.to_string()).collect();
+    if cmd.is_empty() {
+        return Ok((false, "external provider command is empty".to_string()));
+    }
+    let snap = metadata_snapshot(book);
+    let book_id = book.get("id").and_then(|v| v.as_i64()).unwrap_or(-1);
+    let mut identifiers: Vec<String> =
+        snap.identifiers.iter().map(|(k, v)| format!("{k}:{v}")).collect();
+    identifiers.sort();
+    let env = HashMap::from([
+        ("CALIBRE_UPDATR_BOOK_ID".to_string(), book_id.to_string()),
+        ("CALIBRE_UPDATR_TITLE".to_string(), snap.title.clone()),
+        ("CALIBRE_UPDATR_AUTHORS".to_string(), snap.authors.join(" & ")),
+        ("CALIBRE_UPDATR_ISBN".to_string(), snap.isbn.clone()),
+        ("CALIBRE_UPDATR_IDENTIFIERS".to_string(), identifiers.join(",")),
+        ("CALIBRE_UPDATR_OPF_PATH".to_string(), opf_path.display().to_string()),
+        ("CALIBRE_UPDATR_COVER_PATH".to_string(), cover_path.display().to_string()),
+    ]);
+
+    info!(book_id, command = %command, "[fetch] running external provider");
+    let cp = runner.run_with_timeout(
+        &cmd,
+        true,
+        Some(&env),
+        Some(std::time::Duration::from_secs(timeout_seconds)),
+        None,
+    )?;
+    if cp.timed_out {
+        return Ok((false, format!("external provider timed out after {timeout_seconds}s")));
+    }
+    match cp.status_code {
+        0 => {}
+        2 => return Ok((false, "external provider found no match".to_string())),
+        rc => {
+            let mut msg = format!("external provider failed rc={rc}");
+            if !cp.stderr.trim().is_empty() {
+                msg.push_str(&format!(" stderr={}", cp.stderr.trim().chars().take(500).collect::<String>()));
+            }
+            return Ok((false, msg));
+        }
+    }
+    if !opf_path.exists() || opf_path.metadata()?.len() == 0 {
+        return Ok((false, "external provider produced no OPF".to_string()));
+    }
+    Ok((true, "fetched (external provider)".to_string()))
+}
+
+/// With `cover`, the cover goes in the same `set_metadata` call, so the db
+/// never holds the new metadata next to the old cover.
 pub fn apply_opf_to_calibre_db(
     runner: &Runner,
     lib: &str,
     book_id: i64,
     opf_path: &Path,
+    cover: Option<&Path>,
 ) -> Result<(bool, String)> {
     let mut cmd = vec![
         "calibredb".to_string(),
@@ -236,7 +651,116 @@ pub fn apply_opf_to_calibre_db(
         book_id.to_string(),
         opf_path.display().to_string(),
     ]);
-    info!(book_id, "[apply] set_metadata");
+    cmd.extend(cover_field_args(cover));
+    info!(book_id, cover = cover.is_some(), "[apply] set_metadata");
+    let cp = runner.run(&cmd, true, None)?;
+    if cp.status_code != 0 {
+        let mut msg = format!("set_metadata failed rc={}", cp.status_code);
+        if !cp.stderr.trim().is_empty() {
+            msg.push_str(&format!(" stderr={}", cp.stderr.trim().chars().take(500).collect::<String>()));
+        }
+        return Ok((false, msg));
+    }
+    Ok((true, applied_message("metadata applied", cover)))
+}
+
+fn cover_field_args(cover: Option<&Path>) -> Vec<String> {
+    cover
+        .map(|path| vec!["--field".to_string(), format!("cover:{}", path.display())])
+        .unwrap_or_default()
+}
+
+fn applied_message(base: &str, cover: Option<&Path>) -> String {
+    if cover.is_some() {
+        format!("{base}; cover applied")
+    } else {
+        base.to_string()
+    }
+}
+
+/// Value for a calibredb `--field` taken from the OPF; `None` when the OPF
+/// has nothing for it (so the db value is left alone) or the field is not
+/// supported.
+fn opf_field_value(opf: &OpfMetadata, field: &str) -> Option<String> {
+    let value = match field {
+        "title" => opf.title.clone(),
+        "authors" => opf.authors.join(" & "),
+        "publisher" => opf.publisher.clone(),
+        "pubdate" => opf.pubdate.clone(),
+        "comments" => opf.comments.clone(),
+        "series" => opf.series.clone(),
+        "series_index" => opf.series_index.clone(),
+        "tags" => opf.tags.join(","),
+        "languages" => opf.languages.join(","),
+        "isbn" => opf.identifiers.get("isbn").cloned().unwrap_or_default(),
+        "identifiers" => opf
+            .identifiers
+            .iter()
+            .map(|(k, v)| format!("{k}:{v}"))
+            .collect::<Vec<_>>()
+            .join(","),
+        _ => return None,
+    };
+    if value.trim().is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// Applies only the allowed fields from the OPF via `set_metadata --field`,
+/// so every other db field is guaranteed untouched. A `cover` field in
+/// `fields` is ignored; the fetched cover comes in through `cover`, as in
+/// [`apply_opf_to_calibre_db`].
+pub fn apply_opf_fields_to_calibre_db(
+    runner: &Runner,
+    lib: &str,
+    book_id: i64,
+    opf_path: &Path,
+    fields: &[String],
+    cover: Option<&Path>,
+) -> Result<(bool, String)> {
+    let opf = match read_opf(opf_path) {
+        Ok(opf) => opf,
+        Err(e) => return Ok((false, format!("{e:#}"))),
+    };
+    let mut field_args = Vec::new();
+    for field in fields {
+        let field = field.trim().to_lowercase();
+        if field == "cover" {
+            continue;
+        }
+        if let Some(value) = opf_field_value(&opf, &field) {
+            field_args.push("--field".to_string());
+            field_args.push(format!("{field}:{value}"));
+        } else {
+            debug!(book_id, field = %field, "[apply] no value in OPF for allowed field");
+        }
+    }
+    if field_args.is_empty() && cover.is_none() {
+        return Ok((true, "no allowed fields to apply".to_string()));
+    }
+
+    let mut cmd = vec![
+        "calibredb".to_string(),
+        "--with-library".to_string(),
+        lib.to_string(),
+    ];
+    append_calibre_auth(
+        &mut cmd,
+        lib,
+        &runner.calibre_username,
+        &runner.calibre_password,
+    );
+    cmd.extend(["set_metadata".to_string(), book_id.to_string()]);
+    cmd.extend(field_args);
+    cmd.extend(cover_field_args(cover));
+    info!(
+        book_id,
+        fields = %fields.join(","),
+        cover = cover.is_some(),
+        "[apply] set_metadata (allowed fields)"
+    );
     let cp = runner.run(&cmd, true, None)?;
     if cp.status_code != 0 {
         let mut msg = format!("set_metadata failed rc={}", cp.status_code);
@@ -245,7 +769,7 @@ pub fn apply_opf_to_calibre_db(
         }
         return Ok((false, msg));
     }
-    Ok((true, "metadata applied".to_string()))
+    Ok((true, applied_message("metadata applied (allowed fields)", cover)))
 }
 
 pub fn apply_cover_to_calibre_db(
@@ -287,20 +811,149 @@ pub fn apply_cover_to_calibre_db(
     Ok((true, "cover applied".to_string()))
 }
 
+/// Renders page 1 of a PDF to `<out_prefix>.jpg` with `pdftoppm`.
+pub fn render_pdf_first_page(
+    runner: &Runner,
+    pdf_path: &Path,
+    out_prefix: &Path,
+) -> Result<(bool, String)> {
+    let cmd = vec![
+        "pdftoppm".to_string(),
+        "-jpeg".to_string(),
+        "-f".to_string(),
+        "1".to_string(),
+        "-l".to_string(),
+        "1".to_string(),
+        "-singlefile".to_string(),
+        pdf_path.display().to_string(),
+        out_prefix.display().to_string(),
+    ];
+    info!(pdf = %pdf_path.display(), "[cover] rendering pdf first page");
+    let cp = runner.run(&cmd, true, None)?;
+    if cp.status_code != 0 {
+        let mut msg = format!("pdftoppm failed rc={}", cp.status_code);
+        if !cp.stderr.trim().is_empty() {
+            msg.push_str(&format!(" stderr={}", cp.stderr.trim().chars().take(500).collect::<String>()));
+        }
+        return Ok((false, msg));
+    }
+    Ok((true, "rendered".to_string()))
+}
+
+/// Per-format outcome of [`embed_metadata_into_formats`].
+#[derive(Debug, Default)]
+pub struct EmbedResult {
+    pub embedded: Vec<String>,
+    /// (format, error message)
+    pub failed: Vec<(String, String)>,
+}
+
+impl EmbedResult {
+    pub fn partial(&self) -> bool {
+        !self.embedded.is_empty() && !self.failed.is_empty()
+    }
+
+    /// Lowercased formats that were embedded, with combined batches
+    /// (`EPUB,AZW3`) split back into their formats.
+    pub fn embedded_formats(&self) -> BTreeSet<String> {
+        self.embedded
+            .iter()
+            .flat_map(|batch| batch.split(','))
+            .map(|f| f.trim().to_lowercase())
+            .filter(|f| !f.is_empty())
+            .collect()
+    }
+
+    pub fn message(&self) -> String {
+        if self.failed.is_empty() {
+            return format!("embedded {}", self.embedded.join(","));
+        }
+        let failed = self
+            .failed
+            .iter()
+            .map(|(fmt, msg)| format!("{fmt}: {msg}"))
+            .collect::<Vec<_>>()
+            .join("; ");
+        if self.embedded.is_empty() {
+            failed
+        } else {
+            format!("embedded {}; failed {failed}", self.embedded.join(","))
+        }
+    }
+}
+
+/// Formats Calibre has a metadata writer for; `embed_metadata` silently does
+/// nothing for the rest.
+const EMBEDDABLE_FORMATS: &[&str] = &[
+    "azw", "azw1", "azw3", "azw4", "docx", "epub", "fb2", "fbz", "htmlz", "kepub", "lrf", "mobi",
+    "odt", "pdb", "pdf", "prc", "rtf", "tpz", "txtz",
+];
+
+pub fn can_embed(format: &str) -> bool {
+    EMBEDDABLE_FORMATS.contains(&format.to_lowercase().as_str())
+}
+
+/// Runs `calibredb embed_metadata` for the target formats. With
+/// `independently`, each format gets its own call so one corrupt format
+/// doesn't stop the others; otherwise a single call covers all of them.
 pub fn embed_metadata_into_formats(
     runner: &Runner,
     lib: &str,
     book_id: i64,
     target_formats: &BTreeMap<String, ()>,
-) -> Result<(bool, String)> {
+    independently: bool,
+) -> Result<EmbedResult> {
+    let mut result = EmbedResult::default();
     if target_formats.is_empty() {
-        return Ok((false, "no target formats".to_string()));
+        result.failed.push(("-".to_string(), "no target formats".to_string()));
+        return Ok(result);
     }
-    let fmt_arg = target_formats
-        .keys()
-        .map(|f| f.to_uppercase())
-        .collect::<Vec<_>>()
-        .join(",");
+    let formats: Vec<String> = target_formats.keys().map(|f| f.to_uppercase()).collect();
+    let batches = if independently {
+        formats
+    } else {
+        vec![formats.join(",")]
+    };
+    for fmt in batches {
+        let mut cmd = vec![
+            "calibredb".to_string(),
+            "--with-library".to_string(),
+            lib.to_string(),
+        ];
+        append_calibre_auth(
+            &mut cmd,
+            lib,
+            &runner.calibre_username,
+            &runner.calibre_password,
+        );
+        cmd.extend([
+            "embed_metadata".to_string(),
+            "--only-formats".to_string(),
+            fmt.clone(),
+            book_id.to_string(),
+        ]);
+        info!(book_id, format = %fmt, "[embed] embed_metadata");
+        let cp = runner.run(&cmd, true, None)?;
+        if cp.status_code != 0 {
+            let mut msg = format!("embed_metadata failed rc={}", cp.status_code);
+            if !cp.stderr.trim().is_empty() {
+                msg.push_str(&format!(" stderr={}", cp.stderr.trim().chars().take(500).collect::<String>()));
+            }
+            result.failed.push((fmt, msg));
+        } else {
+            result.embedded.push(fmt);
+        }
+    }
+    Ok(result)
+}
+
+pub fn refresh_one_book(
+    runner: &Runner,
+    lib: &str,
+    book_id: i64,
+    custom_columns: &[String],
+) -> Result<Option<Value>> {
+    let fields = book_fields(false, custom_columns);
     let mut cmd = vec![
         "calibredb".to_string(),
         "--with-library".to_string(),
@@ -313,40 +966,120 @@ pub fn embed_metadata_into_formats(
         &runner.calibre_password,
     );
     cmd.extend([
-        "embed_metadata".to_string(),
-        "--only-formats".to_string(),
-        fmt_arg,
+        "list".to_string(),
+        "--for-machine".to_string(),
+        "--fields".to_string(),
+        fields,
+        "--search".to_string(),
+        format!("id:{book_id}"),
+    ]);
+    let cp = runner.run_listing(&cmd)?;
+    // calibredb exits non-zero or prints nothing when the id matches no book
+    if cp.status_code != 0 || cp.stdout.trim().is_empty() {
+        warn!(
+            book_id,
+            rc = cp.status_code,
+            stderr = %cp.stderr.trim().chars().take(500).collect::<String>(),
+            "[refresh] book not listed"
+        );
+        return Ok(None);
+    }
+    let data: Value = serde_json::from_str(&cp.stdout)?;
+    let rows = data.as_array().map(Vec::as_slice).unwrap_or_default();
+    Ok(pick_book_row(rows, book_id).cloned())
+}
+
+/// The row for exactly `book_id`: the search can match more than that book.
+fn pick_book_row(rows: &[Value], book_id: i64) -> Option<&Value> {
+    let found = rows
+        .iter()
+        .find(|row| row.get("id").and_then(|v| v.as_i64()) == Some(book_id));
+    if rows.len() > 1 {
+        warn!(book_id, rows = rows.len(), "[refresh] search returned several books");
+    }
+    if found.is_none() {
+        warn!(book_id, rows = rows.len(), "[refresh] no row for the book in the listing");
+    }
+    found
+}
+
+/// The book's current db metadata as OPF (`show_metadata --as-opf`).
+pub fn show_metadata_opf(runner: &Runner, lib: &str, book_id: i64) -> Result<String> {
+    let mut cmd = vec![
+        "calibredb".to_string(),
+        "--with-library".to_string(),
+        lib.to_string(),
+    ];
+    append_calibre_auth(
+        &mut cmd,
+        lib,
+        &runner.calibre_username,
+        &runner.calibre_password,
+    );
+    cmd.extend([
+        "show_metadata".to_string(),
+        "--as-opf".to_string(),
+        book_id.to_string(),
+    ]);
+    let cp = runner.run(&cmd, true, None)?;
+    if cp.status_code != 0 || cp.stdout.trim().is_empty() {
+        let mut msg = format!("show_metadata failed rc={}", cp.status_code);
+        if !cp.stderr.trim().is_empty() {
+            msg.push_str(&format!(" stderr={}", cp.stderr.trim().chars().take(500).collect::<String>()));
+        }
+        anyhow::bail!(msg);
+    }
+    Ok(cp.stdout)
+}
+
+/// Adds `tag` to the book's current tags (read fresh from the db) unless it
+/// is already there.
+pub fn add_tag_to_book(runner: &Runner, lib: &str, book_id: i64, tag: &str) -> Result<(bool, String)> {
+    let Some(book) = refresh_one_book(runner, lib, book_id, &[])? else {
+        return Ok((false, "book not found when adding tag".to_string()));
+    };
+    let mut tags = metadata_snapshot(&book).tags;
+    if tags.iter().any(|t| t.eq_ignore_ascii_case(tag)) {
+        return Ok((true, "already tagged".to_string()));
+    }
+    tags.push(tag.to_string());
+
+    let mut cmd = vec![
+        "calibredb".to_string(),
+        "--with-library".to_string(),
+        lib.to_string(),
+    ];
+    append_calibre_auth(
+        &mut cmd,
+        lib,
+        &runner.calibre_username,
+        &runner.calibre_password,
+    );
+    cmd.extend([
+        "set_metadata".to_string(),
         book_id.to_string(),
+        "--field".to_string(),
+        format!("tags:{}", tags.join(",")),
     ]);
-    info!(book_id, "[embed] embed_metadata");
     let cp = runner.run(&cmd, true, None)?;
     if cp.status_code != 0 {
-        let mut msg = format!("embed_metadata failed rc={}", cp.status_code);
+        let mut msg = format!("set_metadata tags failed rc={}", cp.status_code);
         if !cp.stderr.trim().is_empty() {
             msg.push_str(&format!(" stderr={}", cp.stderr.trim().chars().take(500).collect::<String>()));
         }
         return Ok((false, msg));
     }
-    Ok((true, "embedded".to_string()))
-}
-
-pub fn refresh_one_book(runner: &Runner, lib: &str, book_id: i64) -> Result<Option<Value>> {
-    let fields = [
-        "id",
-        "title",
-        "authors",
-        "publisher",
-        "pubdate",
-        "languages",
-        "formats",
-        "isbn",
-        "identifiers",
-        "tags",
-        "comments",
-        "cover",
-        "last_modified",
-    ]
-    .join(",");
+    Ok((true, format!("tagged {tag}")))
+}
+
+/// Adds the file at `path` as a new format of the book; an existing format
+/// of the same type is left alone.
+pub fn add_format_to_book(
+    runner: &Runner,
+    lib: &str,
+    book_id: i64,
+    path: &Path,
+) -> Result<(bool, String)> {
     let mut cmd = vec![
         "calibredb".to_string(),
         "--with-library".to_string(),
@@ -359,24 +1092,310 @@ pub fn refresh_one_book(runner: &Runner, lib: &str, book_id: i64) -> Result<Opti
         &runner.calibre_password,
     );
     cmd.extend([
-        "list".to_string(),
-        "--for-machine".to_string(),
-        "--fields".to_string(),
-        fields,
-        "--search".to_string(),
-        format!("id:{book_id}"),
+        "add_format".to_string(),
+        "--dont-replace".to_string(),
+        book_id.to_string(),
+        path.display().to_string(),
     ]);
     let cp = runner.run(&cmd, true, None)?;
-    if cp.status_code != 0 || cp.stdout.trim().is_empty() {
+    if cp.status_code != 0 {
+        let mut msg = format!("add_format failed rc={}", cp.status_code);
+        if !cp.stderr.trim().is_empty() {
+            msg.push_str(&format!(" stderr={}", cp.stderr.trim().chars().take(500).collect::<String>()));
+        }
+        return Ok((false, msg));
+    }
+    Ok((true, "format added".to_string()))
+}
+
+/// Adds `path` as a new book and returns its id. Fails when calibredb
+/// reports no new id, e.g. because the book is already in the library.
+pub fn add_book_file(runner: &Runner, lib: &str, path: &Path) -> Result<i64> {
+    let mut cmd = vec![
+        "calibredb".to_string(),
+        "--with-library".to_string(),
+        lib.to_string(),
+    ];
+    append_calibre_auth(
+        &mut cmd,
+        lib,
+        &runner.calibre_username,
+        &runner.calibre_password,
+    );
+    cmd.extend(["add".to_string(), path.display().to_string()]);
+    let cp = runner.run(&cmd, true, None)?;
+    if cp.status_code != 0 {
+        let mut msg = format!("calibredb add failed rc={}", cp.status_code);
+        if !cp.stderr.trim().is_empty() {
+            msg.push_str(&format!(" stderr={}", cp.stderr.trim().chars().take(500).collect::<String>()));
+        }
+        anyhow::bail!(msg);
+    }
+    // "Added book ids: 123"
+    let id = cp
+        .stdout
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Added book ids:"))
+        .and_then(|ids| ids.split(',').next()?.trim().parse().ok());
+    match id {
+        Some(id) => Ok(id),
+        None => anyhow::bail!(
+            "calibredb add returned no new book id for {} (already in the library?): {}",
+            path.display(),
+            cp.stdout.trim().chars().take(500).collect::<String>()
+        ),
+    }
+}
+
+/// Removes the book (into Calibre's trash, not permanently).
+pub fn remove_book(runner: &Runner, lib: &str, book_id: i64) -> Result<(bool, String)> {
+    let mut cmd = vec![
+        "calibredb".to_string(),
+        "--with-library".to_string(),
+        lib.to_string(),
+    ];
+    append_calibre_auth(
+        &mut cmd,
+        lib,
+        &runner.calibre_username,
+        &runner.calibre_password,
+    );
+    cmd.extend(["remove".to_string(), book_id.to_string()]);
+    let cp = runner.run(&cmd, true, None)?;
+    if cp.status_code != 0 {
+        let mut msg = format!("remove failed rc={}", cp.status_code);
+        if !cp.stderr.trim().is_empty() {
+            msg.push_str(&format!(" stderr={}", cp.stderr.trim().chars().take(500).collect::<String>()));
+        }
+        return Ok((false, msg));
+    }
+    Ok((true, "removed".to_string()))
+}
+
+/// Calibre's config directory: `CALIBRE_CONFIG_DIRECTORY` if set, otherwise
+/// the platform default.
+fn calibre_config_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("CALIBRE_CONFIG_DIRECTORY") {
+        return Some(PathBuf::from(dir));
+    }
+    if cfg!(windows) {
+        return std::env::var_os("APPDATA").map(|d| PathBuf::from(d).join("calibre"));
+    }
+    let home = PathBuf::from(std::env::var_os("HOME")?);
+    if cfg!(target_os = "macos") {
+        return Some(home.join("Library/Preferences/calibre"));
+    }
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| home.join(".config"));
+    Some(base.join("calibre"))
+}
+
+/// Finds the library Calibre itself opens by default, from `global.py.json`,
+/// the older `global.py`, or the most-used entry in `gui.json`.
+pub fn discover_default_library() -> Option<PathBuf> {
+    let dir = calibre_config_dir()?;
+    let from_json = || -> Option<String> {
+        let text = std::fs::read_to_string(dir.join("global.py.json")).ok()?;
+        let v: Value = serde_json::from_str(&text).ok()?;
+        v.get("library_path")?.as_str().map(|s| s.to_string())
+    };
+    let from_py = || -> Option<String> {
+        let text = std::fs::read_to_string(dir.join("global.py")).ok()?;
+        text.lines().find_map(|line| {
+            let rest = line.trim().strip_prefix("library_path")?.trim_start();
+            let value = rest.strip_prefix('=')?.trim();
+            let value = value.strip_prefix('u').unwrap_or(value);
+            let quote = value.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+            value[1..].strip_suffix(quote).map(|s| s.to_string())
+        })
+    };
+    let from_gui = || -> Option<String> {
+        let text = std::fs::read_to_string(dir.join("gui.json")).ok()?;
+        let v: Value = serde_json::from_str(&text).ok()?;
+        v.get("library_usage_stats")?
+            .as_object()?
+            .iter()
+            .max_by_key(|(_, count)| count.as_i64().unwrap_or(0))
+            .map(|(path, _)| path.clone())
+    };
+    [from_json(), from_py(), from_gui()]
+        .into_iter()
+        .flatten()
+        .map(PathBuf::from)
+        .find(|p| p.join("metadata.db").is_file())
+}
+
+/// Reads title and authors embedded in a format file via `ebook-meta`.
+pub fn read_embedded_metadata(
+    runner: &Runner,
+    path: &Path,
+) -> Result<Option<(String, Vec<String>)>> {
+    let cmd = vec!["ebook-meta".to_string(), path.display().to_string()];
+    let cp = runner.run(&cmd, true, None)?;
+    if cp.status_code != 0 {
         return Ok(None);
     }
-    let data: Value = serde_json::from_str(&cp.stdout)?;
-    if let Some(arr) = data.as_array() {
-        if let Some(first) = arr.first() {
-            if first.is_object() {
-                return Ok(Some(first.clone()));
+    let mut title = None;
+    let mut authors = Vec::new();
+    for line in cp.stdout.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        match key.trim() {
+            "Title" => title = Some(value.trim().to_string()),
+            "Author(s)" => {
+                // "A & B [sort]" -> ["A", "B"]
+                let names = match value.rfind(" [") {
+                    Some(i) if value.trim_end().ends_with(']') => &value[..i],
+                    _ => value,
+                };
+                authors = names
+                    .split(" & ")
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect();
             }
+            _ => {}
         }
     }
-    Ok(None)
+    Ok(title.map(|t| (t, authors)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn formats(names: &[&str]) -> BTreeMap<String, ()> {
+        names.iter().map(|f| (f.to_string(), ())).collect()
+    }
+
+    fn rules(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(f, r)| (f.to_string(), r.to_string())).collect()
+    }
+
+    #[test]
+    fn search_expr_with_the_global_search_alone() {
+        let expr = build_search_expr(&formats(&["epub"]), &rules(&[]), &["tags:scifi".into()]);
+        assert_eq!(expr, "((formats:epub)) and (tags:scifi)");
+    }
+
+    #[test]
+    fn search_expr_with_one_rule() {
+        let expr = build_search_expr(
+            &formats(&["epub", "pdf"]),
+            &rules(&[("pdf", "size:<50M")]),
+            &[],
+        );
+        assert_eq!(expr, "(formats:epub) or (formats:pdf and (size:<50M))");
+    }
+
+    #[test]
+    fn search_expr_with_several_rules() {
+        let expr = build_search_expr(
+            &formats(&["epub", "pdf"]),
+            &rules(&[("epub", "tags:=fiction"), ("pdf", "size:<50M or tags:scan")]),
+            &[],
+        );
+        assert_eq!(
+            expr,
+            "(formats:epub and (tags:=fiction)) or (formats:pdf and (size:<50M or tags:scan))"
+        );
+    }
+
+    #[test]
+    fn search_expr_groups_rules_before_anding_the_search() {
+        // The search must apply to every format, not just the last OR term
+        let expr = build_search_expr(
+            &formats(&["epub", "pdf"]),
+            &rules(&[("pdf", "size:<50M"), ("mobi", "ignored")]),
+            &["author:Le Guin or author:Butler".into(), " ".into()],
+        );
+        assert_eq!(
+            expr,
+            "((formats:epub) or (formats:pdf and (size:<50M))) \
+             and (author:Le Guin or author:Butler)"
+        );
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn exact_match_clause_quotes_and_escapes() {
+        assert_eq!(
+            exact_match_clause("tags", &strings(&["Fiction", " Sci-Fi "])).as_deref(),
+            Some(r#"tags:"=Fiction" or tags:"=Sci-Fi""#)
+        );
+        assert_eq!(
+            exact_match_clause("tags", &strings(&[r#"say "hi""#, r"back\slash"])).as_deref(),
+            Some(r#"tags:"=say \"hi\"" or tags:"=back\\slash""#)
+        );
+        assert_eq!(exact_match_clause("tags", &strings(&["", "  "])), None);
+        assert_eq!(exact_match_clause("tags", &[]), None);
+    }
+
+    #[test]
+    fn tag_filter_includes_any_and_excludes_all() {
+        let book = json!({"id": 1, "tags": ["Fiction", "Sci-Fi"]});
+        let pass = |include: &[&str], exclude: &[&str]| {
+            passes_tag_filter(&book, &strings(include), &strings(exclude))
+        };
+        assert!(pass(&[], &[]));
+        assert!(pass(&["fiction"], &[]));
+        assert!(pass(&["Poetry", " SCI-FI "], &[]));
+        assert!(!pass(&["Poetry"], &[]));
+        assert!(!pass(&[], &["sci-fi"]));
+        assert!(!pass(&["Fiction"], &["Sci-Fi"]));
+        // Blank entries are ignored rather than matching everything or nothing
+        assert!(pass(&[""], &[" "]));
+        // Whole tags only
+        assert!(!pass(&["Sci"], &[]));
+
+        let untagged = json!({"id": 2});
+        assert!(passes_tag_filter(&untagged, &[], &strings(&["Fiction"])));
+        assert!(!passes_tag_filter(&untagged, &strings(&["Fiction"]), &[]));
+    }
+
+    #[test]
+    fn identifiers_rank_by_trust_then_name() {
+        let ids: HashMap<String, String> = [
+            ("mobi-asin", "m"),
+            ("goodreads", "g"),
+            ("google", "x"),
+            ("amazon", "a"),
+            ("isbn", "i"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let keys = |max| -> Vec<String> {
+            ranked_identifiers(ids.clone(), max).into_iter().map(|(k, _)| k).collect()
+        };
+        assert_eq!(keys(None), ["isbn", "amazon", "goodreads", "google", "mobi-asin"]);
+        assert_eq!(keys(Some(2)), ["isbn", "amazon"]);
+        assert!(keys(Some(0)).is_empty());
+        assert_eq!(keys(Some(10)).len(), 5);
+        assert!(ranked_identifiers(HashMap::new(), None).is_empty());
+    }
+
+    #[test]
+    fn pick_book_row_takes_the_exact_id_from_several_rows() {
+        let rows = vec![
+            json!({"id": 12, "title": "Twelve"}),
+            json!({"id": 1, "title": "One"}),
+            json!({"id": 123, "title": "One Two Three"}),
+        ];
+        let row = pick_book_row(&rows, 1).expect("row for id 1");
+        assert_eq!(row["title"], "One");
+    }
+
+    #[test]
+    fn pick_book_row_is_none_without_the_id() {
+        let rows = vec![json!({"id": 12}), json!({"id": 123})];
+        assert!(pick_book_row(&rows, 1).is_none());
+        assert!(pick_book_row(&[], 1).is_none());
+    }
 }
@@ -1,8 +1,12 @@
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{fmt, EnvFilter};
 
 const DEFAULT_ENGLISH_CODES: &[&str] = &["en", "eng", "en-us", "en-gb"];
@@ -17,9 +21,24 @@ pub enum CalibreEnvMode {
     Override,
 }
 
+/// Whether `fetch.external_provider` runs before or after fetch-ebook-metadata.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExternalProviderMode {
+    #[default]
+    Fallback,
+    Primary,
+}
+
 #[derive(Parser, Debug)]
 #[command(name = "calibre-updatr")]
 #[command(about = "Calibre bulk metadata updater + format embedder", long_about = None)]
+#[command(after_help = "Exit codes:\n  \
+0    run finished, no book failed\n  \
+1    at least one book failed, or the run aborted with an error (failed\n       \
+fetches under --dry-run-network are reported, not counted)\n  \
+2    the library listing matched no candidate books\n  \
+130  interrupted with Ctrl-C (state saved)")]
 pub struct Args {
     #[arg(long, default_value = "config.toml", help = "Path to config.toml")]
     pub config: String,
@@ -27,6 +46,12 @@ pub struct Args {
     pub library: Option<String>,
     #[arg(long, help = "Override: Calibre Content Server URL to the library")]
     pub library_url: Option<String>,
+    #[arg(
+        long,
+        visible_alias = "state-path",
+        help = "Override: Path to the state file (state.path)"
+    )]
+    pub state: Option<String>,
     #[arg(long, help = "Override: Calibre Content Server username")]
     pub calibre_username: Option<String>,
     #[arg(long, help = "Override: Calibre Content Server password")]
@@ -37,6 +62,183 @@ pub struct Args {
         help = "Override: dry run (no changes)"
     )]
     pub dry_run: bool,
+    #[arg(
+        long,
+        action = clap::ArgAction::SetTrue,
+        help = "Dry run that still fetches metadata and logs what would change; nothing is applied, embedded or recorded"
+    )]
+    pub dry_run_network: bool,
+    #[arg(
+        long,
+        action = clap::ArgAction::SetTrue,
+        help = "Override: render page 1 as the cover for cover-less PDFs (local libraries)"
+    )]
+    pub cover_from_first_page: bool,
+    #[arg(
+        long,
+        value_name = "DATE",
+        help = "Only process books added on/after this date (YYYY-MM-DD or RFC3339; Calibre's timestamp field)"
+    )]
+    pub added_since: Option<String>,
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Only process books by this author (exact name; repeat to OR several)"
+    )]
+    pub author: Vec<String>,
+    #[arg(
+        long,
+        value_name = "WHEN",
+        help = "Only process books whose metadata changed since WHEN (RFC3339, YYYY-MM-DD, or a duration like 24h/7d; Calibre's last_modified field)"
+    )]
+    pub since: Option<String>,
+    #[arg(long, value_name = "ID", help = "Skip candidates with a book id below this one")]
+    pub start_at: Option<i64>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Process a random N% of the candidates left after all other filters (0-100)"
+    )]
+    pub percent: Option<f64>,
+    #[arg(long, requires = "percent", help = "Seed for --percent, to draw the same sample again")]
+    pub seed: Option<u64>,
+    #[arg(
+        long,
+        action = clap::ArgAction::SetTrue,
+        help = "Only process books with no state entry at all (never retry or reprocess)"
+    )]
+    pub only_unprocessed: bool,
+    #[arg(
+        long,
+        value_name = "IDS",
+        value_delimiter = ',',
+        help = "Only process these book ids (comma-separated), even if already processed"
+    )]
+    pub only_ids: Vec<i64>,
+    #[arg(
+        long,
+        value_name = "ID",
+        help = "Print why this book would be skipped, filtered out, embedded only, or fetched, then exit"
+    )]
+    pub explain: Option<i64>,
+    #[arg(long, value_name = "N", help = "Override: number of books processed concurrently")]
+    pub jobs: Option<usize>,
+    #[arg(
+        long,
+        action = clap::ArgAction::SetTrue,
+        help = "Only refresh covers (below fetch.cover_min_width/height), ignoring text-metadata state"
+    )]
+    pub refetch_covers: bool,
+    #[arg(
+        long,
+        action = clap::ArgAction::SetTrue,
+        help = "Override: only fetch books with an ISBN or identifier, never by title/authors"
+    )]
+    pub isbn_only: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        num_args = 0..=1,
+        default_missing_value = "-",
+        help = "Stream per-book progress as JSON lines to PATH (default: stdout)"
+    )]
+    pub progress_json: Option<String>,
+    #[arg(long, value_name = "FILE", help = "Write a JSON report with per-book results to FILE")]
+    pub report: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write a CSV with each book's points per scoring field, total and decision to FILE"
+    )]
+    pub scores_csv: Option<String>,
+    #[arg(
+        long,
+        value_name = "DIR",
+        visible_alias = "output-dir",
+        help = "Write each processed book's fetched.opf, cover.jpg, before/after snapshots and result.json to DIR/<id>/"
+    )]
+    pub artifacts_dir: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Read/write a manifest of evaluated books; unchanged finished books are skipped without hashing"
+    )]
+    pub manifest: Option<String>,
+    #[arg(long, default_value_t = false, help = "Ignore the fetch cache (fetch.cache_ttl_seconds)")]
+    pub no_cache: bool,
+    #[arg(long, default_value_t = false, help = "Stop the run (non-zero exit) at the first failed book")]
+    pub fail_fast: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "After the run, list every skipped or filtered book grouped by skip reason"
+    )]
+    pub report_skipped: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Embed into every format a book has, not just formats.list (selection is unchanged)"
+    )]
+    pub all_formats: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Re-embed books state says are finished (never fetches for them); records embedded_only"
+    )]
+    pub force_reembed: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Mark state entries of books no longer in the library as deleted"
+    )]
+    pub include_deleted_check: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Like --include-deleted-check, but remove those state entries"
+    )]
+    pub prune_deleted: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Move state entries to a book's new id after Calibre renumbered it (by ISBN/identifiers)"
+    )]
+    pub follow_library_moves: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Skip books that have already failed N times (overrides policy.max_fail_count)"
+    )]
+    pub max_fail_count: Option<u32>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Proceed without asking when candidates exceed policy.max_candidates_warn"
+    )]
+    pub assume_yes: bool,
+    #[arg(long, default_value_t = false, help = "Run even if another instance holds the state file lock")]
+    pub force: bool,
+    #[arg(long, value_enum, help = "Print the run summary to stdout as text or json")]
+    pub summary_format: Option<crate::dups::OutputFormat>,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "text",
+        help = "Format of the per-book plan printed to stdout in dry-run mode (and of --config-check)"
+    )]
+    pub output: crate::dups::OutputFormat,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Validate the config (unknown keys, bad values), print the effective settings (--output text = TOML) and exit"
+    )]
+    pub config_check: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Fail instead of warning on ambiguous config (library.path and library.url both set)"
+    )]
+    pub strict_config: bool,
 
     #[command(subcommand)]
     pub command: Option<Command>,
@@ -46,9 +248,28 @@ pub struct Args {
 pub enum Command {
     /// Find duplicate files in a Calibre library via hashing
     Dups(crate::dups::DupsArgs),
+    /// Check the state file against the books currently in the library
+    Verify(crate::verify::VerifyArgs),
+    /// Parse an OPF file and show its fields and score (no Calibre needed)
+    ValidateOpf(crate::validate::ValidateOpfArgs),
+    /// Compare two state files: status/hash/fail_count changes and added or removed books
+    DiffState(crate::diff::DiffStateArgs),
+    /// Re-apply the OPFs saved by policy.backup_before_change, reverting the tool's changes
+    Restore(crate::restore::RestoreArgs),
+    /// Merge library books that share an ISBN into the one with the most formats
+    #[command(visible_alias = "dedup-identifiers")]
+    MergeDuplicates(crate::merge::MergeDuplicatesArgs),
+    /// Add a file to the library, then fetch/apply/embed just that book
+    EnrichFile(EnrichFileArgs),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Parser, Debug)]
+pub struct EnrichFileArgs {
+    /// Ebook file to add (e.g. from a watch folder)
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Config {
     pub logging: LoggingConfig,
@@ -60,6 +281,7 @@ pub struct Config {
     pub fetch: FetchConfig,
     pub policy: PolicyConfig,
     pub scoring: ScoringConfig,
+    pub retry: RetryConfig,
     pub dups: DupsConfig,
 }
 
@@ -67,9 +289,38 @@ pub struct Config {
 #[serde(default)]
 pub struct LoggingConfig {
     pub level: String,
+    /// Also write logs to this file (rotated per `rotation`)
+    pub file: Option<String>,
+    /// "daily", "hourly" or "never"
+    pub rotation: String,
+    /// Log timestamps in "utc" or "local" time; state files stay UTC
+    pub timestamp_tz: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl LoggingConfig {
+    /// Whether timestamps shown to the user are in local time.
+    pub fn local_time(&self) -> bool {
+        self.timestamp_tz.trim().eq_ignore_ascii_case("local")
+    }
+}
+
+/// Log line timer: tracing's default UTC timestamps, or RFC 3339 in the
+/// local offset for `logging.timestamp_tz = "local"`.
+struct LogClock {
+    local: bool,
+}
+
+impl fmt::time::FormatTime for LogClock {
+    fn format_time(&self, w: &mut fmt::format::Writer<'_>) -> std::fmt::Result {
+        if !self.local {
+            return fmt::time::SystemTime.format_time(w);
+        }
+        let now = chrono::Local::now();
+        write!(w, "{}", now.to_rfc3339_opts(chrono::SecondsFormat::Micros, false))
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct LibraryConfig {
     pub path: Option<String>,
@@ -80,12 +331,23 @@ pub struct LibraryConfig {
 #[serde(default)]
 pub struct StateConfig {
     pub path: Option<String>,
+    /// Coalesce state writes automatically when saving is slow
+    pub adaptive_flush: bool,
+    /// A save slower than this counts as slow
+    pub slow_save_ms: u64,
+    /// Upper bound on how many updates are folded into one write
+    pub max_flush_interval: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(default)]
 pub struct FormatsConfig {
     pub list: Vec<String>,
+    /// Extra calibredb search clause per format, ANDed with `formats:<fmt>`
+    pub rules: BTreeMap<String, String>,
+    /// Formats with no metadata container (comics): the db is still updated,
+    /// but embedding is skipped
+    pub non_embeddable: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -93,9 +355,17 @@ pub struct FormatsConfig {
 pub struct CalibredbConfig {
     pub env_mode: CalibreEnvMode,
     pub debug_env: bool,
+    /// List `comments` as a presence flag instead of fetching the full HTML
+    pub comments_presence_only: bool,
+    /// Retries when calibredb reports the library is locked by another program
+    pub lock_retries: u32,
+    pub lock_retry_seconds: f64,
+    /// Retries when a listing fails on a network error (remote libraries)
+    pub network_retries: u32,
+    pub network_retry_seconds: f64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct ContentServerConfig {
     pub username: Option<String>,
@@ -110,6 +380,66 @@ pub struct FetchConfig {
     pub timeout_seconds: u64,
     pub heartbeat_seconds: u64,
     pub use_xvfb: bool,
+    pub pdf_first_page_cover: bool,
+    /// Forward at most this many identifiers to fetch-ebook-metadata (unset = all)
+    pub max_identifiers_forwarded: Option<usize>,
+    /// Persistent directory for fetched OPF/cover artifacts (unset = temp dir, removed on exit)
+    pub workdir: Option<String>,
+    /// Leave each book's artifacts in `workdir` after it finishes
+    pub keep_artifacts: bool,
+    /// Delete artifacts older than this many days from `workdir` at startup
+    pub artifact_retention_days: Option<u64>,
+    /// Command for a user-supplied metadata provider (see calibre::fetch_with_external_provider)
+    pub external_provider: Option<String>,
+    pub external_provider_mode: ExternalProviderMode,
+    /// Extra attempts after a non-zero fetch-ebook-metadata exit within one run
+    pub max_retries: u32,
+    /// Sleep before retry n is `retry_backoff_seconds * 2^n`
+    pub retry_backoff_seconds: f64,
+    /// Dry-run estimate: HTTP requests one fetch makes across all sources
+    pub estimate_requests_per_fetch: f64,
+    /// Dry-run estimate: average downloaded cover size in bytes
+    pub estimate_cover_bytes: u64,
+    /// `--refetch-covers` only replaces covers narrower/shorter than these
+    /// (both unset = refresh every cover)
+    pub cover_min_width: Option<u32>,
+    pub cover_min_height: Option<u32>,
+    /// Metadata sources fetch-ebook-metadata may use (empty = all enabled)
+    pub allowed_sources: Vec<String>,
+    /// Pass only the first author to fetch-ebook-metadata
+    pub primary_author_only: bool,
+    /// Joins multiple authors into the single `--authors` value
+    pub authors_delimiter: String,
+    /// Reuse fetched OPFs/covers for identical queries this long (unset = no cache)
+    pub cache_ttl_seconds: Option<u64>,
+    /// fetch-ebook-metadata processes allowed to run at once
+    pub max_concurrent: usize,
+    /// Reject a fetched OPF from this source when it scores below the value
+    pub source_min_score: HashMap<String, i32>,
+    /// Skip the fetch for books with no ISBN/identifier instead of matching
+    /// on title/authors
+    pub require_strong_identifier: bool,
+}
+
+/// Sources `fetch.source_min_score` can name; `unknown` covers OPFs without
+/// a source-specific identifier.
+pub const KNOWN_SOURCES: &[&str] =
+    &["amazon", "douban", "edelweiss", "google", "openlibrary", "overdrive", "unknown"];
+
+/// Lowercase alphanumeric form of a source name (`Open Library` -> `openlibrary`).
+pub fn source_key(name: &str) -> String {
+    name.trim().to_lowercase().chars().filter(|c| c.is_ascii_alphanumeric()).collect()
+}
+
+impl FetchConfig {
+    /// Minimum score for an OPF fetched from `source` (`None` = unknown source).
+    pub fn source_min_score(&self, source: Option<&str>) -> Option<i32> {
+        let wanted = source.unwrap_or("unknown");
+        self.source_min_score
+            .iter()
+            .find(|(name, _)| source_key(name) == wanted)
+            .map(|(_, score)| *score)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -120,6 +450,38 @@ pub struct PolicyConfig {
     pub include_missing_language: bool,
     pub english_codes: Vec<String>,
     pub delay_between_fetches_seconds: f64,
+    pub verify_sample_rate: f64,
+    pub verify_sample_seed: Option<u64>,
+    /// Read back every embedded book; mismatches become `embed_unverified`
+    pub verify_embed: bool,
+    /// When non-empty, only these fields are ever written to the db
+    pub settable_fields: Vec<String>,
+    /// Books processed concurrently; delay_between_fetches_seconds applies globally
+    pub max_concurrency: usize,
+    /// Tag added (never replacing existing tags) to books after a successful update
+    pub processed_tag: Option<String>,
+    /// Skip embedding (db update only) when a target format file is larger
+    pub max_embed_file_bytes: Option<u64>,
+    /// Books that stay retryable (`failed`) instead of becoming `failed_permanent`
+    pub never_permanent_ids: Vec<i64>,
+    /// Name of a Calibre saved search that candidates must also match
+    pub saved_search: Option<String>,
+    /// One embed_metadata call per format; the book succeeds if any format does
+    pub embed_all_formats_independently: bool,
+    /// Process books left in `started` by an interrupted run before the rest
+    pub prioritize_interrupted: bool,
+    /// Only books with at least one of these tags (empty = no restriction)
+    pub include_tags: Vec<String>,
+    /// Never books with any of these tags
+    pub exclude_tags: Vec<String>,
+    /// Skip `failed` books that have failed this many times (unset = no cap)
+    pub max_fail_count: Option<u32>,
+    /// Skip `failed` books last attempted less than this long ago (0 = off)
+    pub retry_cooldown_seconds: u64,
+    /// Ask before processing more candidates than this (0 = never ask)
+    pub max_candidates_warn: usize,
+    /// Save each book's db metadata as an OPF before changing it
+    pub backup_before_change: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -128,6 +490,8 @@ pub struct ScoringConfig {
     pub min_score_to_skip_fetch: i32,
     pub require_title: bool,
     pub require_authors: bool,
+    /// Never good enough without a valid ISBN, whatever the score
+    pub require_isbn_for_good_enough: bool,
     pub title_weight: i32,
     pub authors_weight: i32,
     pub publisher_weight: i32,
@@ -136,7 +500,40 @@ pub struct ScoringConfig {
     pub identifiers_weight: i32,
     pub tags_weight: i32,
     pub comments_weight: i32,
+    /// Shorter comments (markup stripped) score as missing (0 = any comment counts)
+    pub min_comment_chars: usize,
     pub cover_weight: i32,
+    /// 0 leaves series out of scoring entirely
+    pub series_weight: i32,
+    /// Calibre custom columns (`#name`) and the weight awarded when non-empty
+    pub custom_columns: BTreeMap<String, i32>,
+}
+
+/// What happens to a book after a failure, across runs: `"permanent"` marks it
+/// `failed_permanent` at once, `"always"` retries it every run, and a table
+/// limits retries and/or waits before the next one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RetryPolicy {
+    Mode(String),
+    Rule {
+        /// Failures beyond this many retries become `failed_permanent`
+        max_retries: Option<u32>,
+        /// "none", "exponential" (1h doubling, capped at 30d) or a span like 7d
+        backoff: Option<String>,
+    },
+}
+
+/// Retry policy per failure category.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub fetch_timeout: RetryPolicy,
+    pub fetch_no_match: RetryPolicy,
+    pub network: RetryPolicy,
+    pub apply: RetryPolicy,
+    pub embed: RetryPolicy,
+    pub error: RetryPolicy,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -144,46 +541,37 @@ pub struct ScoringConfig {
 pub struct DupsConfig {
     pub threads: usize,
     pub min_size: u64,
+    /// Skip files larger than this (0 = no limit)
+    pub max_size: u64,
     pub include_sidecars: bool,
     pub follow_symlinks: bool,
+    pub parallel_walk: bool,
+    pub fast: bool,
+    /// blake3, sha256 or xxh3
+    pub hash: String,
     pub ext: Vec<String>,
     pub output: String,
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            logging: LoggingConfig::default(),
-            library: LibraryConfig::default(),
-            state: StateConfig::default(),
-            formats: FormatsConfig::default(),
-            calibredb: CalibredbConfig::default(),
-            content_server: ContentServerConfig::default(),
-            fetch: FetchConfig::default(),
-            policy: PolicyConfig::default(),
-            scoring: ScoringConfig::default(),
-            dups: DupsConfig::default(),
-        }
-    }
-}
-
 impl Default for LoggingConfig {
     fn default() -> Self {
         Self {
             level: "info".to_string(),
+            file: None,
+            rotation: "daily".to_string(),
+            timestamp_tz: "utc".to_string(),
         }
     }
 }
 
-impl Default for LibraryConfig {
-    fn default() -> Self {
-        Self { path: None, url: None }
-    }
-}
-
 impl Default for StateConfig {
     fn default() -> Self {
-        Self { path: None }
+        Self {
+            path: None,
+            adaptive_flush: true,
+            slow_save_ms: 250,
+            max_flush_interval: 32,
+        }
     }
 }
 
@@ -191,6 +579,8 @@ impl Default for FormatsConfig {
     fn default() -> Self {
         Self {
             list: vec!["epub".to_string(), "pdf".to_string()],
+            rules: BTreeMap::new(),
+            non_embeddable: ["cbz", "cbr", "cb7"].map(String::from).to_vec(),
         }
     }
 }
@@ -200,15 +590,11 @@ impl Default for CalibredbConfig {
         Self {
             env_mode: CalibreEnvMode::Inherit,
             debug_env: false,
-        }
-    }
-}
-
-impl Default for ContentServerConfig {
-    fn default() -> Self {
-        Self {
-            username: None,
-            password: None,
+            comments_presence_only: false,
+            lock_retries: 3,
+            lock_retry_seconds: 5.0,
+            network_retries: 3,
+            network_retry_seconds: 2.0,
         }
     }
 }
@@ -230,6 +616,26 @@ impl Default for FetchConfig {
             timeout_seconds: 45,
             heartbeat_seconds: 10,
             use_xvfb: false,
+            pdf_first_page_cover: false,
+            max_identifiers_forwarded: None,
+            workdir: None,
+            keep_artifacts: false,
+            artifact_retention_days: None,
+            external_provider: None,
+            external_provider_mode: ExternalProviderMode::Fallback,
+            max_retries: 0,
+            retry_backoff_seconds: 2.0,
+            estimate_requests_per_fetch: 8.0,
+            estimate_cover_bytes: 200_000,
+            cover_min_width: None,
+            cover_min_height: None,
+            allowed_sources: Vec::new(),
+            primary_author_only: false,
+            authors_delimiter: ", ".to_string(),
+            cache_ttl_seconds: None,
+            max_concurrent: 2,
+            source_min_score: HashMap::new(),
+            require_strong_identifier: false,
         }
     }
 }
@@ -242,16 +648,45 @@ impl Default for PolicyConfig {
             include_missing_language: true,
             english_codes: DEFAULT_ENGLISH_CODES.iter().map(|s| s.to_string()).collect(),
             delay_between_fetches_seconds: DEFAULT_DELAY_BETWEEN_FETCHES_SECONDS,
+            verify_sample_rate: 0.0,
+            verify_sample_seed: None,
+            verify_embed: false,
+            settable_fields: Vec::new(),
+            max_concurrency: 1,
+            processed_tag: None,
+            max_embed_file_bytes: None,
+            never_permanent_ids: Vec::new(),
+            saved_search: None,
+            embed_all_formats_independently: false,
+            prioritize_interrupted: false,
+            include_tags: Vec::new(),
+            exclude_tags: Vec::new(),
+            max_fail_count: None,
+            retry_cooldown_seconds: 0,
+            max_candidates_warn: 5000,
+            backup_before_change: false,
         }
     }
 }
 
+impl PolicyConfig {
+    /// Whether `field` may be written to the db under `settable_fields`.
+    pub fn may_set(&self, field: &str) -> bool {
+        self.settable_fields.is_empty()
+            || self
+                .settable_fields
+                .iter()
+                .any(|f| f.trim().eq_ignore_ascii_case(field))
+    }
+}
+
 impl Default for ScoringConfig {
     fn default() -> Self {
         Self {
             min_score_to_skip_fetch: DEFAULT_MIN_SCORE_TO_SKIP_FETCH,
             require_title: true,
             require_authors: true,
+            require_isbn_for_good_enough: false,
             title_weight: 1,
             authors_weight: 1,
             publisher_weight: 1,
@@ -260,32 +695,187 @@ impl Default for ScoringConfig {
             identifiers_weight: 2,
             tags_weight: 1,
             comments_weight: 1,
+            min_comment_chars: 0,
             cover_weight: 1,
+            series_weight: 0,
+            custom_columns: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        let always = || RetryPolicy::Mode("always".to_string());
+        Self {
+            fetch_timeout: RetryPolicy::Mode("permanent".to_string()),
+            fetch_no_match: always(),
+            network: always(),
+            apply: always(),
+            embed: always(),
+            error: always(),
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn policies(&self) -> [(&'static str, &RetryPolicy); 6] {
+        [
+            ("fetch_timeout", &self.fetch_timeout),
+            ("fetch_no_match", &self.fetch_no_match),
+            ("network", &self.network),
+            ("apply", &self.apply),
+            ("embed", &self.embed),
+            ("error", &self.error),
+        ]
+    }
+
+    pub fn get(&self, category: &str) -> &RetryPolicy {
+        self.policies()
+            .into_iter()
+            .find(|(name, _)| *name == category)
+            .map_or(&self.error, |(_, policy)| policy)
+    }
+}
+
+const MAX_EXPONENTIAL_BACKOFF_DAYS: i64 = 30;
+
+impl RetryPolicy {
+    /// For the `fail_count`-th consecutive failure: whether the book is now
+    /// permanently failed, and how long to wait before retrying it.
+    pub fn after_failure(&self, fail_count: i32) -> (bool, Option<chrono::Duration>) {
+        match self {
+            RetryPolicy::Mode(mode) => (mode.trim().eq_ignore_ascii_case("permanent"), None),
+            RetryPolicy::Rule { max_retries, backoff } => {
+                let permanent = max_retries.is_some_and(|n| fail_count > n as i32);
+                let wait = match backoff.as_deref().map(str::trim) {
+                    None | Some("") | Some("none") => None,
+                    Some("exponential") => {
+                        let exp = fail_count.clamp(1, 16) as u32 - 1;
+                        Some(
+                            chrono::Duration::hours(1i64 << exp)
+                                .min(chrono::Duration::days(MAX_EXPONENTIAL_BACKOFF_DAYS)),
+                        )
+                    }
+                    Some(span) => parse_duration(span).ok(),
+                };
+                (permanent, wait)
+            }
+        }
+    }
+
+    fn problem(&self, name: &str) -> Option<String> {
+        match self {
+            RetryPolicy::Mode(mode) => (!["permanent", "always"]
+                .contains(&mode.trim().to_lowercase().as_str()))
+            .then(|| format!("retry.{name} {mode:?} is not permanent, always or a table")),
+            RetryPolicy::Rule { backoff: Some(b), .. } => {
+                let b = b.trim();
+                if b.is_empty() || b == "none" || b == "exponential" {
+                    return None;
+                }
+                parse_duration(b)
+                    .err()
+                    .map(|e| format!("retry.{name}.backoff {b:?}: {e}"))
+            }
+            RetryPolicy::Rule { .. } => None,
         }
     }
 }
 
+/// A span like `30m`, `12h`, `7d` or `2w`.
+pub fn parse_duration(raw: &str) -> Result<chrono::Duration> {
+    let raw = raw.trim();
+    let split = raw.len() - raw.chars().last().map_or(0, char::len_utf8);
+    let (num, unit) = raw.split_at(split);
+    let n: i64 = num
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("expected a duration like 24h"))?;
+    if n < 0 {
+        anyhow::bail!("duration {raw:?} is negative");
+    }
+    Ok(match unit {
+        "m" => chrono::Duration::minutes(n),
+        "h" => chrono::Duration::hours(n),
+        "d" => chrono::Duration::days(n),
+        "w" => chrono::Duration::weeks(n),
+        _ => anyhow::bail!("unknown duration unit {unit:?} (use m, h, d or w)"),
+    })
+}
+
 impl Default for DupsConfig {
     fn default() -> Self {
         Self {
             threads: 0,
             min_size: 0,
+            max_size: 0,
             include_sidecars: false,
             follow_symlinks: false,
+            parallel_walk: false,
+            fast: false,
             ext: Vec::new(),
             output: "text".to_string(),
+            hash: "blake3".to_string(),
         }
     }
 }
 
-pub fn init_tracing(default_level: &str) {
+/// Sets up stderr logging plus, when `logging.file` is set, a rotating file
+/// log. Keep the returned guard alive until exit or buffered lines are lost.
+pub fn init_tracing(logging: &LoggingConfig) -> Result<Option<WorkerGuard>> {
     let filter = EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| EnvFilter::new(default_level));
-    fmt()
-        .with_env_filter(filter)
+        .unwrap_or_else(|_| EnvFilter::new(&logging.level));
+    // An unknown value falls back to UTC so --config-check can still report it
+    let local = logging.local_time();
+    let stderr_layer = fmt::layer()
+        .with_timer(LogClock { local })
+        .with_writer(std::io::stderr)
         .with_target(false)
-        .with_level(true)
+        .with_level(true);
+
+    let (file_layer, guard) = match logging.file.as_deref().map(str::trim) {
+        Some(file) if !file.is_empty() => {
+            let path = Path::new(file);
+            let dir = path
+                .parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            let prefix = path
+                .file_name()
+                .ok_or_else(|| anyhow::anyhow!("logging.file has no file name: {file}"))?;
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create log directory {}", dir.display()))?;
+            let rotation = match logging.rotation.trim().to_lowercase().as_str() {
+                "daily" => Rotation::DAILY,
+                "hourly" => Rotation::HOURLY,
+                "never" => Rotation::NEVER,
+                other => anyhow::bail!("Unknown logging.rotation {other:?} (daily, hourly, never)"),
+            };
+            let appender = RollingFileAppender::new(rotation, dir, prefix);
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            let layer = fmt::layer()
+                .with_timer(LogClock { local })
+                .with_writer(writer)
+                .with_ansi(false)
+                .with_target(false)
+                .with_level(true);
+            (Some(layer), Some(guard))
+        }
+        _ => (None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(stderr_layer)
+        .with(file_layer)
         .init();
+    if !local && !logging.timestamp_tz.trim().eq_ignore_ascii_case("utc") {
+        tracing::warn!(
+            timestamp_tz = %logging.timestamp_tz,
+            "[config] unknown logging.timestamp_tz (utc, local); using utc"
+        );
+    }
+    Ok(guard)
 }
 
 pub fn normalize_library_spec(spec: &str) -> String {
@@ -305,14 +895,287 @@ pub fn normalize_optional_string(value: Option<String>) -> Option<String> {
     }
 }
 
-pub fn load_config(path: &Path) -> Result<Config> {
+/// Top-level keys of the original flat config layout and where they live now.
+const LEGACY_KEYS: &[(&str, &str, &str)] = &[
+    ("library_path", "library", "path"),
+    ("library_url", "library", "url"),
+    ("state_path", "state", "path"),
+    ("log_level", "logging", "level"),
+    ("env_mode", "calibredb", "env_mode"),
+    ("calibredb_env_mode", "calibredb", "env_mode"),
+    ("username", "content_server", "username"),
+    ("password", "content_server", "password"),
+    ("headless", "fetch", "headless"),
+    ("timeout_seconds", "fetch", "timeout_seconds"),
+    ("fetch_timeout_seconds", "fetch", "timeout_seconds"),
+    ("heartbeat_seconds", "fetch", "heartbeat_seconds"),
+    ("use_xvfb", "fetch", "use_xvfb"),
+    ("dry_run", "policy", "dry_run"),
+    ("reprocess_on_metadata_change", "policy", "reprocess_on_metadata_change"),
+    ("include_missing_language", "policy", "include_missing_language"),
+    ("english_codes", "policy", "english_codes"),
+    ("delay_between_fetches_seconds", "policy", "delay_between_fetches_seconds"),
+    ("min_score_to_skip_fetch", "scoring", "min_score_to_skip_fetch"),
+    ("require_title", "scoring", "require_title"),
+    ("require_authors", "scoring", "require_authors"),
+    ("title_weight", "scoring", "title_weight"),
+    ("authors_weight", "scoring", "authors_weight"),
+    ("publisher_weight", "scoring", "publisher_weight"),
+    ("pubdate_weight", "scoring", "pubdate_weight"),
+    ("isbn_weight", "scoring", "isbn_weight"),
+    ("identifiers_weight", "scoring", "identifiers_weight"),
+    ("tags_weight", "scoring", "tags_weight"),
+    ("comments_weight", "scoring", "comments_weight"),
+    ("cover_weight", "scoring", "cover_weight"),
+];
+
+/// A flat key that was moved: (old name, new dotted name).
+pub type LegacyKey = (String, String);
+
+/// Moves flat-layout keys into their sections so old configs keep loading.
+/// `library = "..."`, `state = "..."` and `formats = [...]` are the flat
+/// forms of what are now tables. Returns `(old, new)` names of moved keys;
+/// a key already set in its section wins over the flat one.
+fn migrate_legacy_keys(table: &mut toml::Table) -> Vec<LegacyKey> {
+    let mut moves: Vec<(String, &str, String)> = Vec::new();
+    for (key, value) in table.iter() {
+        let target = match (key.as_str(), value) {
+            ("library", toml::Value::String(s)) if s.starts_with("http") => {
+                Some(("library", "url"))
+            }
+            ("library", toml::Value::String(_)) => Some(("library", "path")),
+            ("state", toml::Value::String(_)) => Some(("state", "path")),
+            ("formats", toml::Value::Array(_)) => Some(("formats", "list")),
+            _ => LEGACY_KEYS
+                .iter()
+                .find(|(old, _, _)| old == key)
+                .map(|(_, section, new)| (*section, *new)),
+        };
+        if let Some((section, new)) = target {
+            moves.push((key.clone(), section, new.to_string()));
+        }
+    }
+    let mut moved = Vec::new();
+    for (old, section, new) in moves {
+        let Some(value) = table.remove(&old) else {
+            continue;
+        };
+        let entry = table
+            .entry(section)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        if let toml::Value::Table(section_table) = entry
+            && !section_table.contains_key(&new)
+        {
+            section_table.insert(new.clone(), value);
+        }
+        moved.push((old, format!("{section}.{new}")));
+    }
+    moved
+}
+
+/// Loads the config along with any keys serde ignored (usually typos, which
+/// `serde(default)` would otherwise turn into silent defaults) and any
+/// legacy flat keys that were mapped onto the nested layout.
+pub fn load_config(path: &Path) -> Result<(Config, Vec<String>, Vec<LegacyKey>)> {
     let contents = std::fs::read_to_string(path).with_context(|| {
         format!(
             "Failed to read config file {} (create one from config.toml)",
             path.display()
         )
     })?;
-    let cfg: Config = toml::from_str(&contents)
+    let mut table: toml::Table = toml::from_str(&contents)
         .with_context(|| format!("Failed to parse config {}", path.display()))?;
-    Ok(cfg)
+    let legacy = migrate_legacy_keys(&mut table);
+    let mut unknown_keys = Vec::new();
+    let mut note_unknown = |key: serde_ignored::Path| unknown_keys.push(key.to_string());
+    // Parse the text itself when possible: its errors carry line numbers
+    let cfg: Config = if legacy.is_empty() {
+        serde_ignored::deserialize(toml::Deserializer::new(&contents), &mut note_unknown)
+            .with_context(|| format!("Failed to parse config {}", path.display()))?
+    } else {
+        serde_ignored::deserialize(toml::Value::Table(table), &mut note_unknown)
+            .with_context(|| format!("Failed to parse config {}", path.display()))?
+    };
+    Ok((cfg, unknown_keys, legacy))
+}
+
+/// Semantic problems that parse fine but make a run pointless or fail later.
+pub fn validate_config(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    if config.formats.list.iter().all(|f| f.trim().is_empty()) {
+        problems.push("formats.list is empty".to_string());
+    }
+    if config.library.path.is_none() && config.library.url.is_none() {
+        problems.push("neither library.path nor library.url is set".to_string());
+    }
+    if !["daily", "hourly", "never"].contains(&config.logging.rotation.trim().to_lowercase().as_str()) {
+        problems.push(format!(
+            "logging.rotation {:?} is not daily, hourly or never",
+            config.logging.rotation
+        ));
+    }
+    if !["utc", "local"].contains(&config.logging.timestamp_tz.trim().to_lowercase().as_str()) {
+        problems.push(format!(
+            "logging.timestamp_tz {:?} is not utc or local",
+            config.logging.timestamp_tz
+        ));
+    }
+    if !(0.0..=1.0).contains(&config.policy.verify_sample_rate) {
+        problems.push("policy.verify_sample_rate must be between 0 and 1".to_string());
+    }
+    if config.policy.max_concurrency == 0 {
+        problems.push("policy.max_concurrency must be at least 1".to_string());
+    }
+    if crate::dups::HashAlgo::parse(&config.dups.hash).is_none() {
+        problems.push(format!("dups.hash {:?} is not blake3, sha256 or xxh3", config.dups.hash));
+    }
+    if config.dups.max_size > 0 && config.dups.max_size < config.dups.min_size {
+        problems.push("dups.max_size is smaller than dups.min_size".to_string());
+    }
+    if config.fetch.max_concurrent == 0 {
+        problems.push("fetch.max_concurrent must be at least 1".to_string());
+    }
+    for name in config.fetch.source_min_score.keys() {
+        if !KNOWN_SOURCES.contains(&source_key(name).as_str()) {
+            problems.push(format!(
+                "fetch.source_min_score has unknown source {name:?} (known: {})",
+                KNOWN_SOURCES.join(", ")
+            ));
+        }
+    }
+    problems.extend(
+        config
+            .retry
+            .policies()
+            .into_iter()
+            .filter_map(|(name, policy)| policy.problem(name)),
+    );
+    let s = &config.scoring;
+    let max_score = s.title_weight
+        + s.authors_weight
+        + s.publisher_weight
+        + s.pubdate_weight
+        + s.isbn_weight.max(s.identifiers_weight)
+        + s.tags_weight
+        + s.comments_weight
+        + s.cover_weight
+        + s.series_weight.max(0)
+        + s.custom_columns.values().filter(|w| **w > 0).sum::<i32>();
+    if s.min_score_to_skip_fetch > max_score {
+        problems.push(format!(
+            "scoring.min_score_to_skip_fetch ({}) exceeds the highest possible score ({max_score}); every book would be fetched",
+            s.min_score_to_skip_fetch
+        ));
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_config_maps_flat_legacy_keys_onto_sections() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("old.toml");
+        std::fs::write(
+            &path,
+            r#"
+library = "/books/Calibre Library"
+formats = ["epub", "azw3"]
+min_score_to_skip_fetch = 7
+english_codes = ["en", "eng"]
+
+[fetch]
+headless = true
+"#,
+        )
+        .unwrap();
+
+        let (cfg, unknown, legacy) = load_config(&path).unwrap();
+        assert_eq!(cfg.library.path.as_deref(), Some("/books/Calibre Library"));
+        assert_eq!(cfg.library.url, None);
+        assert_eq!(cfg.formats.list, ["epub", "azw3"]);
+        assert_eq!(cfg.scoring.min_score_to_skip_fetch, 7);
+        assert_eq!(cfg.policy.english_codes, ["en", "eng"]);
+        assert!(cfg.fetch.headless);
+        assert!(unknown.is_empty(), "unknown keys: {unknown:?}");
+
+        let mut legacy = legacy;
+        legacy.sort();
+        let expected: Vec<LegacyKey> = [
+            ("english_codes", "policy.english_codes"),
+            ("formats", "formats.list"),
+            ("library", "library.path"),
+            ("min_score_to_skip_fetch", "scoring.min_score_to_skip_fetch"),
+        ]
+        .iter()
+        .map(|(old, new)| (old.to_string(), new.to_string()))
+        .collect();
+        assert_eq!(legacy, expected);
+    }
+
+    #[test]
+    fn legacy_http_library_becomes_the_url() {
+        let mut table: toml::Table =
+            toml::from_str(r#"library = "http://host:8080/#lib""#).unwrap();
+        let legacy = migrate_legacy_keys(&mut table);
+        assert_eq!(legacy, [("library".to_string(), "library.url".to_string())]);
+        assert_eq!(table["library"]["url"].as_str(), Some("http://host:8080/#lib"));
+    }
+
+    #[test]
+    fn mode_policies_never_wait() {
+        let permanent = RetryPolicy::Mode("permanent".to_string());
+        assert_eq!(permanent.after_failure(1), (true, None));
+        assert_eq!(RetryPolicy::Mode(" Permanent ".to_string()).after_failure(1), (true, None));
+        let always = RetryPolicy::Mode("always".to_string());
+        assert_eq!(always.after_failure(1), (false, None));
+        assert_eq!(always.after_failure(100), (false, None));
+    }
+
+    #[test]
+    fn rule_turns_permanent_after_max_retries() {
+        let rule = |max_retries, backoff: Option<&str>| RetryPolicy::Rule {
+            max_retries,
+            backoff: backoff.map(str::to_string),
+        };
+        let two = rule(Some(2), None);
+        assert_eq!(two.after_failure(1), (false, None));
+        assert_eq!(two.after_failure(2), (false, None));
+        assert_eq!(two.after_failure(3), (true, None));
+        assert_eq!(rule(Some(0), Some("none")).after_failure(1), (true, None));
+        assert_eq!(rule(None, Some("")).after_failure(1000), (false, None));
+
+        let fixed = rule(Some(1), Some("7d"));
+        assert_eq!(fixed.after_failure(1), (false, Some(chrono::Duration::days(7))));
+        assert_eq!(fixed.after_failure(2), (true, Some(chrono::Duration::days(7))));
+        // An unparsable span (flagged by --config-check) just doesn't wait
+        assert_eq!(rule(None, Some("soon")).after_failure(1), (false, None));
+    }
+
+    #[test]
+    fn exponential_backoff_doubles_up_to_the_cap() {
+        let exp = RetryPolicy::Rule { max_retries: None, backoff: Some("exponential".to_string()) };
+        let wait = |n| exp.after_failure(n).1.unwrap();
+        assert_eq!(wait(0), chrono::Duration::hours(1));
+        assert_eq!(wait(1), chrono::Duration::hours(1));
+        assert_eq!(wait(2), chrono::Duration::hours(2));
+        assert_eq!(wait(5), chrono::Duration::hours(16));
+        assert_eq!(wait(10), chrono::Duration::hours(512));
+        assert_eq!(wait(11), chrono::Duration::days(MAX_EXPONENTIAL_BACKOFF_DAYS));
+        assert_eq!(wait(1000), chrono::Duration::days(MAX_EXPONENTIAL_BACKOFF_DAYS));
+    }
+
+    #[test]
+    fn parse_duration_units_and_errors() {
+        assert_eq!(parse_duration("30m").unwrap(), chrono::Duration::minutes(30));
+        assert_eq!(parse_duration(" 12h ").unwrap(), chrono::Duration::hours(12));
+        assert_eq!(parse_duration("7d").unwrap(), chrono::Duration::days(7));
+        assert_eq!(parse_duration("2w").unwrap(), chrono::Duration::weeks(2));
+        assert_eq!(parse_duration("0d").unwrap(), chrono::Duration::zero());
+        for raw in ["", "d", "7", "7y", "-1d", "1.5h", "7 days"] {
+            assert!(parse_duration(raw).is_err(), "{raw:?}");
+        }
+    }
 }
@@ -0,0 +1,72 @@
+use crate::dups::{write_output, OutputFormat};
+use crate::state::{diff_states, load_state, StateDiff};
+use anyhow::Result;
+use clap::Parser;
+use std::path::{Path, PathBuf};
+
+#[derive(Parser, Debug)]
+pub struct DiffStateArgs {
+    /// Older state file (e.g. a copy from another machine or before a config change)
+    pub old: PathBuf,
+
+    /// Newer state file; defaults to this run's state file (--state / state.path)
+    pub new: Option<PathBuf>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value = "text")]
+    pub output: OutputFormat,
+}
+
+/// Prints how `new` (or the current state file) differs from `old`.
+pub fn run_diff_state(current_state: &Path, args: &DiffStateArgs) -> Result<()> {
+    let new_path = args.new.as_deref().unwrap_or(current_state);
+    // load_state treats a missing file as empty state, which would diff as "all removed"
+    for path in [args.old.as_path(), new_path] {
+        if !path.is_file() {
+            anyhow::bail!("State file not found: {}", path.display());
+        }
+    }
+    let old = load_state(&args.old)?;
+    let new = load_state(new_path)?;
+    let diff = diff_states(&old, &new);
+    let rendered = match args.output {
+        OutputFormat::Text => render_text(&diff, &args.old, new_path),
+        OutputFormat::Json => serde_json::to_string_pretty(&diff)?,
+    };
+    write_output(&rendered, None)
+}
+
+fn render_text(diff: &StateDiff, old: &Path, new: &Path) -> String {
+    let mut out = format!("--- {}\n+++ {}\n", old.display(), new.display());
+    if diff.is_empty() {
+        out.push_str("no differences");
+        return out;
+    }
+    for c in &diff.changed {
+        let mut line = format!("~ {}: {}", c.id, c.old_status);
+        if c.new_status != c.old_status {
+            line.push_str(&format!(" -> {}", c.new_status));
+        }
+        if c.new_fail_count != c.old_fail_count {
+            line.push_str(&format!(" (fail_count {} -> {})", c.old_fail_count, c.new_fail_count));
+        }
+        if c.hash_changed {
+            line.push_str(" (hash changed)");
+        }
+        out.push_str(&line);
+        out.push('\n');
+    }
+    for b in &diff.removed {
+        out.push_str(&format!("- {}: {}\n", b.id, b.status));
+    }
+    for b in &diff.added {
+        out.push_str(&format!("+ {}: {}\n", b.id, b.status));
+    }
+    out.push_str(&format!(
+        "{} changed, {} only in old, {} only in new",
+        diff.changed.len(),
+        diff.removed.len(),
+        diff.added.len()
+    ));
+    out
+}
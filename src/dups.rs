@@ -1,21 +1,31 @@
 use anyhow::{Context, Result};
-use blake3::Hasher;
 use clap::{Parser, ValueEnum};
 use rayon::prelude::*;
 use serde::Serialize;
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{BufReader, Read};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
 use std::time::Instant;
 use tracing::{debug, info, warn};
 use walkdir::{DirEntry, WalkDir};
+use xxhash_rust::xxh3::Xxh3;
 
 #[derive(Parser, Debug)]
 pub struct DupsArgs {
-    /// Path to the Calibre library root (folder containing author directories)
+    /// Path to a Calibre library root (folder containing author directories).
+    /// Repeatable to find duplicates across several libraries.
     #[arg(long)]
-    pub library: Option<PathBuf>,
+    pub library: Vec<PathBuf>,
+
+    /// Label each file with the root it lives under, e.g. the original
+    /// libraries inside a merged --library directory (repeatable; the
+    /// longest matching root wins)
+    #[arg(long)]
+    pub library_root: Vec<PathBuf>,
 
     /// Output format
     #[arg(long, value_enum)]
@@ -41,9 +51,36 @@ pub struct DupsArgs {
     #[arg(long, default_value_t = 0)]
     pub min_size: u64,
 
+    /// Skip files larger than this many bytes (0 = no limit)
+    #[arg(long, visible_alias = "max-bytes", default_value_t = 0)]
+    pub max_size: u64,
+
     /// Also hash common Calibre sidecar files (metadata.opf, cover.jpg, etc)
     #[arg(long, default_value_t = false)]
     pub include_sidecars: bool,
+
+    /// Walk top-level directories (authors) in parallel; helps when directory
+    /// reads are slow (cold cache, network mounts), costs a little on a warm local one
+    #[arg(long, default_value_t = false)]
+    pub parallel_walk: bool,
+
+    /// Full-file hash used to group duplicates (default blake3)
+    #[arg(long, value_enum)]
+    pub hash: Option<HashAlgo>,
+
+    /// Only fully hash files whose size and first 64 KiB match another file
+    #[arg(long, default_value_t = false)]
+    pub fast: bool,
+
+    /// What to do with each group's non-canonical copies (the canonical copy
+    /// is the lexicographically first path). Deleting leaves Calibre pointing
+    /// at missing files; prefer hardlink inside a library.
+    #[arg(long, value_enum, default_value = "report")]
+    pub action: DupsAction,
+
+    /// Actually perform --action (otherwise only print what would happen)
+    #[arg(long, default_value_t = false)]
+    pub yes: bool,
 }
 
 #[derive(Copy, Clone, Debug, ValueEnum)]
@@ -52,6 +89,72 @@ pub enum OutputFormat {
     Json,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum HashAlgo {
+    Blake3,
+    Sha256,
+    /// 64-bit XXH3, as printed by `xxhsum -H3`
+    Xxh3,
+}
+
+impl HashAlgo {
+    /// Case-insensitive, for the `dups.hash` config value
+    pub fn parse(name: &str) -> Option<HashAlgo> {
+        <HashAlgo as ValueEnum>::from_str(name.trim(), true).ok()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgo::Blake3 => "blake3",
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Xxh3 => "xxh3",
+        }
+    }
+
+    fn hasher(self) -> FileHasher {
+        match self {
+            HashAlgo::Blake3 => FileHasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgo::Sha256 => FileHasher::Sha256(Sha256::new()),
+            HashAlgo::Xxh3 => FileHasher::Xxh3(Box::new(Xxh3::new())),
+        }
+    }
+}
+
+enum FileHasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+    Xxh3(Box<Xxh3>),
+}
+
+impl FileHasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            FileHasher::Blake3(h) => {
+                h.update(data);
+            }
+            FileHasher::Sha256(h) => h.update(data),
+            FileHasher::Xxh3(h) => h.update(data),
+        }
+    }
+
+    fn finish_hex(self) -> String {
+        match self {
+            FileHasher::Blake3(h) => h.finalize().to_hex().to_string(),
+            FileHasher::Sha256(h) => {
+                h.finalize().iter().map(|b| format!("{b:02x}")).collect()
+            }
+            FileHasher::Xxh3(h) => format!("{:016x}", h.digest()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DupsAction {
+    Report,
+    Hardlink,
+    Delete,
+}
+
 #[derive(Debug, Clone)]
 pub struct DupsSettings {
     pub output: OutputFormat,
@@ -60,31 +163,60 @@ pub struct DupsSettings {
     pub follow_symlinks: bool,
     pub threads: usize,
     pub min_size: u64,
+    pub max_size: u64,
     pub include_sidecars: bool,
+    pub parallel_walk: bool,
+    pub hash: HashAlgo,
+    pub fast: bool,
+    pub action: DupsAction,
+    pub yes: bool,
+    pub library_roots: Vec<PathBuf>,
 }
 
 #[derive(Debug, Clone, Serialize)]
 struct FileInfo {
     path: PathBuf,
+    /// Index into the scanned library list
+    library: usize,
     bytes: u64,
-    blake3: String,
+    digest: String,
 }
 
 #[derive(Debug, Serialize)]
 struct DuplicateGroup {
     bytes: u64,
-    blake3: String,
+    /// Name of the `--hash` algorithm that produced `digest`
+    algo: &'static str,
+    digest: String,
+    #[serde(serialize_with = "lossy_paths")]
     files: Vec<PathBuf>,
+    /// Owning library of each entry in `files`, in the same order
+    #[serde(serialize_with = "lossy_paths")]
+    libraries: Vec<PathBuf>,
+    /// `--library-root` of each entry in `files` (None outside every root);
+    /// empty without `--library-root`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    roots: Vec<Option<String>>,
+    /// Indexes into `files` of paths that are not valid UTF-8 and are
+    /// rendered lossily
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    non_utf8: Vec<usize>,
 }
 
-pub fn run_dups(library: &Path, settings: &DupsSettings) -> Result<()> {
+/// JSON strings must be UTF-8, so invalid bytes become U+FFFD.
+fn lossy_paths<S: serde::Serializer>(paths: &[PathBuf], s: S) -> Result<S::Ok, S::Error> {
+    s.collect_seq(paths.iter().map(|p| p.to_string_lossy()))
+}
+
+pub fn run_dups(libraries: &[PathBuf], settings: &DupsSettings) -> Result<()> {
+    // A local pool leaves the global one free for the rest of the program
     if settings.threads > 0 {
-        info!(threads = settings.threads, "Configuring Rayon thread pool");
-        rayon::ThreadPoolBuilder::new()
-            .num_threads(settings.threads)
-            .build_global()
-            .context("Failed to configure Rayon global thread pool")?;
+        info!(threads = settings.threads, "Configuring hashing thread pool");
     }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(settings.threads)
+        .build()
+        .context("Failed to build hashing thread pool")?;
 
     let started = Instant::now();
 
@@ -99,39 +231,102 @@ pub fn run_dups(library: &Path, settings: &DupsSettings) -> Result<()> {
     };
 
     info!(
-        library = %library.display(),
+        libraries = %libraries.iter().map(|l| l.display().to_string()).collect::<Vec<_>>().join(", "),
         follow_symlinks = settings.follow_symlinks,
         include_sidecars = settings.include_sidecars,
         min_size = settings.min_size,
+        max_size = settings.max_size,
+        hash = settings.hash.name(),
         exts = ?exts,
         "Starting duplicate scan"
     );
 
-    let candidates = collect_candidates(
-        library,
-        &exts,
-        settings.follow_symlinks,
-        settings.min_size,
-        settings.include_sidecars,
-    )?;
-
-    info!(count = candidates.len(), "Collected candidate files");
+    let root_labels = RootLabels::new(libraries, &settings.library_roots);
+    let filter = EntryFilter {
+        exts: &exts,
+        follow_symlinks: settings.follow_symlinks,
+        min_size: settings.min_size,
+        max_size: settings.max_size,
+        include_sidecars: settings.include_sidecars,
+        too_large: AtomicUsize::new(0),
+    };
+    let stream = match (settings.output, &settings.out) {
+        (OutputFormat::Text, None) => Some(GroupStream {
+            show_library: libraries.len() > 1,
+            printed: Mutex::new(0),
+        }),
+        _ => None,
+    };
+    let hashed = pool.install(|| -> Result<Vec<FileInfo>> {
+        let per_library = libraries
+            .par_iter()
+            .map(|library| {
+                if settings.parallel_walk {
+                    collect_candidates_parallel(library, &filter)
+                } else {
+                    collect_candidates(library, &filter)
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let too_large = filter.too_large.load(Ordering::Relaxed);
+        if too_large > 0 {
+            info!(count = too_large, max_size = settings.max_size, "Skipped files over max size");
+        }
 
-    let hashed: Vec<FileInfo> = candidates
-        .par_iter()
-        .map(|path| hash_one(path))
-        .filter_map(|r| match r {
-            Ok(v) => Some(v),
-            Err(e) => {
-                warn!(error = %e, "Skipping file due to error");
-                None
+        // Nested or repeated roots would otherwise report a file as its own duplicate;
+        // the first library listed owns it.
+        let mut seen = HashSet::new();
+        let mut candidates: Vec<(usize, PathBuf)> = Vec::new();
+        for (idx, paths) in per_library.into_iter().enumerate() {
+            for path in paths {
+                if seen.insert(path.clone()) {
+                    candidates.push((idx, path));
+                }
             }
-        })
-        .collect();
+        }
+
+        info!(count = candidates.len(), "Collected candidate files");
+
+        let candidates = if settings.fast {
+            narrow_by_prefix(candidates)
+        } else {
+            candidates
+        };
+
+        // Files can only match within a size, so each size bucket's groups are
+        // final once that bucket is hashed; text on stdout prints them then.
+        let hashed: Vec<FileInfo> = by_size(candidates)
+            .par_iter()
+            .flat_map(|bucket| {
+                let hashed: Vec<FileInfo> = bucket
+                    .par_iter()
+                    .map(|(idx, path)| hash_one(*idx, path, settings.hash))
+                    .filter_map(|r| match r {
+                        Ok(v) => Some(v),
+                        Err(e) => {
+                            warn!(error = %e, "Skipping file due to error");
+                            None
+                        }
+                    })
+                    .collect();
+                if let Some(stream) = &stream {
+                    let groups =
+                        find_duplicates(hashed.clone(), libraries, &root_labels, settings);
+                    stream.emit(&groups);
+                }
+                hashed
+            })
+            .collect();
+        Ok(hashed)
+    })?;
 
     info!(count = hashed.len(), "Finished hashing files");
 
-    let dupes = find_duplicates(hashed);
+    let dupes = find_duplicates(hashed, libraries, &root_labels, settings);
+    let lossy: usize = dupes.iter().map(|g| g.non_utf8.len()).sum();
+    if lossy > 0 {
+        warn!(count = lossy, "Rendered non-UTF-8 paths lossily in the output");
+    }
 
     info!(
         groups = dupes.len(),
@@ -139,14 +334,142 @@ pub fn run_dups(library: &Path, settings: &DupsSettings) -> Result<()> {
         "Done"
     );
 
-    match settings.output {
-        OutputFormat::Text => print_text(&dupes, settings.out.as_deref())?,
-        OutputFormat::Json => print_json(&dupes, settings.out.as_deref())?,
+    match (settings.output, &stream) {
+        (OutputFormat::Text, Some(_)) => {
+            let summary = if dupes.is_empty() {
+                no_duplicates_line(settings.hash)
+            } else {
+                format!("Duplicate groups: {}\n", dupes.len())
+            };
+            write_output(summary.trim_end(), None)?;
+        }
+        (OutputFormat::Text, None) => {
+            print_text(&dupes, libraries.len() > 1, settings.hash, settings.out.as_deref())?
+        }
+        (OutputFormat::Json, _) => print_json(&dupes, settings.out.as_deref())?,
+    }
+
+    if settings.action != DupsAction::Report {
+        apply_action(&dupes, settings.action, settings.yes);
     }
 
     Ok(())
 }
 
+/// Splits candidates by file size, dropping files that can't be stat'ed.
+fn by_size(candidates: Vec<(usize, PathBuf)>) -> Vec<Vec<(usize, PathBuf)>> {
+    let sized: Vec<(u64, (usize, PathBuf))> = candidates
+        .into_par_iter()
+        .filter_map(|(idx, path)| match path.metadata() {
+            Ok(md) => Some((md.len(), (idx, path))),
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "Skipping file due to error");
+                None
+            }
+        })
+        .collect();
+    let mut buckets: HashMap<u64, Vec<(usize, PathBuf)>> = HashMap::new();
+    for (size, entry) in sized {
+        buckets.entry(size).or_default().push(entry);
+    }
+    buckets.into_values().collect()
+}
+
+/// Prints text groups to stdout as soon as they are confirmed, numbered in
+/// the order they were found. File output keeps the sorted report instead.
+struct GroupStream {
+    show_library: bool,
+    printed: Mutex<usize>,
+}
+
+impl GroupStream {
+    fn emit(&self, groups: &[DuplicateGroup]) {
+        if groups.is_empty() {
+            return;
+        }
+        let mut printed = self.printed.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut buf = String::new();
+        for g in groups {
+            *printed += 1;
+            push_group(&mut buf, *printed, g, self.show_library);
+        }
+        print!("{buf}");
+    }
+}
+
+/// Hardlinks or deletes every copy but the first in each group. Without
+/// `yes` it only logs what it would do.
+fn apply_action(groups: &[DuplicateGroup], action: DupsAction, yes: bool) {
+    let (mut done, mut skipped) = (0, 0);
+    for g in groups {
+        let Some((canonical, others)) = g.files.split_first() else {
+            continue;
+        };
+        for dup in others {
+            if !yes {
+                info!(
+                    action = ?action,
+                    path = %dup.display(),
+                    canonical = %canonical.display(),
+                    "[dry-run] would apply (pass --yes to do it)"
+                );
+                continue;
+            }
+            let result = match action {
+                DupsAction::Hardlink => hardlink_over(canonical, dup),
+                DupsAction::Delete => std::fs::remove_file(dup)
+                    .with_context(|| format!("Failed to delete {}", dup.display())),
+                DupsAction::Report => Ok(()),
+            };
+            match result {
+                Ok(()) => {
+                    done += 1;
+                    info!(action = ?action, path = %dup.display(), canonical = %canonical.display(), "Applied");
+                }
+                Err(e) => {
+                    skipped += 1;
+                    warn!(action = ?action, path = %dup.display(), error = %format!("{e:#}"), "Skipped");
+                }
+            }
+        }
+    }
+    if yes {
+        info!(action = ?action, applied = done, skipped, "Finished duplicate action");
+    }
+}
+
+/// Replaces `dup` with a hardlink to `canonical` via a temp link and rename,
+/// so `dup` is never lost if linking fails (e.g. across filesystems).
+fn hardlink_over(canonical: &Path, dup: &Path) -> Result<()> {
+    if same_file(canonical, dup) {
+        anyhow::bail!("already a hardlink of {}", canonical.display());
+    }
+    let mut tmp_name = dup.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".dups-link");
+    let tmp = dup.with_file_name(tmp_name);
+    std::fs::hard_link(canonical, &tmp)
+        .with_context(|| format!("Failed to hardlink {} -> {}", tmp.display(), canonical.display()))?;
+    if let Err(e) = std::fs::rename(&tmp, dup) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e).with_context(|| format!("Failed to replace {}", dup.display()));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (a.metadata(), b.metadata()) {
+        (Ok(ma), Ok(mb)) => ma.dev() == mb.dev() && ma.ino() == mb.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
 fn default_exts() -> Vec<String> {
     vec![
         "epub", "pdf", "mobi", "azw", "azw3", "djvu", "fb2", "rtf", "txt", "doc",
@@ -161,49 +484,64 @@ fn is_sidecar(name: &str) -> bool {
     matches!(name, "metadata.opf" | "cover.jpg" | "cover.jpeg" | "cover.png")
 }
 
-fn want_entry(entry: &DirEntry, exts: &[String], min_size: u64, include_sidecars: bool) -> bool {
+/// Which walked files get hashed. `too_large` counts files skipped for
+/// exceeding `max_size` across all walker threads.
+struct EntryFilter<'a> {
+    exts: &'a [String],
+    follow_symlinks: bool,
+    min_size: u64,
+    /// 0 = no limit
+    max_size: u64,
+    include_sidecars: bool,
+    too_large: AtomicUsize,
+}
+
+fn want_entry(entry: &DirEntry, filter: &EntryFilter) -> bool {
     if !entry.file_type().is_file() {
         return false;
     }
 
     let path = entry.path();
 
-    if min_size > 0 {
-        if let Ok(md) = path.metadata() {
-            if md.len() < min_size {
-                return false;
-            }
-        }
-    }
-
-    let file_name = match path.file_name().and_then(|s| s.to_str()) {
-        Some(s) => s,
+    // Lossy so a non-UTF-8 name is still matched by its extension
+    let file_name = match path.file_name() {
+        Some(s) => s.to_string_lossy(),
         None => return false,
     };
 
-    if include_sidecars && is_sidecar(file_name) {
-        return true;
-    }
-
-    let ext = match path.extension().and_then(|s| s.to_str()) {
-        Some(s) => s.to_ascii_lowercase(),
-        None => return false,
+    let wanted = if filter.include_sidecars && is_sidecar(&file_name) {
+        true
+    } else {
+        let ext = match path.extension() {
+            Some(s) => s.to_string_lossy().to_ascii_lowercase(),
+            None => return false,
+        };
+        filter.exts.iter().any(|e| e == &ext)
     };
+    if !wanted {
+        return false;
+    }
 
-    exts.iter().any(|e| e == &ext)
+    // Size last so only files of interest are stat'ed and counted
+    if (filter.min_size > 0 || filter.max_size > 0)
+        && let Ok(md) = path.metadata()
+    {
+        if md.len() < filter.min_size {
+            return false;
+        }
+        if filter.max_size > 0 && md.len() > filter.max_size {
+            filter.too_large.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+    }
+    true
 }
 
-fn collect_candidates(
-    library: &Path,
-    exts: &[String],
-    follow_symlinks: bool,
-    min_size: u64,
-    include_sidecars: bool,
-) -> Result<Vec<PathBuf>> {
+fn collect_candidates(library: &Path, filter: &EntryFilter) -> Result<Vec<PathBuf>> {
     let mut out = Vec::new();
 
     let walker = WalkDir::new(library)
-        .follow_links(follow_symlinks)
+        .follow_links(filter.follow_symlinks)
         .into_iter();
 
     for entry in walker {
@@ -215,7 +553,7 @@ fn collect_candidates(
             }
         };
 
-        if want_entry(&entry, exts, min_size, include_sidecars) {
+        if want_entry(&entry, filter) {
             out.push(entry.path().to_path_buf());
         } else {
             debug!(path = %entry.path().display(), "Skipping");
@@ -225,7 +563,108 @@ fn collect_candidates(
     Ok(out)
 }
 
-fn hash_one(path: &Path) -> Result<FileInfo> {
+/// Walks each top-level directory (one per author in a Calibre library) on
+/// the Rayon pool. Results are sorted so output does not depend on which
+/// walk finished first.
+fn collect_candidates_parallel(library: &Path, filter: &EntryFilter) -> Result<Vec<PathBuf>> {
+    let mut subdirs = Vec::new();
+    let mut out = Vec::new();
+
+    let walker = WalkDir::new(library)
+        .follow_links(filter.follow_symlinks)
+        .min_depth(1)
+        .max_depth(1)
+        .into_iter();
+
+    for entry in walker {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                warn!(error = %e, "WalkDir error");
+                continue;
+            }
+        };
+        if entry.file_type().is_dir() {
+            subdirs.push(entry.path().to_path_buf());
+        } else if want_entry(&entry, filter) {
+            out.push(entry.path().to_path_buf());
+        }
+    }
+
+    let nested: Vec<PathBuf> = subdirs
+        .par_iter()
+        .map(|dir| collect_candidates(dir, filter))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+    out.extend(nested);
+    out.sort();
+
+    Ok(out)
+}
+
+const PREFIX_BYTES: u64 = 64 * 1024;
+
+/// Drops candidates that cannot have a duplicate: first by size, then by a
+/// hash of the first 64 KiB. Survivors still get a full hash.
+fn narrow_by_prefix(candidates: Vec<(usize, PathBuf)>) -> Vec<(usize, PathBuf)> {
+    let total = candidates.len();
+
+    let mut by_size: HashMap<u64, Vec<(usize, PathBuf)>> = HashMap::new();
+    for (idx, path) in candidates {
+        match path.metadata() {
+            Ok(md) => by_size.entry(md.len()).or_default().push((idx, path)),
+            Err(e) => warn!(path = %path.display(), error = %e, "Skipping file due to error"),
+        }
+    }
+    let same_size: Vec<(u64, usize, PathBuf)> = by_size
+        .into_iter()
+        .filter(|(_, group)| group.len() >= 2)
+        .flat_map(|(size, group)| group.into_iter().map(move |(idx, path)| (size, idx, path)))
+        .collect();
+    let size_survivors = same_size.len();
+
+    let prefixed: Vec<((u64, String), (usize, PathBuf))> = same_size
+        .into_par_iter()
+        .filter_map(|(size, idx, path)| match hash_prefix(&path) {
+            Ok(prefix) => Some(((size, prefix), (idx, path))),
+            Err(e) => {
+                warn!(error = %e, "Skipping file due to error");
+                None
+            }
+        })
+        .collect();
+    let mut by_prefix: HashMap<(u64, String), Vec<(usize, PathBuf)>> = HashMap::new();
+    for (key, entry) in prefixed {
+        by_prefix.entry(key).or_default().push(entry);
+    }
+    let mut out: Vec<(usize, PathBuf)> = by_prefix
+        .into_values()
+        .filter(|group| group.len() >= 2)
+        .flatten()
+        .collect();
+    out.sort_by(|a, b| a.1.cmp(&b.1));
+
+    info!(
+        candidates = total,
+        same_size = size_survivors,
+        same_prefix = out.len(),
+        "Narrowed candidates by size and prefix hash"
+    );
+    out
+}
+
+fn hash_prefix(path: &Path) -> Result<String> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut buf = Vec::with_capacity(PREFIX_BYTES as usize);
+    file.take(PREFIX_BYTES)
+        .read_to_end(&mut buf)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(blake3::hash(&buf).to_hex().to_string())
+}
+
+fn hash_one(library: usize, path: &Path, algo: HashAlgo) -> Result<FileInfo> {
     let md = path
         .metadata()
         .with_context(|| format!("Failed to stat {}", path.display()))?;
@@ -234,7 +673,7 @@ fn hash_one(path: &Path) -> Result<FileInfo> {
     let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
     let mut reader = BufReader::with_capacity(1024 * 1024, file);
 
-    let mut hasher = Hasher::new();
+    let mut hasher = algo.hasher();
     let mut buf = vec![0u8; 1024 * 1024];
 
     loop {
@@ -247,31 +686,52 @@ fn hash_one(path: &Path) -> Result<FileInfo> {
         hasher.update(&buf[..n]);
     }
 
-    let digest = hasher.finalize();
-    let blake3_hex = digest.to_hex().to_string();
-
     Ok(FileInfo {
         path: path.to_path_buf(),
+        library,
         bytes,
-        blake3: blake3_hex,
+        digest: hasher.finish_hex(),
     })
 }
 
-fn find_duplicates(files: Vec<FileInfo>) -> Vec<DuplicateGroup> {
-    let mut map: HashMap<(u64, String), Vec<PathBuf>> = HashMap::new();
+fn find_duplicates(
+    files: Vec<FileInfo>,
+    libraries: &[PathBuf],
+    root_labels: &RootLabels,
+    settings: &DupsSettings,
+) -> Vec<DuplicateGroup> {
+    let mut map: HashMap<(u64, String), Vec<(PathBuf, usize)>> = HashMap::new();
 
     for f in files {
-        map.entry((f.bytes, f.blake3.clone()))
+        map.entry((f.bytes, f.digest.clone()))
             .or_default()
-            .push(f.path);
+            .push((f.path, f.library));
     }
 
     let mut groups: Vec<DuplicateGroup> = map
         .into_iter()
-        .filter_map(|((bytes, blake3), mut paths)| {
-            if paths.len() >= 2 {
-                paths.sort();
-                Some(DuplicateGroup { bytes, blake3, files: paths })
+        .filter_map(|((bytes, digest), mut entries)| {
+            if entries.len() >= 2 {
+                entries.sort();
+                let roots = if root_labels.roots.is_empty() {
+                    Vec::new()
+                } else {
+                    entries.iter().map(|(path, idx)| root_labels.label(path, *idx)).collect()
+                };
+                let (files, libs): (Vec<_>, Vec<_>) = entries
+                    .into_iter()
+                    .map(|(path, idx)| (path, libraries[idx].clone()))
+                    .unzip();
+                let non_utf8 = (0..files.len()).filter(|&i| files[i].to_str().is_none()).collect();
+                Some(DuplicateGroup {
+                    bytes,
+                    algo: settings.hash.name(),
+                    digest,
+                    files,
+                    libraries: libs,
+                    roots,
+                    non_utf8,
+                })
             } else {
                 None
             }
@@ -283,43 +743,122 @@ fn find_duplicates(files: Vec<FileInfo>) -> Vec<DuplicateGroup> {
             .len()
             .cmp(&a.files.len())
             .then_with(|| b.bytes.cmp(&a.bytes))
-            .then_with(|| a.blake3.cmp(&b.blake3))
+            .then_with(|| a.digest.cmp(&b.digest))
     });
 
     groups
 }
 
-fn print_text(groups: &[DuplicateGroup], out: Option<&Path>) -> Result<()> {
+/// Matches walked files to `--library-root`s on canonical paths, so a
+/// relative `--library` still lines up with an absolute root and vice versa.
+struct RootLabels {
+    /// (as given, canonical) per scanned library, by index
+    libraries: Vec<(PathBuf, PathBuf)>,
+    /// (as given, canonical) per root
+    roots: Vec<(PathBuf, PathBuf)>,
+}
+
+impl RootLabels {
+    fn new(libraries: &[PathBuf], roots: &[PathBuf]) -> RootLabels {
+        let with_canonical = |paths: &[PathBuf]| -> Vec<(PathBuf, PathBuf)> {
+            paths.iter().map(|p| (p.clone(), canonical(p))).collect()
+        };
+        let labels = RootLabels {
+            libraries: with_canonical(libraries),
+            roots: with_canonical(roots),
+        };
+        for (root, canon) in &labels.roots {
+            let inside = labels
+                .libraries
+                .iter()
+                .any(|(_, lib)| canon.starts_with(lib) || lib.starts_with(canon));
+            if !inside {
+                warn!(root = %root.display(), "[dups] --library-root is outside every library");
+            }
+        }
+        labels
+    }
+
+    /// The longest root containing `path` (walked from library `library`),
+    /// as the user gave it.
+    fn label(&self, path: &Path, library: usize) -> Option<String> {
+        let (given, canon) = &self.libraries[library];
+        let path = match path.strip_prefix(given) {
+            Ok(rest) => canon.join(rest),
+            Err(_) => canonical(path),
+        };
+        self.roots
+            .iter()
+            .filter(|(_, root)| path.starts_with(root))
+            .max_by_key(|(_, root)| root.components().count())
+            .map(|(given, _)| given.to_string_lossy().into_owned())
+    }
+}
+
+/// Falls back to the absolute path when the path can't be resolved (e.g. a
+/// root that does not exist).
+fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn print_text(
+    groups: &[DuplicateGroup],
+    show_library: bool,
+    algo: HashAlgo,
+    out: Option<&Path>,
+) -> Result<()> {
     let mut buf = String::new();
     if groups.is_empty() {
-        buf.push_str("No duplicates found (by full-file BLAKE3 hash).\n");
+        buf.push_str(&no_duplicates_line(algo));
     } else {
         buf.push_str(&format!("Duplicate groups: {}\n\n", groups.len()));
         for (i, g) in groups.iter().enumerate() {
-            buf.push_str(&format!(
-                "== Group {}: {} files | {} bytes | blake3 {} ==\n",
-                i + 1,
-                g.files.len(),
-                g.bytes,
-                g.blake3
-            ));
-            for p in &g.files {
-                buf.push_str(&format!("  - {}\n", p.display()));
-            }
-            buf.push('\n');
+            push_group(&mut buf, i + 1, g, show_library);
         }
     }
     write_output(&buf, out)?;
     Ok(())
 }
 
+fn no_duplicates_line(algo: HashAlgo) -> String {
+    format!("No duplicates found (by full-file {} hash).\n", algo.name().to_uppercase())
+}
+
+fn push_group(buf: &mut String, number: usize, g: &DuplicateGroup, show_library: bool) {
+    buf.push_str(&format!(
+        "== Group {}: {} files | {} bytes | {} {} ==\n",
+        number,
+        g.files.len(),
+        g.bytes,
+        g.algo,
+        g.digest
+    ));
+    for (i, (p, lib)) in g.files.iter().zip(&g.libraries).enumerate() {
+        buf.push_str("  - ");
+        if show_library {
+            buf.push_str(&format!("[{}] ", lib.display()));
+        }
+        if let Some(root) = g.roots.get(i) {
+            buf.push_str(&format!("(root {}) ", root.as_deref().unwrap_or("-")));
+        }
+        buf.push_str(&p.display().to_string());
+        if g.non_utf8.contains(&i) {
+            buf.push_str("  (not valid UTF-8; shown lossily)");
+        }
+        buf.push('\n');
+    }
+    buf.push('\n');
+}
+
 fn print_json(groups: &[DuplicateGroup], out: Option<&Path>) -> Result<()> {
     let s = serde_json::to_string_pretty(groups)?;
     write_output(&s, out)?;
     Ok(())
 }
 
-fn write_output(contents: &str, out: Option<&Path>) -> Result<()> {
+pub fn write_output(contents: &str, out: Option<&Path>) -> Result<()> {
     if let Some(path) = out {
         let mut file = std::fs::File::create(path)
             .with_context(|| format!("Failed to create {}", path.display()))?;
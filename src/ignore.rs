@@ -0,0 +1,260 @@
+use anyhow::{Context, Result};
+use std::path::{Component, Path};
+
+pub const IGNORE_FILE_NAME: &str = ".calibreupdatrignore";
+
+/// Exclusions read from `.calibreupdatrignore` at the library root.
+///
+/// One pattern per line, gitignore-style: `#` starts a comment, `!` negates,
+/// and the last matching line wins. `id:N` matches a book id; anything else
+/// is a glob (`*`, `?`, `[a-z]`, `**`) matched against the book's folder
+/// relative to the library root (`Author/Title (12)`) and its parents. A
+/// pattern containing `/` is anchored at the root, one without matches any
+/// single folder name, and a trailing `/` is accepted and ignored.
+#[derive(Debug, Default)]
+pub struct IgnoreList {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    negate: bool,
+    pattern: Pattern,
+}
+
+#[derive(Debug)]
+enum Pattern {
+    Id(i64),
+    Glob { glob: String, anchored: bool },
+}
+
+impl IgnoreList {
+    /// `None` when the library has no ignore file.
+    pub fn load(library_root: &Path) -> Result<Option<IgnoreList>> {
+        let path = library_root.join(IGNORE_FILE_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(Some(IgnoreList::parse(&contents)))
+    }
+
+    pub fn parse(contents: &str) -> IgnoreList {
+        let rules = contents
+            .lines()
+            .filter_map(|line| {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    return None;
+                }
+                let (negate, line) = match line.strip_prefix('!') {
+                    Some(rest) => (true, rest.trim()),
+                    None => (false, line.strip_prefix('\\').unwrap_or(line)),
+                };
+                let pattern = match line.strip_prefix("id:").map(|n| n.trim().parse()) {
+                    Some(Ok(id)) => Pattern::Id(id),
+                    _ => {
+                        let glob = line.trim_end_matches('/');
+                        let anchored = glob.contains('/');
+                        Pattern::Glob {
+                            glob: glob.trim_start_matches('/').to_string(),
+                            anchored,
+                        }
+                    }
+                };
+                Some(Rule { negate, pattern })
+            })
+            .collect();
+        IgnoreList { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// `book_dir` is relative to the library root; `None` if unknown (no
+    /// format files), in which case only `id:` lines can match.
+    pub fn is_ignored(&self, book_id: i64, book_dir: Option<&Path>) -> bool {
+        let parts: Vec<&str> = book_dir
+            .map(|dir| {
+                dir.components()
+                    .filter_map(|c| match c {
+                        Component::Normal(s) => s.to_str(),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut ignored = false;
+        for rule in &self.rules {
+            let matched = match &rule.pattern {
+                Pattern::Id(id) => *id == book_id,
+                Pattern::Glob { glob, anchored: true } => {
+                    (1..=parts.len()).any(|n| glob_match(glob, &parts[..n].join("/")))
+                }
+                Pattern::Glob { glob, anchored: false } => {
+                    parts.iter().any(|part| glob_match(glob, part))
+                }
+            };
+            if matched {
+                ignored = !rule.negate;
+            }
+        }
+        ignored
+    }
+}
+
+/// Glob match where `*` and `?` stop at `/` and `**` crosses it.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    match_from(&p, &t)
+}
+
+fn match_from(p: &[char], t: &[char]) -> bool {
+    let Some(&first) = p.first() else {
+        return t.is_empty();
+    };
+    match first {
+        '*' if p.get(1) == Some(&'*') => {
+            let mut rest = &p[2..];
+            // `a/**/b` also matches `a/b`
+            if let Some(stripped) = rest.strip_prefix(&['/']) {
+                if match_from(stripped, t) {
+                    return true;
+                }
+                rest = stripped;
+            }
+            (0..=t.len()).any(|i| match_from(rest, &t[i..]))
+        }
+        '*' => {
+            let limit = t.iter().position(|c| *c == '/').unwrap_or(t.len());
+            (0..=limit).any(|i| match_from(&p[1..], &t[i..]))
+        }
+        '?' => t.first().is_some_and(|c| *c != '/') && match_from(&p[1..], &t[1..]),
+        '[' => match class_match(&p[1..], t.first().copied()) {
+            Some((hit, len)) => hit && match_from(&p[1 + len..], &t[1..]),
+            // No closing `]`: a literal bracket
+            None => t.first() == Some(&'[') && match_from(&p[1..], &t[1..]),
+        },
+        '\\' if p.len() > 1 => t.first() == Some(&p[1]) && match_from(&p[2..], &t[1..]),
+        c => t.first() == Some(&c) && match_from(&p[1..], &t[1..]),
+    }
+}
+
+/// Matches `c` against the class body after `[`; returns whether it matched
+/// and how many pattern chars the class used (through `]`).
+fn class_match(p: &[char], c: Option<char>) -> Option<(bool, usize)> {
+    let negated = matches!(p.first(), Some('!') | Some('^'));
+    let mut i = usize::from(negated);
+    let mut hit = false;
+    let mut first = true;
+    while i < p.len() {
+        if p[i] == ']' && !first {
+            let hit = c.is_some_and(|c| c != '/') && hit != negated;
+            return Some((hit, i + 1));
+        }
+        first = false;
+        if i + 2 < p.len() && p[i + 1] == '-' && p[i + 2] != ']' {
+            hit |= c.is_some_and(|c| (p[i]..=p[i + 2]).contains(&c));
+            i += 3;
+        } else {
+            hit |= c == Some(p[i]);
+            i += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignored(list: &IgnoreList, id: i64, dir: &str) -> bool {
+        list.is_ignored(id, Some(Path::new(dir)))
+    }
+
+    #[test]
+    fn parse_skips_comments_and_blank_lines() {
+        let list = IgnoreList::parse("# comment\n\n   \nid:7\n  Samples/  \n");
+        assert_eq!(list.rules.len(), 2);
+        assert!(IgnoreList::parse("# only a comment\n").is_empty());
+    }
+
+    #[test]
+    fn id_lines_match_without_a_folder() {
+        let list = IgnoreList::parse("id:12\nid: 13\n");
+        assert!(list.is_ignored(12, None));
+        assert!(list.is_ignored(13, None));
+        assert!(!list.is_ignored(14, None));
+        assert!(ignored(&list, 12, "Author/Title (12)"));
+    }
+
+    #[test]
+    fn last_matching_line_wins() {
+        let list = IgnoreList::parse("Jane Doe\n!Jane Doe/Keep (3)\n");
+        assert!(ignored(&list, 2, "Jane Doe/Drop (2)"));
+        assert!(!ignored(&list, 3, "Jane Doe/Keep (3)"));
+
+        let list = IgnoreList::parse("!Jane Doe/Keep (3)\nJane Doe\n");
+        assert!(ignored(&list, 3, "Jane Doe/Keep (3)"));
+
+        let list = IgnoreList::parse("*\n!id:5\n");
+        assert!(ignored(&list, 4, "A/B (4)"));
+        assert!(!ignored(&list, 5, "A/B (5)"));
+    }
+
+    #[test]
+    fn unanchored_patterns_match_any_folder_name() {
+        let list = IgnoreList::parse("*(1?)\n");
+        assert!(ignored(&list, 12, "Author/Title (12)"));
+        assert!(!ignored(&list, 2, "Author/Title (2)"));
+        // `*` never spans a `/`
+        let list = IgnoreList::parse("Author*Title (12)\n");
+        assert!(!ignored(&list, 12, "Author/Title (12)"));
+    }
+
+    #[test]
+    fn anchored_patterns_match_from_the_root() {
+        let list = IgnoreList::parse("/Jane Doe/\n");
+        assert!(ignored(&list, 1, "Jane Doe/Title (1)"));
+        assert!(!ignored(&list, 1, "Other/Jane Doe (1)"));
+
+        let list = IgnoreList::parse("Jane*/Draft*\n");
+        assert!(ignored(&list, 1, "Jane Doe/Draft two (1)"));
+        assert!(!ignored(&list, 1, "Jane Doe/Final (1)"));
+        assert!(!ignored(&list, 1, "X/Jane Doe/Draft (1)"));
+    }
+
+    #[test]
+    fn double_star_crosses_folders() {
+        assert!(glob_match("**/Title (1)", "Author/Title (1)"));
+        assert!(glob_match("**/Title (1)", "Title (1)"));
+        assert!(glob_match("A/**/b", "A/x/y/b"));
+        assert!(glob_match("A/**/b", "A/b"));
+        assert!(glob_match("A/**", "A/x/y"));
+        assert!(!glob_match("A/*", "A/x/y"));
+        let list = IgnoreList::parse("Series/**/Vol*\n");
+        assert!(ignored(&list, 1, "Series/Sub/Vol 1 (1)"));
+        assert!(!ignored(&list, 1, "Other/Sub/Vol 1 (1)"));
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        assert!(glob_match("[a-c]x", "bx"));
+        assert!(!glob_match("[!a-c]x", "bx"));
+        assert!(glob_match("[]]", "]"));
+        assert!(glob_match("a[b", "a[b"));
+        assert!(glob_match("\\*", "*"));
+        assert!(!glob_match("\\*", "x"));
+        assert!(glob_match("what\\?", "what?"));
+        assert!(!glob_match("what\\?", "whats"));
+
+        // A leading `\` keeps `#` and `!` literal
+        let list = IgnoreList::parse("\\#drafts\n\\!important\n");
+        assert!(ignored(&list, 1, "#drafts"));
+        assert!(ignored(&list, 2, "Author/!important"));
+        assert!(!ignored(&list, 3, "Author/important"));
+    }
+}
@@ -1,11 +1,20 @@
 mod app;
+mod cache;
 mod calibre;
 mod config;
+mod diff;
 mod dups;
+mod ignore;
+mod merge;
 mod metadata;
+mod opf;
+mod report;
+mod restore;
 mod runner;
 mod state;
+mod validate;
+mod verify;
 
-fn main() -> anyhow::Result<()> {
-    app::run()
+fn main() -> anyhow::Result<std::process::ExitCode> {
+    Ok(app::run()?.exit_code())
 }
@@ -0,0 +1,271 @@
+use crate::calibre::{add_format_to_book, list_all_books, remove_book};
+use crate::config::ScoringConfig;
+use crate::dups::write_output;
+use crate::metadata::{
+    format_files, metadata_snapshot, normalize_formats, normalized_isbn, score_good_enough,
+};
+use crate::runner::Runner;
+use anyhow::Result;
+use clap::Parser;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+#[derive(Parser, Debug)]
+pub struct MergeDuplicatesArgs {
+    /// Merge and remove books (otherwise only print the planned merges)
+    #[arg(long, default_value_t = false)]
+    pub yes: bool,
+}
+
+/// One book of a duplicate group as the merge sees it.
+struct Member {
+    id: i64,
+    title: String,
+    formats: Vec<String>,
+    files: Vec<(String, PathBuf)>,
+    score: i32,
+}
+
+impl Member {
+    fn new(book: &Value, scoring: &ScoringConfig) -> Member {
+        let listed = book.get("formats").unwrap_or(&Value::Null);
+        Member {
+            id: book.get("id").and_then(|v| v.as_i64()).unwrap_or_default(),
+            title: book.get("title").and_then(|v| v.as_str()).unwrap_or("").trim().to_string(),
+            formats: normalize_formats(listed),
+            files: format_files(listed),
+            score: score_good_enough(&metadata_snapshot(book), scoring).0,
+        }
+    }
+
+    /// Format files whose format is not in `have` yet.
+    fn extra_files(&self, have: &[String]) -> Vec<&(String, PathBuf)> {
+        self.files.iter().filter(|(fmt, _)| !have.contains(fmt)).collect()
+    }
+}
+
+/// Merges library books that share a normalized ISBN. The survivor of each
+/// group has the most formats, then the highest score, then the lowest id;
+/// the others' missing formats are added to it and they are then removed
+/// (into Calibre's trash). The survivor's metadata is kept as it is.
+/// `false` if any merge failed.
+pub fn run_merge_duplicates(
+    runner: &Runner,
+    lib: &str,
+    is_remote: bool,
+    scoring: &ScoringConfig,
+    args: &MergeDuplicatesArgs,
+) -> Result<bool> {
+    if is_remote {
+        // add_format needs the format files, which only a local library has
+        anyhow::bail!("merge-duplicates needs a local library path, not a Content Server URL");
+    }
+    let mut groups: BTreeMap<String, Vec<Member>> = BTreeMap::new();
+    for book in list_all_books(runner, lib)? {
+        if let Some(isbn) = normalized_isbn(&book) {
+            groups.entry(isbn).or_default().push(Member::new(&book, scoring));
+        }
+    }
+    groups.retain(|_, members| members.len() > 1);
+
+    let mut out = String::new();
+    let mut all_ok = true;
+    for (isbn, members) in groups {
+        all_ok &= merge_group(&isbn, members, args.yes, &mut out, |survivor, dup, extra| {
+            merge_into(runner, lib, survivor, dup, extra)
+        });
+    }
+    if out.is_empty() {
+        out.push_str("no books share an ISBN");
+    } else if !args.yes {
+        out.push_str("pass --yes to merge");
+    }
+    write_output(out.trim_end(), None)?;
+    Ok(all_ok)
+}
+
+/// Survivor first: most formats, then the highest score, then the lowest id.
+fn survivor_first(members: &mut [Member]) {
+    members.sort_by_key(|m| (std::cmp::Reverse((m.formats.len(), m.score)), m.id));
+}
+
+/// Plans one group into `out` and, with `yes`, runs `merge` for each
+/// duplicate. `false` if any merge failed.
+fn merge_group(
+    isbn: &str,
+    mut members: Vec<Member>,
+    yes: bool,
+    out: &mut String,
+    mut merge: impl FnMut(&Member, i64, &[&(String, PathBuf)]) -> Result<(), String>,
+) -> bool {
+    survivor_first(&mut members);
+    let survivor = &members[0];
+    out.push_str(&format!(
+        "isbn {isbn}: keep {} \"{}\" ({} formats, score {})\n",
+        survivor.id,
+        survivor.title,
+        survivor.formats.len(),
+        survivor.score
+    ));
+    let mut all_ok = true;
+    // Two duplicates with the same extra format add it only once; a format
+    // counts once an add succeeded (or, in the plan, would be attempted)
+    let mut have = survivor.formats.clone();
+    for dup in &members[1..] {
+        let extra = dup.extra_files(&have);
+        let adds = extra.iter().map(|(fmt, _)| fmt.as_str()).collect::<Vec<_>>().join(",");
+        let adds = if adds.is_empty() { "nothing".to_string() } else { adds };
+        out.push_str(&format!("  merge {} \"{}\" (adds {adds})", dup.id, dup.title));
+        if !yes {
+            have.extend(extra.iter().map(|(fmt, _)| fmt.clone()));
+            out.push('\n');
+            continue;
+        }
+        match merge(survivor, dup.id, &extra) {
+            Ok(()) => {
+                have.extend(extra.iter().map(|(fmt, _)| fmt.clone()));
+                info!(
+                    isbn = %isbn,
+                    survivor = survivor.id,
+                    merged = dup.id,
+                    adds = %adds,
+                    "[merge] merged duplicate"
+                );
+                out.push_str(": done\n");
+            }
+            Err(msg) => {
+                warn!(
+                    isbn = %isbn,
+                    survivor = survivor.id,
+                    merged = dup.id,
+                    error = %msg,
+                    "[merge] failed"
+                );
+                out.push_str(&format!(": failed ({msg})\n"));
+                all_ok = false;
+            }
+        }
+    }
+    all_ok
+}
+
+/// Adds `extra` to the survivor, then removes `dup`; a failed add leaves
+/// `dup` in place.
+fn merge_into(
+    runner: &Runner,
+    lib: &str,
+    survivor: &Member,
+    dup: i64,
+    extra: &[&(String, PathBuf)],
+) -> Result<(), String> {
+    for (fmt, path) in extra {
+        let (ok, msg) = add_format_to_book(runner, lib, survivor.id, path)
+            .map_err(|e| format!("{e:#}"))?;
+        if !ok {
+            return Err(format!("adding {fmt}: {msg}"));
+        }
+    }
+    let (ok, msg) = remove_book(runner, lib, dup).map_err(|e| format!("{e:#}"))?;
+    if ok { Ok(()) } else { Err(msg) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(id: i64, formats: &[&str], score: i32) -> Member {
+        Member {
+            id,
+            title: format!("Book {id}"),
+            formats: formats.iter().map(|f| f.to_string()).collect(),
+            files: formats
+                .iter()
+                .map(|f| (f.to_string(), PathBuf::from(format!("/lib/{id}/book.{f}"))))
+                .collect(),
+            score,
+        }
+    }
+
+    fn ids(members: &[Member]) -> Vec<i64> {
+        members.iter().map(|m| m.id).collect()
+    }
+
+    #[test]
+    fn survivor_has_most_formats_then_highest_score_then_lowest_id() {
+        let mut members = vec![
+            member(5, &["epub"], 9),
+            member(4, &["epub", "pdf"], 1),
+            member(3, &["epub"], 9),
+            member(2, &["epub"], 2),
+        ];
+        survivor_first(&mut members);
+        assert_eq!(ids(&members), [4, 3, 5, 2]);
+    }
+
+    #[test]
+    fn extra_files_skips_formats_already_present() {
+        let dup = member(2, &["epub", "pdf", "mobi"], 0);
+        let have = vec!["epub".to_string(), "mobi".to_string()];
+        let extra: Vec<&str> = dup.extra_files(&have).iter().map(|(f, _)| f.as_str()).collect();
+        assert_eq!(extra, ["pdf"]);
+    }
+
+    #[test]
+    fn a_format_is_added_once_across_duplicates() {
+        let members = vec![
+            member(1, &["epub", "azw3"], 5),
+            member(2, &["epub", "pdf"], 5),
+            member(3, &["pdf"], 1),
+        ];
+        let mut calls = Vec::new();
+        let mut out = String::new();
+        let ok = merge_group("9780306406157", members, true, &mut out, |survivor, dup, extra| {
+            calls.push((survivor.id, dup, extra.iter().map(|(f, _)| f.clone()).collect()));
+            Ok(())
+        });
+        assert!(ok);
+        let expected: Vec<(i64, i64, Vec<String>)> =
+            vec![(1, 2, vec!["pdf".to_string()]), (1, 3, vec![])];
+        assert_eq!(calls, expected);
+    }
+
+    #[test]
+    fn a_failed_add_leaves_the_format_for_the_next_duplicate() {
+        let members = vec![
+            member(1, &["epub", "azw3"], 5),
+            member(2, &["epub", "pdf"], 5),
+            member(3, &["pdf"], 1),
+        ];
+        let mut calls = Vec::new();
+        let mut out = String::new();
+        let ok = merge_group("9780306406157", members, true, &mut out, |_, dup, extra| {
+            calls.push((dup, extra.iter().map(|(f, _)| f.clone()).collect::<Vec<_>>()));
+            if dup == 2 { Err("adding pdf: disk full".to_string()) } else { Ok(()) }
+        });
+        assert!(!ok);
+        // Book 3 still carries the pdf book 2 failed to add, so it is not lost
+        assert_eq!(calls, [(2, vec!["pdf".to_string()]), (3, vec!["pdf".to_string()])]);
+        assert!(out.contains("merge 2 \"Book 2\" (adds pdf): failed (adding pdf: disk full)"));
+        assert!(out.contains("merge 3 \"Book 3\" (adds pdf): done"));
+    }
+
+    #[test]
+    fn plan_without_yes_merges_nothing() {
+        let members = vec![member(1, &["epub"], 5), member(2, &["epub", "pdf"], 5)];
+        let mut out = String::new();
+        let ok = merge_group("9780306406157", members, false, &mut out, |_, _, _| {
+            panic!("merged without --yes")
+        });
+        assert!(ok);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "isbn 9780306406157: keep 2 \"Book 2\" (2 formats, score 5)",
+                "  merge 1 \"Book 1\" (adds nothing)",
+            ]
+        );
+    }
+}
@@ -3,6 +3,7 @@ use serde::Serialize;
 use serde_json::Value;
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Serialize)]
 pub struct Snapshot {
@@ -15,7 +16,19 @@ pub struct Snapshot {
     pub identifiers: HashMap<String, String>,
     pub tags: Vec<String>,
     pub comments_present: bool,
+    /// Visible length of the comments (markup stripped); `None` from a
+    /// presence-only listing. Not hashed, so the change hash stays as it was.
+    #[serde(skip)]
+    pub comments_chars: Option<usize>,
     pub cover_present: bool,
+    // Skipped when absent so books without a series keep their existing hash
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub series: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub series_index: Option<f64>,
+    /// Non-empty custom column values keyed by `#name`
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub custom: HashMap<String, String>,
 }
 
 fn sort_value(value: &Value) -> Value {
@@ -61,6 +74,68 @@ fn normalize_identifiers(val: &Value) -> HashMap<String, String> {
     out
 }
 
+/// The book's ISBN (the `isbn` field, else the `isbn` identifier) without
+/// dashes or spaces and with an uppercase check digit `X`.
+pub fn normalized_isbn(book: &Value) -> Option<String> {
+    let identifiers = normalize_identifiers(book.get("identifiers").unwrap_or(&Value::Null));
+    let isbn = book
+        .get("isbn")
+        .and_then(|v| v.as_str())
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .or(identifiers.get("isbn").map(String::as_str))?;
+    let isbn = normalize_isbn(isbn);
+    (!isbn.is_empty()).then_some(isbn)
+}
+
+/// `raw` without dashes or spaces, with an uppercase check digit `X`.
+fn normalize_isbn(raw: &str) -> String {
+    raw.chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase()
+}
+
+/// An ISBN-10 or ISBN-13 whose check digit is right, once normalized.
+pub fn is_valid_isbn(raw: &str) -> bool {
+    let isbn = normalize_isbn(raw);
+    let digit = |c: char, i: usize| match c {
+        'X' if isbn.len() == 10 && i == 9 => Some(10),
+        c => c.to_digit(10),
+    };
+    let Some(digits) = isbn
+        .chars()
+        .enumerate()
+        .map(|(i, c)| digit(c, i))
+        .collect::<Option<Vec<u32>>>()
+    else {
+        return false;
+    };
+    match digits.len() {
+        10 => digits.iter().zip((1..=10).rev()).map(|(d, w)| d * w).sum::<u32>() % 11 == 0,
+        13 => {
+            let weights = [1, 3].into_iter().cycle();
+            digits.iter().zip(weights).map(|(d, w)| d * w).sum::<u32>() % 10 == 0
+        }
+        _ => false,
+    }
+}
+
+/// Key that survives Calibre renumbering a book: its ISBN, else a hash of
+/// all its identifiers. `None` when the book has neither.
+pub fn identity_key(book: &Value) -> Option<String> {
+    if let Some(isbn) = normalized_isbn(book) {
+        return Some(format!("isbn:{isbn}"));
+    }
+    let identifiers = normalize_identifiers(book.get("identifiers").unwrap_or(&Value::Null));
+    if identifiers.is_empty() {
+        return None;
+    }
+    let mut pairs: Vec<String> = identifiers.iter().map(|(k, v)| format!("{k}:{v}")).collect();
+    pairs.sort();
+    Some(format!("ids:{}", sha256_text(&pairs.join(","))))
+}
+
 fn normalize_languages(val: &Value) -> Vec<String> {
     match val {
         Value::Null => vec![],
@@ -80,25 +155,59 @@ fn normalize_languages(val: &Value) -> Vec<String> {
     }
 }
 
-fn normalize_formats(val: &Value) -> Vec<String> {
+/// Local libraries list format file paths; reduce those to their extension.
+fn format_name(entry: &str) -> String {
+    if entry.contains('/') || entry.contains('\\') {
+        return Path::new(entry)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+    }
+    entry.to_lowercase()
+}
+
+pub fn normalize_formats(val: &Value) -> Vec<String> {
     match val {
         Value::Null => vec![],
         Value::Array(arr) => arr
             .iter()
-            .filter_map(|v| v.as_str().map(|s| s.trim().to_lowercase()))
+            .filter_map(|v| v.as_str().map(|s| format_name(s.trim())))
             .filter(|s| !s.is_empty())
             .collect(),
         _ => {
-            let s = val.as_str().unwrap_or(&val.to_string()).to_lowercase();
+            let s = val.as_str().unwrap_or(&val.to_string()).to_string();
             s.replace(';', ",")
                 .split(',')
-                .map(|x| x.trim().to_string())
+                .map(|x| format_name(x.trim()))
                 .filter(|x| !x.is_empty())
                 .collect()
         }
     }
 }
 
+/// Format file paths as reported by `calibredb list` for local libraries,
+/// paired with the lowercased format (file extension).
+pub fn format_files(val: &Value) -> Vec<(String, PathBuf)> {
+    let entries: Vec<String> = match val {
+        Value::Array(arr) => arr
+            .iter()
+            .filter_map(|v| v.as_str().map(|s| s.trim().to_string()))
+            .collect(),
+        Value::String(s) => s.split(',').map(|x| x.trim().to_string()).collect(),
+        _ => vec![],
+    };
+    entries
+        .into_iter()
+        .filter(|e| e.contains('/') || e.contains('\\'))
+        .filter_map(|e| {
+            let path = PathBuf::from(e);
+            let fmt = path.extension()?.to_str()?.to_lowercase();
+            Some((fmt, path))
+        })
+        .collect()
+}
+
 pub fn has_any_format(formats_val: &Value, targets: &std::collections::BTreeMap<String, ()>) -> bool {
     let fmts = normalize_formats(formats_val);
     if fmts.is_empty() {
@@ -130,6 +239,34 @@ pub fn is_english_or_missing(
     false
 }
 
+/// Custom columns come back keyed `*name` or `#name` depending on the
+/// calibredb version; only columns that were requested are present.
+fn custom_columns(book: &Value) -> HashMap<String, String> {
+    let mut out = HashMap::new();
+    let Some(map) = book.as_object() else {
+        return out;
+    };
+    for (k, v) in map {
+        let Some(name) = k.strip_prefix('*').or_else(|| k.strip_prefix('#')) else {
+            continue;
+        };
+        let text = match v {
+            Value::Null => String::new(),
+            Value::String(s) => s.trim().to_string(),
+            Value::Array(arr) => arr
+                .iter()
+                .map(|x| x.as_str().map(str::to_string).unwrap_or_else(|| x.to_string()))
+                .collect::<Vec<_>>()
+                .join(", "),
+            _ => v.to_string(),
+        };
+        if !text.is_empty() {
+            out.insert(format!("#{}", name.to_lowercase()), text);
+        }
+    }
+    out
+}
+
 pub fn metadata_snapshot(book: &Value) -> Snapshot {
     let identifiers = normalize_identifiers(book.get("identifiers").unwrap_or(&Value::Null));
     let langs = normalize_languages(book.get("languages").unwrap_or(&Value::Null));
@@ -173,6 +310,19 @@ pub fn metadata_snapshot(book: &Value) -> Snapshot {
         }
     };
 
+    let series = book
+        .get("series")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .trim()
+        .to_string();
+    // calibredb reports an index even for books without a series
+    let series_index = if series.is_empty() {
+        None
+    } else {
+        book.get("series_index").and_then(|v| v.as_f64())
+    };
+
     Snapshot {
         title: book
             .get("title")
@@ -202,82 +352,285 @@ pub fn metadata_snapshot(book: &Value) -> Snapshot {
             .to_string(),
         identifiers,
         tags,
-        comments_present: book
-            .get("comments")
-            .and_then(|v| v.as_str())
-            .map(|s| !s.trim().is_empty())
-            .unwrap_or(false),
+        // A bool here comes from a presence-only listing
+        comments_present: match book.get("comments") {
+            Some(Value::String(s)) => !s.trim().is_empty(),
+            Some(Value::Bool(b)) => *b,
+            _ => false,
+        },
+        comments_chars: match book.get("comments") {
+            Some(Value::String(s)) => Some(comment_chars(s)),
+            Some(Value::Bool(_)) => None,
+            _ => Some(0),
+        },
         cover_present: book.get("cover").is_some() && !book.get("cover").unwrap().is_null(),
+        series,
+        series_index,
+        custom: custom_columns(book),
     }
 }
 
+/// Snapshot of a fetched OPF, for scoring it like a library book. Custom
+/// columns never come from an OPF.
+pub fn opf_snapshot(opf: &crate::opf::OpfMetadata, cover_present: bool) -> Snapshot {
+    let identifiers: HashMap<String, String> = opf
+        .identifiers
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    let isbn = identifiers.get("isbn").cloned().unwrap_or_default();
+    let series_index = if opf.series.is_empty() {
+        None
+    } else {
+        opf.series_index.parse().ok()
+    };
+    Snapshot {
+        title: opf.title.clone(),
+        authors: opf.authors.clone(),
+        publisher: opf.publisher.clone(),
+        pubdate: opf.pubdate.clone(),
+        languages: opf.languages.clone(),
+        isbn,
+        identifiers,
+        tags: opf.tags.clone(),
+        comments_present: !opf.comments.is_empty(),
+        comments_chars: Some(comment_chars(&opf.comments)),
+        cover_present,
+        series: opf.series.clone(),
+        series_index,
+        custom: HashMap::new(),
+    }
+}
+
+/// Characters of a (usually HTML) comment outside its tags, trimmed.
+fn comment_chars(comments: &str) -> usize {
+    let mut in_tag = false;
+    let text: String = comments
+        .chars()
+        .filter(|c| match c {
+            '<' => {
+                in_tag = true;
+                false
+            }
+            '>' if in_tag => {
+                in_tag = false;
+                false
+            }
+            _ => !in_tag,
+        })
+        .collect();
+    text.trim().chars().count()
+}
+
+/// Fields `fetched` has a value for that differs from `current`.
+pub fn changed_fields(current: &Snapshot, fetched: &Snapshot) -> Vec<&'static str> {
+    let mut out = Vec::new();
+    let mut check = |field, has_value: bool, differs: bool| {
+        if has_value && differs {
+            out.push(field);
+        }
+    };
+    check("title", !fetched.title.is_empty(), fetched.title != current.title);
+    check("authors", !fetched.authors.is_empty(), fetched.authors != current.authors);
+    check("publisher", !fetched.publisher.is_empty(), fetched.publisher != current.publisher);
+    check("pubdate", !fetched.pubdate.is_empty(), fetched.pubdate != current.pubdate);
+    check("isbn", !fetched.isbn.is_empty(), fetched.isbn != current.isbn);
+    check(
+        "identifiers",
+        !fetched.identifiers.is_empty(),
+        fetched.identifiers != current.identifiers,
+    );
+    check("tags", !fetched.tags.is_empty(), fetched.tags != current.tags);
+    check("comments", fetched.comments_present, !current.comments_present);
+    check("cover", fetched.cover_present, !current.cover_present);
+    check("series", !fetched.series.is_empty(), fetched.series != current.series);
+    out
+}
+
 pub fn snapshot_hash(snap: &Snapshot) -> Result<String> {
     let value = serde_json::to_value(snap)?;
     let stable = stable_json_string(&value)?;
     Ok(sha256_text(&stable))
 }
 
+/// One scored field: the points it earned (0 if missing) and, when missing,
+/// the reason reported for it.
+#[derive(Debug, Clone)]
+pub struct ScorePart {
+    pub field: String,
+    pub points: i32,
+    pub missing: Option<String>,
+}
+
+/// Per-field breakdown behind `score_good_enough`, in a fixed order for a
+/// given scoring config.
+pub fn score_parts(snap: &Snapshot, scoring: &crate::config::ScoringConfig) -> Vec<ScorePart> {
+    let part = |field: &str, present: bool, weight: i32, reason: &str| ScorePart {
+        field: field.to_string(),
+        points: if present { weight } else { 0 },
+        missing: (!present).then(|| reason.to_string()),
+    };
+    let mut parts = vec![
+        part("title", !snap.title.is_empty(), scoring.title_weight, "missing title"),
+        part("authors", !snap.authors.is_empty(), scoring.authors_weight, "missing authors"),
+        part("publisher", !snap.publisher.is_empty(), scoring.publisher_weight, "missing publisher"),
+        part("pubdate", !snap.pubdate.is_empty(), scoring.pubdate_weight, "missing pubdate"),
+        ScorePart {
+            field: "isbn/identifiers".to_string(),
+            points: if !snap.isbn.is_empty() {
+                scoring.isbn_weight
+            } else if !snap.identifiers.is_empty() {
+                scoring.identifiers_weight
+            } else {
+                0
+            },
+            missing: (snap.isbn.is_empty() && snap.identifiers.is_empty())
+                .then(|| "missing identifiers/isbn".to_string()),
+        },
+        part("tags", !snap.tags.is_empty(), scoring.tags_weight, "missing tags"),
+        comments_part(snap, scoring),
+        part("cover", snap.cover_present, scoring.cover_weight, "missing cover"),
+    ];
+    if scoring.series_weight != 0 {
+        parts.push(part("series", !snap.series.is_empty(), scoring.series_weight, "missing series"));
+    }
+    for (column, weight) in &scoring.custom_columns {
+        let present = snap.custom.contains_key(column);
+        parts.push(part(column, present, *weight, &format!("missing {column}")));
+    }
+    parts
+}
+
+/// Comments shorter than `scoring.min_comment_chars` score as missing; a
+/// presence-only listing has no length and always passes.
+fn comments_part(snap: &Snapshot, scoring: &crate::config::ScoringConfig) -> ScorePart {
+    let min = scoring.min_comment_chars;
+    let too_short = snap.comments_chars.is_some_and(|n| n < min);
+    let present = snap.comments_present && !too_short;
+    ScorePart {
+        field: "comments".to_string(),
+        points: if present { scoring.comments_weight } else { 0 },
+        missing: (!present).then(|| {
+            if snap.comments_present {
+                format!("description shorter than {min} chars")
+            } else {
+                "missing description/comments".to_string()
+            }
+        }),
+    }
+}
+
 pub fn score_good_enough(
     snap: &Snapshot,
     scoring: &crate::config::ScoringConfig,
 ) -> (i32, Vec<String>) {
-    let mut score = 0;
-    let mut reasons = Vec::new();
+    let parts = score_parts(snap, scoring);
+    let score = parts.iter().map(|p| p.points).sum();
+    let reasons = parts.into_iter().filter_map(|p| p.missing).collect();
+    (score, reasons)
+}
 
-    if !snap.title.is_empty() {
-        score += scoring.title_weight;
-    } else {
-        reasons.push("missing title".to_string());
-    }
-    if !snap.authors.is_empty() {
-        score += scoring.authors_weight;
-    } else {
-        reasons.push("missing authors".to_string());
-    }
-    if !snap.publisher.is_empty() {
-        score += scoring.publisher_weight;
-    } else {
-        reasons.push("missing publisher".to_string());
+/// Whether a book with this score is embedded as-is instead of fetched.
+pub fn is_good_enough(score: i32, snap: &Snapshot, scoring: &crate::config::ScoringConfig) -> bool {
+    let has_isbn = || {
+        [Some(&snap.isbn), snap.identifiers.get("isbn")]
+            .into_iter()
+            .flatten()
+            .any(|isbn| is_valid_isbn(isbn))
+    };
+    score >= scoring.min_score_to_skip_fetch
+        && (!scoring.require_title || !snap.title.is_empty())
+        && (!scoring.require_authors || !snap.authors.is_empty())
+        && (!scoring.require_isbn_for_good_enough || has_isbn())
+}
+
+pub fn normalize_languages_for_filter(val: &Value) -> Vec<String> {
+    normalize_languages(val)
+}
+
+pub fn normalize_identifiers_for_fetch(val: &Value) -> HashMap<String, String> {
+    normalize_identifiers(val)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ScoringConfig;
+    use serde_json::json;
+
+    fn book(comments: Value) -> Value {
+        json!({
+            "id": 1,
+            "title": "Title",
+            "authors": "Author",
+            "publisher": "Publisher",
+            "pubdate": "2020-01-01T00:00:00+00:00",
+            "isbn": "9780306406157",
+            "tags": "Fiction",
+            "cover": "/covers/1.jpg",
+            "comments": comments,
+        })
     }
-    if !snap.pubdate.is_empty() {
-        score += scoring.pubdate_weight;
-    } else {
-        reasons.push("missing pubdate".to_string());
+
+    fn scoring(min_comment_chars: usize) -> ScoringConfig {
+        ScoringConfig { min_comment_chars, ..ScoringConfig::default() }
     }
 
-    if !snap.isbn.is_empty() {
-        score += scoring.isbn_weight;
-    } else if !snap.identifiers.is_empty() {
-        score += scoring.identifiers_weight;
-    } else {
-        reasons.push("missing identifiers/isbn".to_string());
+    fn comments_part_of(snap: &Snapshot, scoring: &ScoringConfig) -> ScorePart {
+        score_parts(snap, scoring).into_iter().find(|p| p.field == "comments").unwrap()
     }
 
-    if !snap.tags.is_empty() {
-        score += scoring.tags_weight;
-    } else {
-        reasons.push("missing tags".to_string());
+    /// Makes every point count towards good enough
+    fn strict(mut scoring: ScoringConfig) -> ScoringConfig {
+        let full = score_good_enough(&metadata_snapshot(&book(json!("x".repeat(50)))), &scoring).0;
+        scoring.min_score_to_skip_fetch = full;
+        scoring
     }
 
-    if snap.comments_present {
-        score += scoring.comments_weight;
-    } else {
-        reasons.push("missing description/comments".to_string());
+    #[test]
+    fn empty_comments_are_missing() {
+        let scoring = strict(scoring(20));
+        let snap = metadata_snapshot(&book(json!("  ")));
+        let part = comments_part_of(&snap, &scoring);
+        assert_eq!(part.points, 0);
+        assert_eq!(part.missing.as_deref(), Some("missing description/comments"));
+        let (score, _) = score_good_enough(&snap, &scoring);
+        assert!(!is_good_enough(score, &snap, &scoring));
     }
 
-    if snap.cover_present {
-        score += scoring.cover_weight;
-    } else {
-        reasons.push("missing cover".to_string());
+    #[test]
+    fn short_comments_score_nothing_ignoring_markup() {
+        let scoring = strict(scoring(20));
+        let snap = metadata_snapshot(&book(json!("<div><p>Too short.</p></div>")));
+        assert_eq!(snap.comments_chars, Some(10));
+        let part = comments_part_of(&snap, &scoring);
+        assert_eq!(part.points, 0);
+        assert_eq!(part.missing.as_deref(), Some("description shorter than 20 chars"));
+        let (score, _) = score_good_enough(&snap, &scoring);
+        assert!(!is_good_enough(score, &snap, &scoring));
     }
 
-    (score, reasons)
-}
+    #[test]
+    fn long_comments_score() {
+        let scoring = strict(scoring(20));
+        let snap = metadata_snapshot(&book(json!("<p>A description well over twenty chars.</p>")));
+        let part = comments_part_of(&snap, &scoring);
+        assert_eq!(part.points, scoring.comments_weight);
+        assert_eq!(part.missing, None);
+        let (score, _) = score_good_enough(&snap, &scoring);
+        assert!(is_good_enough(score, &snap, &scoring));
+    }
 
-pub fn normalize_languages_for_filter(val: &Value) -> Vec<String> {
-    normalize_languages(val)
-}
+    #[test]
+    fn presence_only_listing_cannot_apply_the_minimum() {
+        let scoring = strict(scoring(20));
+        let snap = metadata_snapshot(&book(json!(true)));
+        assert_eq!(snap.comments_chars, None);
+        assert_eq!(comments_part_of(&snap, &scoring).points, scoring.comments_weight);
+        let (score, _) = score_good_enough(&snap, &scoring);
+        assert!(is_good_enough(score, &snap, &scoring));
 
-pub fn normalize_identifiers_for_fetch(val: &Value) -> HashMap<String, String> {
-    normalize_identifiers(val)
+        let snap = metadata_snapshot(&book(json!(false)));
+        assert_eq!(comments_part_of(&snap, &scoring).points, 0);
+    }
 }
@@ -0,0 +1,104 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Metadata fields extracted from an OPF package, as written by
+/// `fetch-ebook-metadata --opf`.
+#[derive(Debug, Default, Clone)]
+pub struct OpfMetadata {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub publisher: String,
+    pub pubdate: String,
+    pub languages: Vec<String>,
+    pub identifiers: BTreeMap<String, String>,
+    pub tags: Vec<String>,
+    pub comments: String,
+    pub series: String,
+    pub series_index: String,
+}
+
+/// Identifier schemes only one metadata source writes, mapped to that source.
+const SOURCE_IDENTIFIERS: &[(&str, &str)] = &[
+    ("google", "google"),
+    ("amazon", "amazon"),
+    ("edelweiss", "edelweiss"),
+    ("douban", "douban"),
+    ("odid", "overdrive"),
+    ("openlibrary", "openlibrary"),
+];
+
+impl OpfMetadata {
+    /// The metadata source the OPF came from, going by the source-specific
+    /// identifier it carries (`amazon_de` counts as amazon). When Calibre
+    /// merged several sources the first in `SOURCE_IDENTIFIERS` wins.
+    pub fn source(&self) -> Option<&'static str> {
+        SOURCE_IDENTIFIERS.iter().find_map(|(scheme, source)| {
+            let hit = self.identifiers.keys().any(|k| {
+                k == scheme || k.strip_prefix(scheme).is_some_and(|rest| rest.starts_with('_'))
+            });
+            hit.then_some(*source)
+        })
+    }
+}
+
+pub fn read_opf(path: &Path) -> Result<OpfMetadata> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read OPF {}", path.display()))?;
+    parse_opf(&contents).with_context(|| format!("Failed to parse OPF {}", path.display()))
+}
+
+pub fn parse_opf(contents: &str) -> Result<OpfMetadata> {
+    let doc = roxmltree::Document::parse(contents)?;
+    let metadata = doc
+        .descendants()
+        .find(|n| n.tag_name().name() == "metadata")
+        .ok_or_else(|| anyhow::anyhow!("OPF has no <metadata> element"))?;
+
+    let mut out = OpfMetadata::default();
+    for node in metadata.children().filter(|n| n.is_element()) {
+        let text = node.text().unwrap_or("").trim().to_string();
+        match node.tag_name().name() {
+            "title" if out.title.is_empty() => out.title = text,
+            "creator" => {
+                let role = attr(&node, "role");
+                if (role.is_empty() || role == "aut") && !text.is_empty() {
+                    out.authors.push(text);
+                }
+            }
+            "publisher" => out.publisher = text,
+            "date" => out.pubdate = text,
+            "language" if !text.is_empty() => out.languages.push(text.to_lowercase()),
+            "subject" if !text.is_empty() => out.tags.push(text),
+            "description" => out.comments = text,
+            "identifier" => {
+                let scheme = attr(&node, "scheme").to_lowercase();
+                let (key, value) = if !scheme.is_empty() {
+                    (scheme, text)
+                } else if let Some((k, v)) = text.split_once(':') {
+                    (k.trim().to_lowercase(), v.trim().to_string())
+                } else {
+                    continue;
+                };
+                if key != "calibre" && key != "uuid" && !value.is_empty() {
+                    out.identifiers.insert(key, value);
+                }
+            }
+            "meta" => match attr(&node, "name").as_str() {
+                "calibre:series" => out.series = attr(&node, "content"),
+                "calibre:series_index" => out.series_index = attr(&node, "content"),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    Ok(out)
+}
+
+/// Attribute by local name, ignoring its namespace (`opf:role` == `role`).
+fn attr(node: &roxmltree::Node, name: &str) -> String {
+    node.attributes()
+        .find(|a| a.name() == name)
+        .map(|a| a.value().trim().to_string())
+        .unwrap_or_default()
+}
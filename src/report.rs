@@ -0,0 +1,326 @@
+use crate::dups::{write_output, OutputFormat};
+use crate::metadata::Snapshot;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+/// Run-level outcome of the main update pipeline.
+#[derive(Debug, Default, Serialize)]
+pub struct RunSummary {
+    pub library: String,
+    pub dry_run: bool,
+    pub candidates: usize,
+    pub done_ok: usize,
+    pub done_failed: usize,
+    pub skipped: usize,
+    pub skip_reasons: SkipBreakdown,
+    pub already_known: usize,
+    pub missing_files: Vec<i64>,
+    /// Candidates whose state was still `started` from an interrupted run
+    pub interrupted_last_run: Vec<i64>,
+    /// Candidates that also carry formats outside `formats.list`
+    pub books_with_non_targeted_formats: Vec<i64>,
+    /// How many candidates carry each non-targeted format
+    pub non_targeted_formats: BTreeMap<String, usize>,
+    pub verify_checked: usize,
+    pub verify_mismatched: usize,
+    /// Dry runs only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fetch_estimate: Option<FetchEstimate>,
+    /// `--dry-run-network` fetches that failed; not part of `done_failed`
+    pub dry_run_fetch_failed: usize,
+}
+
+/// Rough network cost of the fetches a dry run would perform.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct FetchEstimate {
+    pub fetches: usize,
+    pub requests: u64,
+    pub cover_bytes: u64,
+}
+
+/// One book's outcome in the `--report` file.
+#[derive(Debug, Serialize)]
+pub struct BookResult {
+    pub id: i64,
+    pub title: String,
+    pub action: String,
+    pub score: i32,
+    pub reasons: Vec<String>,
+    pub elapsed_seconds: f64,
+}
+
+/// Shape of the `--report` file.
+#[derive(Debug, Serialize)]
+pub struct RunReport<'a> {
+    pub generated_utc: String,
+    pub duration_seconds: f64,
+    pub summary: &'a RunSummary,
+    pub books: Vec<BookResult>,
+}
+
+/// One book's row in the `--scores-csv` file.
+#[derive(Debug)]
+pub struct ScoreRow {
+    pub id: i64,
+    pub title: String,
+    /// Points per scored field, in the order of the CSV header
+    pub points: Vec<i32>,
+    pub total: i32,
+    /// fetch, embed or skip
+    pub decision: &'static str,
+}
+
+/// CSV with one column per scoring field (its points, 0 when missing).
+pub fn scores_csv(fields: &[String], rows: &[ScoreRow]) -> String {
+    let mut header = vec!["id".to_string(), "book_title".to_string()];
+    header.extend(fields.iter().cloned());
+    header.extend(["total".to_string(), "decision".to_string()]);
+    let mut lines = vec![header.iter().map(|h| csv_field(h)).collect::<Vec<_>>().join(",")];
+    for row in rows {
+        let mut cells = vec![row.id.to_string(), csv_field(&row.title)];
+        cells.extend(row.points.iter().map(i32::to_string));
+        cells.extend([row.total.to_string(), row.decision.to_string()]);
+        lines.push(cells.join(","));
+    }
+    lines.join("\n")
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[derive(Serialize)]
+struct ProgressEvent<'a> {
+    id: Option<i64>,
+    title: Option<&'a str>,
+    phase: &'a str,
+    status: &'a str,
+    processed: usize,
+    total: usize,
+}
+
+/// JSON-lines progress stream for `--progress-json`, one compact object per
+/// event. A `done` event marks a book finished and advances `processed`.
+pub struct ProgressWriter {
+    out: Mutex<Box<dyn Write + Send>>,
+    total: AtomicUsize,
+    processed: AtomicUsize,
+}
+
+impl ProgressWriter {
+    /// `-` writes to stdout, anything else is a file path (truncated).
+    pub fn open(target: &str) -> Result<Self> {
+        let out: Box<dyn Write + Send> = if target == "-" {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(
+                std::fs::File::create(target)
+                    .with_context(|| format!("Failed to create {target}"))?,
+            )
+        };
+        Ok(Self {
+            out: Mutex::new(out),
+            total: AtomicUsize::new(0),
+            processed: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn listed(&self, total: usize) {
+        self.total.store(total, Ordering::Relaxed);
+        self.write(None, None, "listing", "done", 0);
+    }
+
+    pub fn book(&self, id: i64, title: &str, phase: &str, status: &str) {
+        let processed = if phase == "done" {
+            self.processed.fetch_add(1, Ordering::Relaxed) + 1
+        } else {
+            self.processed.load(Ordering::Relaxed)
+        };
+        self.write(Some(id), Some(title), phase, status, processed);
+    }
+
+    fn write(&self, id: Option<i64>, title: Option<&str>, phase: &str, status: &str, processed: usize) {
+        let event = ProgressEvent {
+            id,
+            title,
+            phase,
+            status,
+            processed,
+            total: self.total.load(Ordering::Relaxed),
+        };
+        let Ok(line) = serde_json::to_string(&event) else {
+            return;
+        };
+        let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+        // Progress is best effort; a closed pipe must not abort the run
+        let _ = writeln!(out, "{line}").and_then(|_| out.flush());
+    }
+}
+
+/// Writes the report via a temp file and rename, like the state file.
+pub fn write_report(path: &Path, report: &RunReport) -> Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+    let mut file = std::fs::File::create(&tmp_path)
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+    let json = serde_json::to_string_pretty(report)?;
+    file.write_all(json.as_bytes())?;
+    file.write_all(b"\n")?;
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to move {} -> {}", tmp_path.display(), path.display()))?;
+    Ok(())
+}
+
+/// Why books were not processed. The first seven add up to `skipped`; the
+/// `filtered_*` books never became candidates.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct SkipBreakdown {
+    pub already_done: usize,
+    pub already_known: usize,
+    pub good_enough: usize,
+    pub backoff: usize,
+    /// Failed at least `policy.max_fail_count` times
+    pub max_fail_count: usize,
+    /// No ISBN/identifier under `fetch.require_strong_identifier`
+    pub no_strong_identifier: usize,
+    /// `--refetch-covers` books whose current cover was kept
+    pub cover_kept: usize,
+    pub filtered_language: usize,
+    pub filtered_format: usize,
+}
+
+/// One line of the dry-run plan: what would happen to a book and why,
+/// from the listed metadata only.
+pub fn dry_run_plan_line(
+    book_id: i64,
+    action: &str,
+    score: i32,
+    reasons: &[String],
+    snap: &Snapshot,
+    format: OutputFormat,
+) -> Result<String> {
+    Ok(match format {
+        OutputFormat::Json => serde_json::to_string(&serde_json::json!({
+            "id": book_id,
+            "action": action,
+            "score": score,
+            "missing": reasons,
+            "metadata": snap,
+        }))?,
+        OutputFormat::Text => {
+            let mut line = format!(
+                "{book_id:>7}  {action:<5}  score={score:<3} {} / {}",
+                snap.title,
+                snap.authors.join(" & ")
+            );
+            if !reasons.is_empty() {
+                line.push_str(&format!("  [{}]", reasons.join(", ")));
+            }
+            line
+        }
+    })
+}
+
+pub fn print_summary(summary: &RunSummary, format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Text => write_output(&summary_text(summary), None),
+        OutputFormat::Json => write_output(&serde_json::to_string_pretty(summary)?, None),
+    }
+}
+
+fn summary_text(summary: &RunSummary) -> String {
+    let mut buf = String::new();
+    buf.push_str(&format!("Library: {}\n", summary.library));
+    if summary.dry_run {
+        buf.push_str("Mode: dry-run\n");
+    }
+    buf.push_str(&format!("Candidates: {}\n", summary.candidates));
+    buf.push_str(&format!("Done: {}\n", summary.done_ok));
+    buf.push_str(&format!("Failed: {}\n", summary.done_failed));
+    buf.push_str(&format!("Skipped: {}\n", summary.skipped));
+    let reasons = &summary.skip_reasons;
+    for (label, count) in [
+        ("already done", reasons.already_done),
+        ("already known", reasons.already_known),
+        ("good enough (embedded only)", reasons.good_enough),
+        ("backoff (failed permanently or waiting to retry)", reasons.backoff),
+        ("exceeded max fail count", reasons.max_fail_count),
+        ("no strong identifier (isbn-only)", reasons.no_strong_identifier),
+        ("cover kept (large enough, or fetched one not larger)", reasons.cover_kept),
+    ] {
+        if count > 0 {
+            buf.push_str(&format!("  {label}: {count}\n"));
+        }
+    }
+    if reasons.filtered_language + reasons.filtered_format > 0 {
+        buf.push_str(&format!(
+            "Filtered out: {} by language, {} by format\n",
+            reasons.filtered_language, reasons.filtered_format
+        ));
+    }
+    if !summary.missing_files.is_empty() {
+        buf.push_str(&format!(
+            "Missing format files: {} (ids: {})\n",
+            summary.missing_files.len(),
+            summary
+                .missing_files
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        ));
+    }
+    if !summary.interrupted_last_run.is_empty() {
+        buf.push_str(&format!(
+            "Interrupted last run: {} (ids: {})\n",
+            summary.interrupted_last_run.len(),
+            summary
+                .interrupted_last_run
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        ));
+    }
+    if !summary.books_with_non_targeted_formats.is_empty() {
+        buf.push_str(&format!(
+            "Books with additional non-targeted formats: {} ({})\n",
+            summary.books_with_non_targeted_formats.len(),
+            summary
+                .non_targeted_formats
+                .iter()
+                .map(|(fmt, n)| format!("{fmt}: {n}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    if let Some(est) = &summary.fetch_estimate {
+        buf.push_str(&format!(
+            "Estimated fetch load: {} fetches, ~{} requests, ~{:.1} MB of covers\n",
+            est.fetches,
+            est.requests,
+            est.cover_bytes as f64 / 1_000_000.0
+        ));
+    }
+    if summary.dry_run_fetch_failed > 0 {
+        buf.push_str(&format!(
+            "Dry-run fetches failed: {} (not counted as failures)\n",
+            summary.dry_run_fetch_failed
+        ));
+    }
+    if summary.verify_checked > 0 {
+        buf.push_str(&format!(
+            "Embed verification: {} checked, {} mismatched\n",
+            summary.verify_checked, summary.verify_mismatched
+        ));
+    }
+    buf
+}
@@ -0,0 +1,108 @@
+use crate::calibre::apply_opf_to_calibre_db;
+use crate::dups::write_output;
+use crate::runner::Runner;
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+#[derive(Parser, Debug)]
+pub struct RestoreArgs {
+    /// Book ids to restore (comma-separated)
+    #[arg(
+        value_delimiter = ',',
+        required_unless_present = "all",
+        conflicts_with = "all"
+    )]
+    pub ids: Vec<i64>,
+
+    /// Restore every book with a backup
+    #[arg(long, default_value_t = false)]
+    pub all: bool,
+
+    /// Apply the backups; without it, only list what would be restored
+    #[arg(long, default_value_t = false)]
+    pub yes: bool,
+}
+
+/// Re-applies `<backup_dir>/<id>.opf` (written under
+/// `policy.backup_before_change`) to each book. State entries are left as
+/// they are. `false` if any book failed to restore.
+pub fn run_restore(
+    runner: &Runner,
+    lib: &str,
+    backup_dir: &Path,
+    args: &RestoreArgs,
+) -> Result<bool> {
+    let ids = if args.all {
+        backed_up_ids(backup_dir)?
+    } else {
+        args.ids.clone()
+    };
+    let (found, missing): (Vec<i64>, Vec<i64>) =
+        ids.into_iter().partition(|id| backup_path(backup_dir, *id).is_file());
+
+    let mut out = String::new();
+    if !args.yes {
+        out.push_str(&format!(
+            "would restore {} from {}: {}\n",
+            found.len(),
+            backup_dir.display(),
+            join_ids(&found)
+        ));
+        push_missing(&mut out, &missing);
+        out.push_str("pass --yes to apply");
+        write_output(&out, None)?;
+        return Ok(true);
+    }
+
+    let mut restored = Vec::new();
+    let mut failed = Vec::new();
+    for id in found {
+        let opf_path = backup_path(backup_dir, id);
+        let (ok, msg) = apply_opf_to_calibre_db(runner, lib, id, &opf_path, None)?;
+        if ok {
+            info!(id, "[restore] metadata restored");
+            restored.push(id);
+        } else {
+            warn!(id, error = %msg, "[restore] failed");
+            failed.push((id, msg));
+        }
+    }
+    out.push_str(&format!("restored {}: {}\n", restored.len(), join_ids(&restored)));
+    for (id, msg) in &failed {
+        out.push_str(&format!("failed {id}: {msg}\n"));
+    }
+    push_missing(&mut out, &missing);
+    write_output(out.trim_end(), None)?;
+    Ok(failed.is_empty())
+}
+
+fn backup_path(backup_dir: &Path, book_id: i64) -> PathBuf {
+    backup_dir.join(format!("{book_id}.opf"))
+}
+
+/// Ids of every `<id>.opf` in the backup dir, in order.
+fn backed_up_ids(backup_dir: &Path) -> Result<Vec<i64>> {
+    if !backup_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let entries = std::fs::read_dir(backup_dir)
+        .with_context(|| format!("Failed to read {}", backup_dir.display()))?;
+    let mut ids: Vec<i64> = entries
+        .flatten()
+        .filter_map(|e| e.file_name().to_str()?.strip_suffix(".opf")?.parse().ok())
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+fn push_missing(out: &mut String, missing: &[i64]) {
+    if !missing.is_empty() {
+        out.push_str(&format!("no backup {}: {}\n", missing.len(), join_ids(missing)));
+    }
+}
+
+fn join_ids(ids: &[i64]) -> String {
+    ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(",")
+}
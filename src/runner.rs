@@ -6,6 +6,7 @@ use std::io::{BufRead, BufReader};
 use std::path::Path;
 use std::process::{Command, Stdio};
 use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
 use std::thread;
 use std::time::{Duration, Instant};
 use tracing::{debug, info, warn};
@@ -43,12 +44,66 @@ pub struct CmdResult {
 #[derive(Debug)]
 pub struct Runner {
     pub calibredb_env_mode: CalibreEnvMode,
+    /// Env mode resolved at runtime; set once a cleaned-env retry succeeds so
+    /// later calibredb calls skip the failing inherited env.
+    pub resolved_env_mode: Mutex<Option<CalibreEnvMode>>,
     pub debug_calibredb_env: bool,
     pub headless_fetch: bool,
     pub headless_env: HashMap<String, String>,
     pub fetch_use_xvfb: bool,
     pub calibre_username: Option<String>,
     pub calibre_password: Option<String>,
+    /// Bounds simultaneous fetch-ebook-metadata processes
+    pub fetch_slots: FetchSlots,
+    /// Extra attempts for a calibredb call that failed on a locked library
+    pub lock_retries: u32,
+    pub lock_retry_delay: Duration,
+    /// Extra attempts for a listing that failed on a network error; the
+    /// delay doubles after each one
+    pub network_retries: u32,
+    pub network_retry_delay: Duration,
+}
+
+/// Counting semaphore over fetch processes; each Qt/WebEngine instance can
+/// take hundreds of MB, so this is kept below the worker count.
+#[derive(Debug)]
+pub struct FetchSlots {
+    free: Mutex<usize>,
+    released: Condvar,
+}
+
+struct FetchSlot<'a>(&'a FetchSlots);
+
+impl FetchSlots {
+    pub fn new(max: usize) -> Self {
+        Self {
+            free: Mutex::new(max.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, usize> {
+        self.free.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn acquire(&self) -> FetchSlot<'_> {
+        let mut free = self.lock();
+        if *free == 0 {
+            debug!("[fetch] waiting for a free fetch slot");
+        }
+        while *free == 0 {
+            free = self.released.wait(free).unwrap_or_else(PoisonError::into_inner);
+        }
+        *free -= 1;
+        FetchSlot(self)
+    }
+}
+
+impl Drop for FetchSlot<'_> {
+    fn drop(&mut self) {
+        *self.0.lock() += 1;
+        self.0.released.notify_one();
+    }
 }
 
 fn is_calibredb(cmd0: &str) -> bool {
@@ -59,6 +114,34 @@ fn is_calibredb(cmd0: &str) -> bool {
         .unwrap_or(false)
 }
 
+/// calibredb's refusal while the GUI or calibre-server has the library open,
+/// and SQLite's busy error.
+fn is_library_locked(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    stderr.contains("another calibre program") || stderr.contains("database is locked")
+}
+
+/// Connection and gateway failures talking to a Content Server, worth
+/// retrying; auth and not-found errors are not.
+fn is_network_error(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    [
+        "connection refused",
+        "connection reset",
+        "connection aborted",
+        "remote end closed connection",
+        "timed out",
+        "temporary failure in name resolution",
+        "network is unreachable",
+        "http error 500",
+        "http error 502",
+        "http error 503",
+        "http error 504",
+    ]
+    .iter()
+    .any(|needle| stderr.contains(needle))
+}
+
 fn trim_if_present(s: &str) -> String {
     s.trim().to_string()
 }
@@ -88,6 +171,20 @@ fn base_env_with_extra(extra_env: Option<&HashMap<String, String>>) -> HashMap<S
 }
 
 impl Runner {
+    fn env_mode(&self) -> CalibreEnvMode {
+        self.resolved_env_mode
+            .lock()
+            .ok()
+            .and_then(|m| *m)
+            .unwrap_or(self.calibredb_env_mode)
+    }
+
+    fn set_env_mode(&self, mode: CalibreEnvMode) {
+        if let Ok(mut m) = self.resolved_env_mode.lock() {
+            *m = Some(mode);
+        }
+    }
+
     pub fn run(
         &self,
         cmd: &[String],
@@ -97,6 +194,8 @@ impl Runner {
         self.run_with_timeout(cmd, capture, extra_env, None, None)
     }
 
+    /// Runs `cmd`; a calibredb call that fails because another Calibre
+    /// process holds the library is retried `lock_retries` times.
     pub fn run_with_timeout(
         &self,
         cmd: &[String],
@@ -104,6 +203,60 @@ impl Runner {
         extra_env: Option<&HashMap<String, String>>,
         timeout: Option<Duration>,
         heartbeat: Option<Duration>,
+    ) -> Result<CmdResult> {
+        let mut attempt = 0;
+        loop {
+            let result = self.run_once(cmd, capture, extra_env, timeout, heartbeat)?;
+            if result.status_code == 0
+                || attempt >= self.lock_retries
+                || !is_calibredb(&cmd[0])
+                || !is_library_locked(&result.stderr)
+            {
+                return Ok(result);
+            }
+            attempt += 1;
+            warn!(
+                attempt,
+                max = self.lock_retries,
+                wait_seconds = self.lock_retry_delay.as_secs_f64(),
+                "[calibredb] library is locked by another Calibre program; retrying"
+            );
+            std::thread::sleep(self.lock_retry_delay);
+        }
+    }
+
+    /// `run` for read-only `calibredb list` calls, retried with backoff while
+    /// they fail on a network error (see `is_network_error`).
+    pub fn run_listing(&self, cmd: &[String]) -> Result<CmdResult> {
+        let mut attempt = 0;
+        loop {
+            let result = self.run(cmd, true, None)?;
+            if result.status_code == 0
+                || attempt >= self.network_retries
+                || !is_network_error(&result.stderr)
+            {
+                return Ok(result);
+            }
+            let delay = self.network_retry_delay * 2u32.saturating_pow(attempt);
+            attempt += 1;
+            warn!(
+                attempt,
+                max = self.network_retries,
+                wait_seconds = delay.as_secs_f64(),
+                stderr = %truncate(result.stderr.trim(), 200),
+                "[calibredb] listing failed on a network error; retrying"
+            );
+            std::thread::sleep(delay);
+        }
+    }
+
+    fn run_once(
+        &self,
+        cmd: &[String],
+        capture: bool,
+        extra_env: Option<&HashMap<String, String>>,
+        timeout: Option<Duration>,
+        heartbeat: Option<Duration>,
     ) -> Result<CmdResult> {
         if cmd.is_empty() {
             anyhow::bail!("empty command");
@@ -111,7 +264,7 @@ impl Runner {
         debug!(command = %cmd.join(" "), "[cmd]");
         let mut base_env = base_env_with_extra(extra_env);
 
-        if cmd.get(0).map(|s| s == "fetch-ebook-metadata").unwrap_or(false)
+        if cmd.first().map(|s| s == "fetch-ebook-metadata").unwrap_or(false)
             && self.headless_fetch
         {
             for (k, v) in &self.headless_env {
@@ -213,7 +366,7 @@ impl Runner {
                 }
             }
 
-            match self.calibredb_env_mode {
+            match self.env_mode() {
                 CalibreEnvMode::Clean => {
                     base_env.retain(|k, _| !should_clean_env_key(k));
                     return run_with_env(&base_env);
@@ -270,7 +423,8 @@ impl Runner {
                         base_env.retain(|k, _| !should_clean_env_key(k));
                         let retry = run_with_env(&base_env)?;
                         if retry.status_code == 0 {
-                            info!("[calibredb] succeeded after cleaning env vars");
+                            info!("[calibredb] succeeded after cleaning env vars; using clean env for the rest of the run");
+                            self.set_env_mode(CalibreEnvMode::Clean);
                             return Ok(retry);
                         }
                         if !retry.stderr.trim().is_empty() {
@@ -298,6 +452,7 @@ impl Runner {
         if cmd.is_empty() {
             anyhow::bail!("empty command");
         }
+        let _slot = self.fetch_slots.acquire();
         debug!(command = %cmd.join(" "), "[cmd]");
         let mut env = base_env_with_extra(None);
         if self.headless_fetch {
@@ -341,14 +496,14 @@ impl Runner {
 
         let out_handle = thread::spawn(move || {
             let reader = BufReader::new(stdout);
-            for line in reader.lines().flatten() {
+            for line in reader.lines().map_while(Result::ok) {
                 let _ = tx_out.send((true, line));
             }
         });
 
         let err_handle = thread::spawn(move || {
             let reader = BufReader::new(stderr);
-            for line in reader.lines().flatten() {
+            for line in reader.lines().map_while(Result::ok) {
                 let _ = tx_err.send((false, line));
             }
         });
@@ -424,3 +579,55 @@ impl Runner {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn library_locked_samples() {
+        for stderr in [
+            "Another calibre program such as calibre-server or the main calibre program is \
+             running. Having multiple programs that can make changes to a calibre library \
+             running at the same time is a bad idea.",
+            "apsw.BusyError: BusyError: database is locked",
+            "sqlite3.OperationalError: Database Is Locked",
+        ] {
+            assert!(is_library_locked(stderr), "{stderr}");
+        }
+        for stderr in [
+            "",
+            "No book with id: 42 present",
+            "PermissionError: [Errno 13] Permission denied: '/books/metadata.db'",
+            "urllib.error.URLError: <urlopen error [Errno 111] Connection refused>",
+        ] {
+            assert!(!is_library_locked(stderr), "{stderr}");
+        }
+    }
+
+    #[test]
+    fn network_error_samples() {
+        for stderr in [
+            "urllib.error.URLError: <urlopen error [Errno 111] Connection refused>",
+            "ConnectionResetError: [Errno 104] Connection reset by peer",
+            "http.client.RemoteDisconnected: Remote end closed connection without response",
+            "socket.timeout: timed out",
+            "<urlopen error [Errno -3] Temporary failure in name resolution>",
+            "OSError: [Errno 101] Network is unreachable",
+            "urllib.error.HTTPError: HTTP Error 502: Bad Gateway",
+            "HTTP Error 503: Service Unavailable",
+        ] {
+            assert!(is_network_error(stderr), "{stderr}");
+        }
+        for stderr in [
+            "",
+            "urllib.error.HTTPError: HTTP Error 401: Unauthorized",
+            "urllib.error.HTTPError: HTTP Error 403: Forbidden",
+            "urllib.error.HTTPError: HTTP Error 404: Not Found",
+            "No book with id: 42 present",
+            "apsw.BusyError: BusyError: database is locked",
+        ] {
+            assert!(!is_network_error(stderr), "{stderr}");
+        }
+    }
+}
@@ -2,7 +2,10 @@ use anyhow::{Context, Result};
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 #[serde(default)]
@@ -13,6 +16,29 @@ pub struct BookState {
     pub last_ok_utc: Option<String>,
     pub message: Option<String>,
     pub fail_count: i32,
+    /// Set by a `[retry]` backoff: the book is skipped until then
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_utc: Option<String>,
+    /// Metadata source of the last applied fetch (`google`, `amazon`, ...,
+    /// `external`, or `unknown`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// What that fetch was matched by: `isbn`, `identifier` or `title`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched_by: Option<String>,
+}
+
+/// Outcome of the last `--refetch-covers` pass for a book, kept apart from
+/// the text-metadata status in `BookState`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct CoverState {
+    pub status: String,
+    pub last_attempt_utc: String,
+    pub last_ok_utc: Option<String>,
+    pub message: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -21,30 +47,72 @@ pub struct StateFile {
     pub version: i32,
     pub updated_at_utc: Option<String>,
     pub books: HashMap<String, BookState>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub covers: HashMap<String, CoverState>,
+    /// Book id -> `metadata::identity_key` as last listed, for
+    /// `--follow-library-moves`
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub identities: HashMap<String, String>,
 }
 
 pub fn now_iso() -> String {
     Utc::now().to_rfc3339()
 }
 
+/// A stored UTC timestamp for display, converted to the local offset when
+/// `local`; returned as-is if it doesn't parse.
+pub fn display_timestamp(raw: &str, local: bool) -> String {
+    match chrono::DateTime::parse_from_rfc3339(raw.trim()) {
+        Ok(dt) if local => dt
+            .with_timezone(&chrono::Local)
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+        _ => raw.to_string(),
+    }
+}
+
+/// Loads the state file, falling back to the `.json.bak` copy kept by
+/// `save_state` when the primary is unreadable (e.g. truncated by a crash).
 pub fn load_state(path: &Path) -> Result<StateFile> {
+    let tmp_path = path.with_extension("json.tmp");
+    if tmp_path.exists() {
+        warn!(path = %tmp_path.display(), "[state] ignoring leftover from an interrupted write");
+        let _ = std::fs::remove_file(&tmp_path);
+    }
     if !path.exists() {
         return Ok(StateFile {
             version: 1,
-            updated_at_utc: None,
-            books: HashMap::new(),
+            ..Default::default()
         });
     }
-    let contents = std::fs::read_to_string(path)
-        .with_context(|| format!("Failed to read state file {}", path.display()))?;
-    let mut state: StateFile = serde_json::from_str(&contents)
-        .with_context(|| format!("Failed to parse state file {}", path.display()))?;
+    let mut state = match read_state_file(path) {
+        Ok(state) => state,
+        Err(err) => {
+            let bak_path = path.with_extension("json.bak");
+            if !bak_path.exists() {
+                return Err(err);
+            }
+            let state = read_state_file(&bak_path).with_context(|| format!("{err:#}"))?;
+            warn!(
+                error = %format!("{err:#}"),
+                backup = %bak_path.display(),
+                "[state] state file unreadable; continuing from backup"
+            );
+            state
+        }
+    };
     if state.version == 0 {
         state.version = 1;
     }
     Ok(state)
 }
 
+fn read_state_file(path: &Path) -> Result<StateFile> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read state file {}", path.display()))?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse state file {}", path.display()))
+}
+
 pub fn save_state(path: &Path, state: &mut StateFile) -> Result<()> {
     state.updated_at_utc = Some(now_iso());
     let tmp_path = path.with_extension("json.tmp");
@@ -54,11 +122,162 @@ pub fn save_state(path: &Path, state: &mut StateFile) -> Result<()> {
     use std::io::Write;
     file.write_all(json.as_bytes())?;
     file.write_all(b"\n")?;
+    file.sync_all()?;
+    // The previous good copy, for load_state to fall back on
+    if path.exists() {
+        let bak_path = path.with_extension("json.bak");
+        std::fs::copy(path, &bak_path)
+            .with_context(|| format!("Failed to back up state to {}", bak_path.display()))?;
+    }
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to move {} -> {}", tmp_path.display(), path.display()))?;
+    Ok(())
+}
+
+/// Per-book record of the last run's outcome, keyed by book id. A book whose
+/// `last_modified` still matches a terminal entry is skipped without
+/// computing its metadata hash.
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Manifest {
+    pub generated_utc: String,
+    pub books: HashMap<String, ManifestEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ManifestEntry {
+    pub final_hash: String,
+    pub status: String,
+    /// Calibre's last_modified as listed at the start of the run
+    pub last_modified: String,
+}
+
+pub fn load_manifest(path: &Path) -> Result<Manifest> {
+    if !path.exists() {
+        return Ok(Manifest::default());
+    }
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read manifest {}", path.display()))?;
+    serde_json::from_str(&contents).with_context(|| format!("Failed to parse manifest {}", path.display()))
+}
+
+pub fn save_manifest(path: &Path, manifest: &mut Manifest) -> Result<()> {
+    manifest.generated_utc = now_iso();
+    let tmp_path = path.with_extension("json.tmp");
+    let json = serde_json::to_string_pretty(manifest)?;
+    std::fs::write(&tmp_path, json + "\n")
+        .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
     std::fs::rename(&tmp_path, path)
         .with_context(|| format!("Failed to move {} -> {}", tmp_path.display(), path.display()))?;
     Ok(())
 }
 
+/// Takes an exclusive advisory lock on `<state>.lock` so two runs can't
+/// interleave state writes. `None` means another process holds it; the lock
+/// is released when the returned file is dropped (or the process dies).
+pub fn acquire_run_lock(state_path: &Path) -> Result<Option<std::fs::File>> {
+    let lock_path = state_path.with_extension("json.lock");
+    if let Some(dir) = lock_path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .with_context(|| format!("Failed to open lock file {}", lock_path.display()))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) => return Ok(None),
+        Err(std::fs::TryLockError::Error(err)) => {
+            return Err(err).with_context(|| format!("Failed to lock {}", lock_path.display()));
+        }
+    }
+    // Informational only: the lock itself is what counts
+    use std::io::Write;
+    file.set_len(0)?;
+    writeln!(file, "{}", std::process::id())?;
+    Ok(Some(file))
+}
+
+/// A book whose entry differs between two state files.
+#[derive(Debug, Serialize)]
+pub struct BookChange {
+    pub id: String,
+    pub old_status: String,
+    pub new_status: String,
+    pub hash_changed: bool,
+    pub old_fail_count: i32,
+    pub new_fail_count: i32,
+}
+
+/// A book present in only one of two state files.
+#[derive(Debug, Serialize)]
+pub struct BookPresence {
+    pub id: String,
+    pub status: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct StateDiff {
+    pub changed: Vec<BookChange>,
+    /// Only in the old file
+    pub removed: Vec<BookPresence>,
+    /// Only in the new file
+    pub added: Vec<BookPresence>,
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed.is_empty() && self.added.is_empty()
+    }
+}
+
+/// Books whose status, hash or fail count differ, and books present in only
+/// one of the files. Sorted numerically by id.
+pub fn diff_states(old: &StateFile, new: &StateFile) -> StateDiff {
+    let mut diff = StateDiff::default();
+    for (id, before) in &old.books {
+        match new.books.get(id) {
+            None => diff.removed.push(BookPresence {
+                id: id.clone(),
+                status: before.status.clone(),
+            }),
+            Some(after)
+                if after.status != before.status
+                    || after.last_hash != before.last_hash
+                    || after.fail_count != before.fail_count =>
+            {
+                diff.changed.push(BookChange {
+                    id: id.clone(),
+                    old_status: before.status.clone(),
+                    new_status: after.status.clone(),
+                    hash_changed: after.last_hash != before.last_hash,
+                    old_fail_count: before.fail_count,
+                    new_fail_count: after.fail_count,
+                });
+            }
+            Some(_) => {}
+        }
+    }
+    for (id, after) in &new.books {
+        if !old.books.contains_key(id) {
+            diff.added.push(BookPresence {
+                id: id.clone(),
+                status: after.status.clone(),
+            });
+        }
+    }
+    // Numeric ids first in order, anything unparseable after them
+    let order = |id: &str| (id.parse::<i64>().unwrap_or(i64::MAX), id.to_string());
+    diff.changed.sort_by_key(|c| order(&c.id));
+    diff.removed.sort_by_key(|b| order(&b.id));
+    diff.added.sort_by_key(|b| order(&b.id));
+    diff
+}
+
 pub fn get_book_state(state: &StateFile, book_id: i64) -> Option<BookState> {
     state.books.get(&book_id.to_string()).cloned()
 }
@@ -66,3 +285,325 @@ pub fn get_book_state(state: &StateFile, book_id: i64) -> Option<BookState> {
 pub fn put_book_state(state: &mut StateFile, book_id: i64, bs: BookState) {
     state.books.insert(book_id.to_string(), bs);
 }
+
+/// Writes the state file, coalescing writes when they are slow.
+///
+/// Each write is timed; when one takes longer than `slow_threshold` the
+/// number of `save` calls folded into one write doubles (up to
+/// `max_interval`), and it halves again once writes get fast. `flush`
+/// always writes pending changes.
+pub struct StateSaver {
+    path: PathBuf,
+    adaptive: bool,
+    slow_threshold: Duration,
+    max_interval: u32,
+    interval: u32,
+    pending: u32,
+}
+
+impl StateSaver {
+    pub fn new(path: &Path, adaptive: bool, slow_threshold: Duration, max_interval: u32) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            adaptive,
+            slow_threshold,
+            max_interval: max_interval.max(1),
+            interval: 1,
+            pending: 0,
+        }
+    }
+
+    pub fn save(&mut self, state: &mut StateFile) -> Result<()> {
+        self.pending += 1;
+        if self.pending < self.interval {
+            return Ok(());
+        }
+        self.write(state)
+    }
+
+    pub fn flush(&mut self, state: &mut StateFile) -> Result<()> {
+        if self.pending == 0 {
+            return Ok(());
+        }
+        self.write(state)
+    }
+
+    fn write(&mut self, state: &mut StateFile) -> Result<()> {
+        let started = Instant::now();
+        save_state(&self.path, state)?;
+        self.pending = 0;
+        if !self.adaptive {
+            return Ok(());
+        }
+        let elapsed = started.elapsed();
+        if elapsed > self.slow_threshold && self.interval < self.max_interval {
+            self.interval = (self.interval * 2).min(self.max_interval);
+            info!(
+                elapsed_ms = elapsed.as_millis(),
+                interval = self.interval,
+                "[state] slow save; coalescing writes"
+            );
+        } else if elapsed < self.slow_threshold / 4 && self.interval > 1 {
+            self.interval /= 2;
+            debug!(interval = self.interval, "[state] fast save; reducing coalescing");
+        }
+        Ok(())
+    }
+}
+
+/// Shared handle over the state file and its saver so workers can record
+/// results concurrently; every `put` goes through the saver.
+pub struct StateStore {
+    inner: Mutex<(StateFile, StateSaver)>,
+}
+
+impl StateStore {
+    pub fn new(state: StateFile, saver: StateSaver) -> Self {
+        Self {
+            inner: Mutex::new((state, saver)),
+        }
+    }
+
+    pub fn get(&self, book_id: i64) -> Option<BookState> {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        get_book_state(&inner.0, book_id)
+    }
+
+    pub fn put(&self, book_id: i64, bs: BookState) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let (state, saver) = &mut *inner;
+        put_book_state(state, book_id, bs);
+        saver.save(state)
+    }
+
+    /// Records `bs` in memory only; it reaches disk with the next `put` or
+    /// `flush`. For markers like `started` that never claim work was done.
+    pub fn stage(&self, book_id: i64, bs: BookState) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        put_book_state(&mut inner.0, book_id, bs);
+    }
+
+    pub fn get_cover(&self, book_id: i64) -> Option<CoverState> {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.0.covers.get(&book_id.to_string()).cloned()
+    }
+
+    pub fn put_cover(&self, book_id: i64, cs: CoverState) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let (state, saver) = &mut *inner;
+        state.covers.insert(book_id.to_string(), cs);
+        saver.save(state)
+    }
+
+    /// Marks state entries whose book is no longer in `library_ids` as
+    /// `deleted`, or drops them (and their cover state) when `prune` is set.
+    /// Returns the affected ids; without `apply` (dry runs) state is left alone.
+    pub fn reconcile_deleted(
+        &self,
+        library_ids: &std::collections::HashSet<i64>,
+        prune: bool,
+        apply: bool,
+    ) -> Result<Vec<i64>> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let (state, saver) = &mut *inner;
+        let mut gone: Vec<i64> = state
+            .books
+            .iter()
+            .filter(|(_, bs)| prune || bs.status != "deleted")
+            .filter_map(|(key, _)| key.parse::<i64>().ok())
+            .filter(|id| !library_ids.contains(id))
+            .collect();
+        gone.sort_unstable();
+        if gone.is_empty() || !apply {
+            return Ok(gone);
+        }
+        for id in &gone {
+            let key = id.to_string();
+            if prune {
+                state.books.remove(&key);
+                state.covers.remove(&key);
+                state.identities.remove(&key);
+            } else if let Some(bs) = state.books.get_mut(&key) {
+                bs.status = "deleted".to_string();
+                bs.message = Some("no longer in the library".to_string());
+                bs.retry_after_utc = None;
+            }
+        }
+        saver.save(state)?;
+        Ok(gone)
+    }
+
+    /// Records the identity of each listed book. With `follow_moves`, state
+    /// entries whose identity now belongs to a different id are moved there
+    /// first; identities listed under several ids are ambiguous and ignored,
+    /// as are moves onto an id that already has state of its own. Returns
+    /// the `(old, new)` moves; without `apply` (dry runs) nothing is changed.
+    pub fn sync_identities(
+        &self,
+        listed: &[(i64, String)],
+        follow_moves: bool,
+        apply: bool,
+    ) -> Result<Vec<(i64, i64)>> {
+        let mut current: HashMap<&str, Option<i64>> = HashMap::new();
+        for (id, identity) in listed {
+            current
+                .entry(identity.as_str())
+                .and_modify(|found| *found = None)
+                .or_insert(Some(*id));
+        }
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let (state, saver) = &mut *inner;
+        let mut moves: Vec<(i64, i64)> = Vec::new();
+        if follow_moves {
+            moves = state
+                .identities
+                .iter()
+                .filter_map(|(key, identity)| {
+                    let old = key.parse::<i64>().ok()?;
+                    let new = (*current.get(identity.as_str())?)?;
+                    let has_state =
+                        state.books.contains_key(key) || state.covers.contains_key(key);
+                    (new != old && has_state).then_some((old, new))
+                })
+                .collect();
+            moves.sort_unstable();
+            let sources: std::collections::HashSet<i64> = moves.iter().map(|m| m.0).collect();
+            moves.retain(|(old, new)| {
+                let taken = !sources.contains(new)
+                    && (state.books.contains_key(&new.to_string())
+                        || state.covers.contains_key(&new.to_string()));
+                if taken {
+                    warn!(old, new, "[moves] target id already has state; not moving");
+                }
+                !taken
+            });
+            if !apply {
+                return Ok(moves);
+            }
+            // Take every source out before inserting, so swapped ids don't clobber each other
+            let taken: Vec<_> = moves
+                .iter()
+                .map(|(old, new)| {
+                    let key = old.to_string();
+                    (*new, state.books.remove(&key), state.covers.remove(&key))
+                })
+                .collect();
+            for (new, book, cover) in taken {
+                let key = new.to_string();
+                if let Some(book) = book {
+                    state.books.insert(key.clone(), book);
+                }
+                if let Some(cover) = cover {
+                    state.covers.insert(key, cover);
+                }
+            }
+            for (old, _) in &moves {
+                state.identities.remove(&old.to_string());
+            }
+        }
+        if !apply {
+            return Ok(moves);
+        }
+        for (id, identity) in listed {
+            state.identities.insert(id.to_string(), identity.clone());
+        }
+        saver.save(state)?;
+        Ok(moves)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let (state, saver) = &mut *inner;
+        saver.flush(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(dir: &tempfile::TempDir, books: &[(i64, &str)]) -> (StateStore, PathBuf) {
+        let path = dir.path().join("state.json");
+        let mut state = StateFile { version: 1, ..Default::default() };
+        for (id, identity) in books {
+            let bs = BookState { status: format!("done {id}"), ..Default::default() };
+            state.books.insert(id.to_string(), bs);
+            state.identities.insert(id.to_string(), identity.to_string());
+        }
+        let saver = StateSaver::new(&path, false, Duration::from_secs(1), 1);
+        (StateStore::new(state, saver), path)
+    }
+
+    fn listed(books: &[(i64, &str)]) -> Vec<(i64, String)> {
+        books.iter().map(|(id, identity)| (*id, identity.to_string())).collect()
+    }
+
+    fn status(store: &StateStore, id: i64) -> Option<String> {
+        store.get(id).map(|bs| bs.status)
+    }
+
+    #[test]
+    fn state_follows_a_moved_identity() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (store, path) = store(&dir, &[(1, "a"), (2, "b")]);
+        let moves = store.sync_identities(&listed(&[(2, "b"), (7, "a")]), true, true).unwrap();
+        assert_eq!(moves, [(1, 7)]);
+        assert_eq!(status(&store, 1), None);
+        assert_eq!(status(&store, 7).as_deref(), Some("done 1"));
+        assert_eq!(status(&store, 2).as_deref(), Some("done 2"));
+
+        let saved = load_state(&path).unwrap();
+        assert!(!saved.identities.contains_key("1"));
+        assert_eq!(saved.identities["7"], "a");
+    }
+
+    #[test]
+    fn moves_are_reported_but_not_applied_on_dry_runs() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (store, path) = store(&dir, &[(1, "a")]);
+        let moves = store.sync_identities(&listed(&[(7, "a")]), true, false).unwrap();
+        assert_eq!(moves, [(1, 7)]);
+        assert_eq!(status(&store, 1).as_deref(), Some("done 1"));
+        assert_eq!(status(&store, 7), None);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn ambiguous_identities_are_not_followed() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (store, _path) = store(&dir, &[(1, "a")]);
+        let moves = store.sync_identities(&listed(&[(7, "a"), (8, "a")]), true, true).unwrap();
+        assert!(moves.is_empty());
+        assert_eq!(status(&store, 1).as_deref(), Some("done 1"));
+    }
+
+    #[test]
+    fn swapped_ids_keep_both_states() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (store, _path) = store(&dir, &[(1, "a"), (2, "b")]);
+        let moves = store.sync_identities(&listed(&[(1, "b"), (2, "a")]), true, true).unwrap();
+        assert_eq!(moves, [(1, 2), (2, 1)]);
+        assert_eq!(status(&store, 1).as_deref(), Some("done 2"));
+        assert_eq!(status(&store, 2).as_deref(), Some("done 1"));
+    }
+
+    #[test]
+    fn occupied_target_is_not_overwritten() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (store, _path) = store(&dir, &[(1, "a"), (2, "b")]);
+        // "b" is no longer listed, so 2 keeps its state and 1 can't move onto it
+        let moves = store.sync_identities(&listed(&[(2, "a")]), true, true).unwrap();
+        assert!(moves.is_empty());
+        assert_eq!(status(&store, 1).as_deref(), Some("done 1"));
+        assert_eq!(status(&store, 2).as_deref(), Some("done 2"));
+    }
+
+    #[test]
+    fn identities_are_saved_without_moves() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (store, path) = store(&dir, &[]);
+        let moves = store.sync_identities(&listed(&[(3, "c")]), false, true).unwrap();
+        assert!(moves.is_empty());
+        assert_eq!(load_state(&path).unwrap().identities["3"], "c");
+    }
+}
@@ -0,0 +1,98 @@
+use crate::config::ScoringConfig;
+use crate::dups::{write_output, OutputFormat};
+use crate::metadata::{is_good_enough, opf_snapshot, score_good_enough, Snapshot};
+use crate::opf::read_opf;
+use anyhow::Result;
+use clap::Parser;
+use serde::Serialize;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+pub struct ValidateOpfArgs {
+    /// OPF file to check (e.g. one left in fetch.workdir)
+    pub path: PathBuf,
+
+    /// Cover image that goes with the OPF; defaults to <stem>.cover.jpg beside it
+    #[arg(long)]
+    pub cover: Option<PathBuf>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value = "text")]
+    pub output: OutputFormat,
+}
+
+#[derive(Debug, Serialize)]
+struct OpfReport {
+    path: PathBuf,
+    cover: Option<PathBuf>,
+    fields: Snapshot,
+    score: i32,
+    min_score_to_skip_fetch: i32,
+    good_enough: bool,
+    reasons: Vec<String>,
+}
+
+/// Parses an OPF and scores it the way a library book would be scored.
+/// Touches neither Calibre nor the state file.
+pub fn run_validate_opf(scoring: &ScoringConfig, args: &ValidateOpfArgs) -> Result<()> {
+    let opf = read_opf(&args.path)?;
+    let cover = args
+        .cover
+        .clone()
+        .unwrap_or_else(|| args.path.with_extension("cover.jpg"));
+    let cover = cover.is_file().then_some(cover);
+    let fields = opf_snapshot(&opf, cover.is_some());
+    let (score, reasons) = score_good_enough(&fields, scoring);
+    let good_enough = is_good_enough(score, &fields, scoring);
+
+    let report = OpfReport {
+        path: args.path.clone(),
+        cover,
+        fields,
+        score,
+        min_score_to_skip_fetch: scoring.min_score_to_skip_fetch,
+        good_enough,
+        reasons,
+    };
+    match args.output {
+        OutputFormat::Text => write_output(&report_text(&report), None),
+        OutputFormat::Json => write_output(&serde_json::to_string_pretty(&report)?, None),
+    }
+}
+
+fn report_text(report: &OpfReport) -> String {
+    let f = &report.fields;
+    let mut buf = String::new();
+    buf.push_str(&format!("OPF: {}\n", report.path.display()));
+    buf.push_str(&format!("Title: {}\n", f.title));
+    buf.push_str(&format!("Authors: {}\n", f.authors.join(" & ")));
+    buf.push_str(&format!("Publisher: {}\n", f.publisher));
+    buf.push_str(&format!("Published: {}\n", f.pubdate));
+    buf.push_str(&format!("Languages: {}\n", f.languages.join(",")));
+    let mut ids: Vec<String> = f.identifiers.iter().map(|(k, v)| format!("{k}:{v}")).collect();
+    ids.sort();
+    buf.push_str(&format!("Identifiers: {}\n", ids.join(",")));
+    buf.push_str(&format!("Tags: {}\n", f.tags.join(", ")));
+    if !f.series.is_empty() {
+        let index = f.series_index.map(|i| format!(" [{i}]")).unwrap_or_default();
+        buf.push_str(&format!("Series: {}{index}\n", f.series));
+    }
+    match f.comments_chars.filter(|_| f.comments_present) {
+        Some(chars) => buf.push_str(&format!("Comments: yes ({chars} chars)\n")),
+        None => buf.push_str("Comments: no\n"),
+    }
+    match &report.cover {
+        Some(path) => buf.push_str(&format!("Cover: {}\n", path.display())),
+        None => buf.push_str("Cover: none\n"),
+    }
+    buf.push_str(&format!(
+        "Score: {} (min_score_to_skip_fetch {}) -> {}\n",
+        report.score,
+        report.min_score_to_skip_fetch,
+        if report.good_enough { "good enough" } else { "below threshold" }
+    ));
+    if !report.reasons.is_empty() {
+        buf.push_str(&format!("Missing: {}\n", report.reasons.join(", ")));
+    }
+    buf
+}
@@ -0,0 +1,132 @@
+use crate::calibre::list_book_ids;
+use crate::dups::{write_output, OutputFormat};
+use crate::runner::Runner;
+use crate::state::{display_timestamp, load_state, save_state};
+use anyhow::Result;
+use clap::Parser;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+use tracing::{info, warn};
+
+#[derive(Parser, Debug)]
+pub struct VerifyArgs {
+    /// Remove state entries whose book no longer exists in the library
+    #[arg(long, default_value_t = false)]
+    pub prune: bool,
+
+    /// Output format
+    #[arg(long, value_enum, default_value = "text")]
+    pub output: OutputFormat,
+}
+
+#[derive(Debug, Serialize)]
+struct VerifyReport {
+    library_books: usize,
+    state_entries: usize,
+    /// State entries for ids that are no longer in the library
+    orphaned: Vec<i64>,
+    /// Library books that have never been recorded in state
+    untracked: Vec<i64>,
+    /// State keys that are not book ids at all
+    invalid_keys: Vec<String>,
+    /// Books whose last applied fetch was matched by title/authors only
+    title_matches: Vec<i64>,
+    pruned: usize,
+    /// When the state file was last written (UTC)
+    state_updated_utc: Option<String>,
+}
+
+/// Cross-references the state file with the ids currently in the library.
+/// `local_time` renders timestamps in the text output in local time.
+pub fn run_verify(
+    runner: &Runner,
+    lib: &str,
+    state_path: &Path,
+    args: &VerifyArgs,
+    local_time: bool,
+) -> Result<()> {
+    let library_ids: BTreeSet<i64> = list_book_ids(runner, lib, "")?.into_iter().collect();
+    let mut state = load_state(state_path)?;
+
+    let mut state_ids = BTreeSet::new();
+    let mut invalid_keys = Vec::new();
+    for key in state.books.keys() {
+        match key.parse::<i64>() {
+            Ok(id) => {
+                state_ids.insert(id);
+            }
+            Err(_) => invalid_keys.push(key.clone()),
+        }
+    }
+
+    let orphaned: Vec<i64> = state_ids.difference(&library_ids).copied().collect();
+    let mut title_matches: Vec<i64> = state
+        .books
+        .iter()
+        .filter(|(_, bs)| bs.matched_by.as_deref() == Some("title"))
+        .filter_map(|(key, _)| key.parse().ok())
+        .collect();
+    title_matches.sort_unstable();
+    let untracked: Vec<i64> = library_ids.difference(&state_ids).copied().collect();
+
+    let state_updated_utc = state.updated_at_utc.clone();
+    let mut pruned = 0;
+    if args.prune && !orphaned.is_empty() {
+        if library_ids.is_empty() {
+            // An empty listing is far more likely a wrong library than a wiped one
+            warn!("[verify] library returned no books; refusing to prune state");
+        } else {
+            for id in &orphaned {
+                state.books.remove(&id.to_string());
+            }
+            pruned = orphaned.len();
+            save_state(state_path, &mut state)?;
+            info!(pruned, state = %state_path.display(), "[verify] pruned orphaned state entries");
+        }
+    }
+
+    let report = VerifyReport {
+        library_books: library_ids.len(),
+        state_entries: state.books.len() + pruned,
+        orphaned,
+        untracked,
+        invalid_keys,
+        title_matches,
+        pruned,
+        state_updated_utc,
+    };
+    match args.output {
+        OutputFormat::Text => write_output(&report_text(&report, local_time), None),
+        OutputFormat::Json => write_output(&serde_json::to_string_pretty(&report)?, None),
+    }
+}
+
+fn report_text(report: &VerifyReport, local_time: bool) -> String {
+    let join = |ids: &[i64]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(",");
+    let mut buf = String::new();
+    buf.push_str(&format!("Library books: {}\n", report.library_books));
+    buf.push_str(&format!("State entries: {}\n", report.state_entries));
+    if let Some(updated) = &report.state_updated_utc {
+        buf.push_str(&format!("State updated: {}\n", display_timestamp(updated, local_time)));
+    }
+    buf.push_str(&format!("Orphaned state entries: {}\n", report.orphaned.len()));
+    if !report.orphaned.is_empty() {
+        buf.push_str(&format!("  ids: {}\n", join(&report.orphaned)));
+    }
+    buf.push_str(&format!("Books without state: {}\n", report.untracked.len()));
+    if !report.untracked.is_empty() {
+        buf.push_str(&format!("  ids: {}\n", join(&report.untracked)));
+    }
+    if !report.title_matches.is_empty() {
+        buf.push_str(&format!("Matched by title only: {}\n", report.title_matches.len()));
+        buf.push_str(&format!("  ids: {}\n", join(&report.title_matches)));
+    }
+    if !report.invalid_keys.is_empty() {
+        buf.push_str(&format!("Invalid state keys: {}\n", report.invalid_keys.join(",")));
+    }
+    if report.pruned > 0 {
+        buf.push_str(&format!("Pruned: {}\n", report.pruned));
+    }
+    buf
+}